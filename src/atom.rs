@@ -1,54 +1,93 @@
 use crate::{
+    conditions::Condition,
     env::Env,
-    lisp_eval::{Args, EvalResult},
+    lisp_eval::{run, Args, EvalResult, Tail, TailResult},
+    random::RandomState,
     sexpr::SExpr,
+    vm::{self, Closure},
+};
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use std::{
+    fmt::Debug,
+    ptr,
+    sync::{Arc, Mutex},
 };
-use std::{fmt::Debug, ptr, sync::Arc};
 
 pub type NativeFn = Box<dyn Fn(&mut Env, &Args) -> EvalResult + Send + Sync>;
-pub type UserFn = Box<(
-    SAtom,
-    Box<dyn Fn(&mut Env, &Args) -> EvalResult + Send + Sync>,
-)>;
+/// A function that may leave the rest of its work to the caller's eval loop.
+pub type TailFn = Box<dyn Fn(&mut Env, &Args) -> TailResult + Send + Sync>;
+pub type UserFn = Box<(SAtom, TailFn)>;
 pub type SAtom = Arc<Atom>;
 
 pub enum Fun {
     Native(NativeFn),
+    Tail(TailFn),
     User(UserFn),
+    Compiled(Arc<Closure>),
 }
 
 impl Fun {
     pub fn call(&self, env: &mut Env, args: &Args) -> EvalResult {
         match self {
             Fun::Native(s_fun) => s_fun(env, args),
+            Fun::Compiled(closure) => vm::call(closure, args.to_vec(), env),
+            _ => run(self.call_tail(env, args)?, env),
+        }
+    }
+
+    /// Call without finishing a tail call, see `lisp_eval::run`.
+    pub fn call_tail(&self, env: &mut Env, args: &Args) -> TailResult {
+        match self {
+            Fun::Native(s_fun) => s_fun(env, args).map(Tail::Done),
+            Fun::Tail(s_fun) => s_fun(env, args),
             Fun::User(s_fun) => s_fun.1(env, args),
+            Fun::Compiled(closure) => Ok(Tail::Compiled(closure.clone(), args.to_vec())),
         }
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub enum Atom {
     T,
+    #[default]
     Nil,
+    /// An integer that fits an `i64` (a fixnum).
+    Int(i64),
+    /// An integer too big for an `i64` (a bignum).
+    Big(Arc<BigInt>),
+    /// An exact fraction, never with a denominator of 1.
+    Ratio(Arc<BigRational>),
+    /// A float.
     Num(f64),
     Str(String),
     Sym(String),
     Cons(SExpr),
     Fun(Arc<Fun>),
+    Condition(Arc<Condition>),
+    /// A pseudo-random generator state, advanced in place by `random`.
+    RandomState(Arc<Mutex<RandomState>>),
 }
 
 impl PartialEq for Atom {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
             (Atom::T, Atom::T) | (Atom::Nil, Atom::Nil) => true,
+            (Atom::Int(a), Atom::Int(b)) => a == b,
+            (Atom::Big(a), Atom::Big(b)) => a == b,
+            (Atom::Ratio(a), Atom::Ratio(b)) => a == b,
             (Atom::Num(a), Atom::Num(b)) => a == b,
             (Atom::Str(a), Atom::Str(b)) => a == b,
             (Atom::Sym(a), Atom::Sym(b)) => a == b,
             (Atom::Cons(a), Atom::Cons(b)) => a == b,
+            (Atom::Condition(a), Atom::Condition(b)) => Arc::ptr_eq(a, b),
+            (Atom::RandomState(a), Atom::RandomState(b)) => Arc::ptr_eq(a, b),
 
             (Atom::Fun(a), Atom::Fun(b)) => match (&**a, &**b) {
                 (Fun::Native(a), Fun::Native(b)) => ptr::eq(&**a, &**b),
+                (Fun::Tail(a), Fun::Tail(b)) => ptr::eq(&**a, &**b),
                 (Fun::User(a), Fun::User(b)) => a.0 == b.0,
+                (Fun::Compiled(a), Fun::Compiled(b)) => a.proto.body == b.proto.body,
                 _ => false,
             },
             _ => false,
@@ -62,6 +101,12 @@ impl From<f64> for Atom {
     }
 }
 
+impl From<i64> for Atom {
+    fn from(v: i64) -> Self {
+        Atom::Int(v)
+    }
+}
+
 impl From<SExpr> for Atom {
     fn from(v: SExpr) -> Self {
         Atom::Cons(v)
@@ -71,22 +116,23 @@ impl From<SExpr> for Atom {
 impl Debug for Atom {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Atom::Num(n) => write!(f, "{}", n),
+            Atom::Int(n) => write!(f, "{}", n),
+            Atom::Big(n) => write!(f, "{}", n),
+            Atom::Ratio(n) => write!(f, "{}", n),
+            // `{:?}` keeps the `.0` of integral floats, so they read back as floats
+            Atom::Num(n) => write!(f, "{:?}", n),
             Atom::Str(s) => write!(f, "{:?}", s),
             Atom::Sym(s) => write!(f, "{}", s),
             Atom::Nil => write!(f, "Nil"),
             Atom::T => write!(f, "T"),
             Atom::Cons(sexpr) => sexpr.fmt(f),
             Atom::Fun(fun) => match &**fun {
-                Fun::Native(_) => write!(f, "NativeFn"),
+                Fun::Native(_) | Fun::Tail(_) => write!(f, "NativeFn"),
                 Fun::User(fun) => write!(f, "{:#?}", fun.0),
+                Fun::Compiled(closure) => write!(f, "{:#?}", closure.proto.body),
             },
+            Atom::Condition(cond) => write!(f, "#<{} {:?}>", cond.kind, cond.message),
+            Atom::RandomState(_) => write!(f, "#<random-state>"),
         }
     }
 }
-
-impl Default for Atom {
-    fn default() -> Self {
-        Atom::Nil
-    }
-}
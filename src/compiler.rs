@@ -0,0 +1,1278 @@
+use std::sync::Arc;
+
+use crate::{
+    atom::{Atom, SAtom},
+    control::{block_name, tag_name},
+    env::{function_body, parse_bindings, parse_fun_bindings, progn_form, Env},
+    iteration::{do_bindings, loop_spec, DoBinding, LoopSpec},
+    lisp_error::EvalError,
+    lisp_eval::{macroexpand_1, SPECIAL_FORMS},
+    num,
+    sexpr::SExpr,
+};
+
+/// A VM instruction. Operands index the `consts` or `protos` of the `Proto`
+/// being run, a local slot of the current frame, or a code offset.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    /// Push `consts[i]`.
+    Const(usize),
+    /// Push local slot `i`.
+    Local(usize),
+    /// Store the top of the stack in local slot `i`, leaving it on the stack.
+    SetLocal(usize),
+    /// Push the variable named `consts[i]`, looked up in the lexical
+    /// bindings captured by the closure, then in the globals.
+    Global(usize),
+    /// Assign the variable named `consts[i]`, like `setq` does.
+    SetGlobal(usize),
+    /// Push the function named `consts[i]`.
+    Function(usize),
+    Pop,
+    /// Push a copy of the top of the stack.
+    Dup,
+    Jump(usize),
+    /// Pop the top of the stack and jump when it is nil.
+    JumpIfNil(usize),
+    /// Jump when one of the variables in the list `consts[i]` is special.
+    JumpIfSpecial(usize, usize),
+    /// Push a closure over `protos[i]`.
+    Closure(usize),
+    /// Call the function below the top `n` values with them as arguments.
+    Call(usize),
+    /// Like `Call` in tail position: the callee replaces the current frame.
+    TailCall(usize),
+    /// Like `Call`, or `TailCall` with `true`, for a function designator as
+    /// `funcall` and `apply` take: a symbol names a global function.
+    Funcall(usize, bool),
+    /// Pop a function and call it with the unevaluated arguments of the
+    /// call form `consts[i]`, as the interpreter does for `((lambda ...) ...)`.
+    /// A value that isn't a function is left to the interpreter, with the
+    /// slots of `scopes[j]` bound.
+    CallForm(usize, usize),
+    /// Pop a closure and install it as the function, or with `true` the
+    /// macro, named `consts[i]`.
+    Defun(usize, bool),
+    /// Evaluate `consts[i]` with the interpreter, with the local slots of
+    /// `scopes[j]` bound by name. Used for the special forms the compiler
+    /// doesn't know. A compiled function the form calls last is run by the
+    /// VM, in place of the frame when the `Interp` is followed by `Return`.
+    Interp(usize, usize),
+    /// Pop a key and push t when it matches the keys `consts[i]` of a `case`
+    /// clause, or with `true` of an `ecase` clause.
+    Case(usize, bool),
+    /// Pop the key no `ecase` clause matched and signal a type error.
+    EcaseError,
+    /// Pop the top of the stack into a new binding in local slot `i`, as
+    /// `let` does.
+    Bind(usize),
+    /// Enter a `block` with a new marker in local slot `i`. An exit to it
+    /// unwinds the stack to its height here, pushes the value and goes on at
+    /// `j`, its `Leave`.
+    Block(usize, usize),
+    /// Enter a `tagbody` with a new marker in the slots of its tags, which
+    /// `targets[i]` lists with their code offsets.
+    Tagbody(usize),
+    /// Leave the innermost `block` or `tagbody` of the frame: exits to it
+    /// are errors from now on.
+    Leave,
+    /// Pop a value and `return-from` the block marked in local slot `i`, or
+    /// with `true` `return` from it.
+    ReturnFrom(usize, bool),
+    /// `go` to the tag in local slot `i`.
+    Go(usize),
+    /// With a counter on top of the stack and a count below it, jump once
+    /// the counter reaches the count, which must be an integer.
+    Dotimes(usize),
+    /// Add 1 to the counter on top of the stack.
+    Incr,
+    /// Jump when the list on top of the stack is empty, or else replace it
+    /// with its cdr and push its car.
+    Dolist(usize),
+    Return,
+}
+
+/// What a local slot binds.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Slot {
+    Var(String),
+    /// A local function of `flet` or `labels`.
+    Fun(String),
+    /// The marker of the `block` of that name.
+    Block(String),
+    /// The marker of the `tagbody` with that tag.
+    Tag(String),
+}
+
+/// A variable slot of that name.
+impl PartialEq<&str> for Slot {
+    fn eq(&self, name: &&str) -> bool {
+        matches!(self, Slot::Var(var) if var == name)
+    }
+}
+
+/// A compiled lambda body or top-level form.
+#[derive(Debug)]
+pub struct Proto {
+    /// Function name for arity errors, `lambda` if anonymous.
+    pub name: String,
+    /// The source body, printed for closures and compared by `eq`.
+    pub body: SAtom,
+    pub nparams: usize,
+    /// The local slots: the params, then the captured variables, the
+    /// variables bound by `let` and the block and tag markers, in the order
+    /// they were met.
+    pub slots: Vec<Slot>,
+    /// For each captured variable, its slot in the enclosing frame, shared
+    /// with each closure over this proto, and its slot in this one.
+    pub captures: Vec<(usize, usize)>,
+    /// The slots in scope at each `Interp` and `CallForm`, outermost first.
+    pub scopes: Vec<Vec<usize>>,
+    /// The tag slots of each `Tagbody` with the code offset of the tag.
+    pub targets: Vec<Vec<(usize, usize)>>,
+    pub code: Vec<Op>,
+    /// Source form of each instruction, for error locations.
+    pub forms: Vec<SAtom>,
+    pub consts: Vec<SAtom>,
+    pub protos: Vec<Arc<Proto>>,
+    /// For a top-level form, the macros called in it and whether a local
+    /// function shadowed each, which decided whether it was expanded.
+    pub macros: Vec<(String, bool)>,
+}
+
+impl Proto {
+    fn new(name: &str, body: SAtom, params: Vec<String>) -> Self {
+        Proto {
+            name: name.into(),
+            body,
+            nparams: params.len(),
+            slots: params.into_iter().map(Slot::Var).collect(),
+            captures: Vec::new(),
+            scopes: Vec::new(),
+            targets: Vec::new(),
+            code: Vec::new(),
+            forms: Vec::new(),
+            consts: Vec::new(),
+            protos: Vec::new(),
+            macros: Vec::new(),
+        }
+    }
+}
+
+/// Compile a top-level form into a proto without params.
+pub fn compile(form: &SAtom, s: &mut Env) -> Result<Arc<Proto>, EvalError> {
+    let mut compiler = Compiler {
+        env: s,
+        protos: vec![Proto::new("lambda", form.clone(), Vec::new())],
+        visible: vec![Vec::new()],
+        macros: Vec::new(),
+    };
+    compiler.expr(form, true)?;
+    let mut proto = compiler.protos.pop().unwrap();
+    proto.macros = compiler.macros;
+    Ok(Arc::new(proto))
+}
+
+/// The forms of a body as one form, `None` if there are none.
+fn body_form(body: &[SAtom]) -> Option<SAtom> {
+    match body.is_empty() {
+        true => None,
+        false => Some(progn_form(&SAtom::new(Atom::Cons(
+            body.iter().cloned().collect(),
+        )))),
+    }
+}
+
+/// Param names of a lambda list, if it is one the compiler handles: only
+/// required params, `&optional`, `&rest` and `&key` run on the interpreter.
+/// So do special variables, which are bound dynamically rather than in slots;
+/// params that only become special later are checked for when the closure
+/// is made, see `Compiler::unless_special`.
+fn lambda_params(v: &Atom, s: &Env) -> Option<Vec<String>> {
+    match v {
+        Atom::Nil => Some(Vec::new()),
+        Atom::Cons(params) => params
+            .iter()
+            .map(|p| match &*p {
+                Atom::Sym(name) if !name.starts_with('&') && !s.is_special(name) => {
+                    Some(name.clone())
+                }
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+struct Compiler<'a> {
+    /// Macros are expanded at compile time, against this environment.
+    env: &'a mut Env,
+    /// The protos being built, innermost last.
+    protos: Vec<Proto>,
+    /// The slots of each proto in `protos` that are in scope at the form
+    /// being compiled: the params and the `let` variables around it.
+    visible: Vec<Vec<usize>>,
+    /// The macros called so far, see `Proto::macros`.
+    macros: Vec<(String, bool)>,
+}
+
+impl Compiler<'_> {
+    fn proto(&mut self) -> &mut Proto {
+        self.protos.last_mut().unwrap()
+    }
+
+    fn emit(&mut self, op: Op, form: &SAtom) -> usize {
+        let proto = self.proto();
+        proto.code.push(op);
+        proto.forms.push(form.clone());
+        proto.code.len() - 1
+    }
+
+    /// Point the jump at `at` to the next instruction.
+    fn patch(&mut self, at: usize) {
+        let target = self.proto().code.len();
+        match &mut self.proto().code[at] {
+            Op::Jump(to)
+            | Op::JumpIfNil(to)
+            | Op::JumpIfSpecial(_, to)
+            | Op::Block(_, to)
+            | Op::Dotimes(to)
+            | Op::Dolist(to) => *to = target,
+            _ => unreachable!(),
+        }
+    }
+
+    fn constant(&mut self, v: SAtom) -> usize {
+        let consts = &mut self.proto().consts;
+        consts.push(v);
+        consts.len() - 1
+    }
+
+    fn name(&mut self, name: &str) -> usize {
+        let consts = &self.proto().consts;
+        match consts
+            .iter()
+            .position(|c| matches!(&**c, Atom::Sym(n) if n == name))
+        {
+            Some(idx) => idx,
+            None => self.constant(SAtom::new(Atom::Sym(name.into()))),
+        }
+    }
+
+    /// Emit `op` and, in tail position, return its value.
+    fn value(&mut self, op: Op, form: &SAtom, tail: bool) {
+        self.emit(op, form);
+        if tail {
+            self.emit(Op::Return, form);
+        }
+    }
+
+    /// Local slot binding `name` in the proto at `depth`, capturing it from
+    /// the enclosing protos when needed.
+    fn resolve(&mut self, name: &Slot, depth: usize) -> Option<usize> {
+        let proto = &self.protos[depth];
+        let slots = self.visible[depth]
+            .iter()
+            .rev()
+            .chain(proto.captures.iter().map(|(_, slot)| slot));
+        if let Some(slot) = slots.copied().find(|slot| proto.slots[*slot] == *name) {
+            return Some(slot);
+        }
+        if depth == 0 {
+            return None;
+        }
+        let outer = self.resolve(name, depth - 1)?;
+        let proto = &mut self.protos[depth];
+        proto.slots.push(name.clone());
+        proto.captures.push((outer, proto.slots.len() - 1));
+        Some(proto.slots.len() - 1)
+    }
+
+    /// The slots in scope in the proto at `depth`, outermost first: the
+    /// captured ones, then the params, `let` variables and markers.
+    fn in_scope(&self, depth: usize) -> Vec<usize> {
+        let captured = self.protos[depth].captures.iter().map(|(_, slot)| *slot);
+        captured
+            .chain(self.visible[depth].iter().copied())
+            .collect()
+    }
+
+    /// Record the slots in scope for an `Interp` or `CallForm`.
+    fn scope(&mut self) -> usize {
+        let scope = self.in_scope(self.protos.len() - 1);
+        let scopes = &mut self.proto().scopes;
+        scopes.push(scope);
+        scopes.len() - 1
+    }
+
+    /// A new slot binding `name`, not in scope yet.
+    fn slot(&mut self, name: Slot) -> usize {
+        let slots = &mut self.proto().slots;
+        slots.push(name);
+        slots.len() - 1
+    }
+
+    fn local(&mut self, name: Slot) -> Option<usize> {
+        self.resolve(&name, self.protos.len() - 1)
+    }
+
+    /// Compile `v`. In tail position the emitted code returns from the frame.
+    fn expr(&mut self, v: &SAtom, tail: bool) -> Result<(), EvalError> {
+        if let Atom::Cons(SExpr { car, .. }) = &**v {
+            if let Atom::Sym(name) = &**car {
+                let seen = self.macros.iter().any(|(n, _)| n == name);
+                if !seen && self.env.macros.contains_key(name) {
+                    let shadowed = self.env.val.get_fun(name).is_some();
+                    self.macros.push((name.clone(), shadowed));
+                }
+            }
+        }
+        // local functions shadow macros
+        let local_fun = match &**v {
+            Atom::Cons(SExpr { car, .. }) => match &**car {
+                Atom::Sym(name) => self.local(Slot::Fun(name.clone())).is_some(),
+                _ => false,
+            },
+            _ => false,
+        };
+        if !local_fun {
+            if let Some(expansion) = macroexpand_1(v, self.env)? {
+                return self.expr(&expansion, tail);
+            }
+        }
+
+        match &**v {
+            Atom::Sym(name) => {
+                let op = match self.local(Slot::Var(name.clone())) {
+                    Some(slot) => Op::Local(slot),
+                    None => Op::Global(self.name(name)),
+                };
+                self.value(op, v, tail);
+            }
+            Atom::Cons(SExpr { car, cdr }) => match (&**car, &**cdr) {
+                (Atom::Sym(name), Atom::Cons(args)) => self.form(v, name, args, tail)?,
+                (Atom::Sym(name), Atom::Nil) if !SPECIAL_FORMS.contains(&name.as_str()) => {
+                    self.function(v, name);
+                    self.call(v, 0, tail);
+                }
+                (Atom::Sym(_), _) => self.interp(v, tail),
+                _ => {
+                    self.expr(car, false)?;
+                    let form = self.constant(v.clone());
+                    let scope = self.scope();
+                    self.value(Op::CallForm(form, scope), v, tail);
+                }
+            },
+            _ => {
+                let c = self.constant(v.clone());
+                self.value(Op::Const(c), v, tail);
+            }
+        }
+        Ok(())
+    }
+
+    /// Push the function `name`, a local one if in scope.
+    fn function(&mut self, v: &SAtom, name: &str) {
+        let op = match self.local(Slot::Fun(name.into())) {
+            Some(slot) => Op::Local(slot),
+            None => Op::Function(self.name(name)),
+        };
+        self.emit(op, v);
+    }
+
+    fn call(&mut self, v: &SAtom, argc: usize, tail: bool) {
+        match tail {
+            true => self.emit(Op::TailCall(argc), v),
+            false => self.emit(Op::Call(argc), v),
+        };
+    }
+
+    /// Leave `v` to the interpreter.
+    fn interp(&mut self, v: &SAtom, tail: bool) {
+        // The form may refer to any variable in scope, so make sure all of
+        // them are slots of this proto.
+        let depth = self.protos.len() - 1;
+        let names: Vec<Slot> = (0..depth)
+            .flat_map(|d| {
+                let slots = &self.protos[d].slots;
+                self.in_scope(d).into_iter().map(|slot| slots[slot].clone())
+            })
+            .collect();
+        for name in names {
+            self.resolve(&name, depth);
+        }
+
+        let form = self.constant(v.clone());
+        let scope = self.scope();
+        self.value(Op::Interp(form, scope), v, tail);
+    }
+
+    /// Compile the list `v` = `(name args...)`.
+    fn form(&mut self, v: &SAtom, name: &str, args: &SExpr, tail: bool) -> Result<(), EvalError> {
+        let args: Vec<SAtom> = args.iter().collect();
+
+        match (name, args.as_slice()) {
+            ("quote", [datum]) => {
+                let c = self.constant(datum.clone());
+                self.value(Op::Const(c), v, tail);
+            }
+            ("if", [test, then, otherwise @ ..]) if otherwise.len() <= 1 => {
+                self.branch(v, test, Some(then), otherwise.first(), tail)?
+            }
+            ("when", [test, body @ ..]) => {
+                self.branch(v, test, body_form(body).as_ref(), None, tail)?
+            }
+            ("unless", [test, body @ ..]) => {
+                self.branch(v, test, None, body_form(body).as_ref(), tail)?
+            }
+            ("cond", clauses) if clauses.iter().all(|c| matches!(&**c, Atom::Cons(_))) => {
+                let clauses: Vec<Vec<SAtom>> = clauses
+                    .iter()
+                    .map(|clause| match &**clause {
+                        Atom::Cons(clause) => clause.iter().collect(),
+                        _ => unreachable!(),
+                    })
+                    .collect();
+                self.cond(v, &clauses, tail)?
+            }
+            // (or a b) is (cond (a) (b))
+            ("or", forms) => {
+                let clauses: Vec<Vec<SAtom>> = forms.iter().map(|f| vec![f.clone()]).collect();
+                self.cond(v, &clauses, tail)?
+            }
+            ("and", [forms @ .., last]) => {
+                let mut to_nil = Vec::new();
+                for form in forms {
+                    self.expr(form, false)?;
+                    to_nil.push(self.emit(Op::JumpIfNil(0), v));
+                }
+                self.expr(last, tail)?;
+                if !to_nil.is_empty() {
+                    let to_end = (!tail).then(|| self.emit(Op::Jump(0), v));
+                    for at in to_nil {
+                        self.patch(at);
+                    }
+                    self.nil(v, tail);
+                    if let Some(to_end) = to_end {
+                        self.patch(to_end);
+                    }
+                }
+            }
+            // calls through funcall stay in the VM
+            ("funcall" | "apply", [fun, args @ ..]) => {
+                self.expr(fun, false)?;
+                for arg in args {
+                    self.expr(arg, false)?;
+                }
+                self.emit(Op::Funcall(args.len(), tail), v);
+            }
+            ("let" | "let*" | "letrec", [bindings, body @ ..]) => {
+                // Special variables are bound dynamically, by the interpreter.
+                // So are malformed bindings, which it reports.
+                match parse_bindings("let", bindings) {
+                    Ok(bindings) if !bindings.iter().any(|(n, _)| self.env.is_special(n)) => {
+                        let names: Vec<String> = bindings.iter().map(|(n, _)| n.clone()).collect();
+                        self.unless_special(v, &names, tail, |c| {
+                            c.bindings(v, name, &bindings, body, tail)
+                        })?
+                    }
+                    _ => self.interp(v, tail),
+                }
+            }
+            ("case" | "ecase", [key, clauses @ ..])
+                if clauses.iter().all(|c| matches!(&**c, Atom::Cons(_))) =>
+            {
+                self.case(v, key, clauses, name == "ecase", tail)?
+            }
+            ("function", [fname]) if matches!(&**fname, Atom::Sym(_)) => {
+                let Atom::Sym(fname) = &**fname else {
+                    unreachable!()
+                };
+                self.function(v, fname);
+                if tail {
+                    self.emit(Op::Return, v);
+                }
+            }
+            ("function", [lambda])
+                if matches!(&**lambda, Atom::Cons(SExpr { car, .. })
+                    if matches!(&**car, Atom::Sym(l) if l == "lambda")) =>
+            {
+                self.expr(lambda, tail)?
+            }
+            ("progn", [body @ .., last]) => {
+                for form in body {
+                    self.expr(form, false)?;
+                    self.emit(Op::Pop, v);
+                }
+                self.expr(last, tail)?;
+            }
+            ("lambda", [params, body @ ..]) if lambda_params(params, self.env).is_some() => {
+                let names = lambda_params(params, self.env).unwrap();
+                self.unless_special(v, &names, tail, |c| {
+                    c.lambda(v, "lambda", params, body)?;
+                    if tail {
+                        c.emit(Op::Return, v);
+                    }
+                    Ok(())
+                })?
+            }
+            ("defun" | "defmacro", [fname, params, body @ ..])
+                if matches!(&**fname, Atom::Sym(_))
+                    && lambda_params(params, self.env).is_some() =>
+            {
+                let Atom::Sym(fname) = &**fname else {
+                    unreachable!()
+                };
+                let body = function_body(
+                    fname,
+                    &SAtom::new(match body.is_empty() {
+                        true => Atom::Nil,
+                        false => Atom::Cons(body.iter().cloned().collect()),
+                    }),
+                );
+                let names = lambda_params(params, self.env).unwrap();
+                self.unless_special(v, &names, tail, |c| {
+                    c.lambda(v, fname, params, &[body])?;
+                    let fname = c.name(fname);
+                    c.value(Op::Defun(fname, name == "defmacro"), v, tail);
+                    Ok(())
+                })?
+            }
+            ("setq", pairs)
+                if pairs.len() % 2 == 0
+                    && pairs
+                        .iter()
+                        .step_by(2)
+                        .all(|n| matches!(&**n, Atom::Sym(_))) =>
+            {
+                if pairs.is_empty() {
+                    let c = self.constant(SAtom::new(Atom::Nil));
+                    self.emit(Op::Const(c), v);
+                }
+                for (idx, pair) in pairs.chunks(2).enumerate() {
+                    let Atom::Sym(vname) = &*pair[0] else {
+                        unreachable!()
+                    };
+                    if idx > 0 {
+                        self.emit(Op::Pop, v);
+                    }
+                    self.expr(&pair[1], false)?;
+                    let op = match self.local(Slot::Var(vname.clone())) {
+                        Some(slot) => Op::SetLocal(slot),
+                        None => Op::SetGlobal(self.name(vname)),
+                    };
+                    self.emit(op, v);
+                }
+                if tail {
+                    self.emit(Op::Return, v);
+                }
+            }
+            ("block", [bname, body @ ..]) if block_name("block", bname).is_ok() => {
+                let bname = block_name("block", bname).unwrap();
+                self.block(v, &bname, tail, |c| match body_form(body) {
+                    Some(body) => c.expr(&body, tail),
+                    None => {
+                        c.nil(v, tail);
+                        Ok(())
+                    }
+                })?
+            }
+            ("return-from", [bname, value @ ..])
+                if value.len() <= 1 && block_name("return-from", bname).is_ok() =>
+            {
+                let bname = block_name("return-from", bname).unwrap();
+                self.return_from(v, &bname, value.first(), false, tail)?
+            }
+            ("return", value) if value.len() <= 1 => {
+                self.return_from(v, "nil", value.first(), true, tail)?
+            }
+            ("tagbody", items) => self.tagbody(v, items, tail)?,
+            ("go", [tag]) => match tag_name(tag).and_then(|tag| self.local(Slot::Tag(tag))) {
+                Some(slot) => {
+                    self.emit(Op::Go(slot), v);
+                }
+                None => self.interp(v, tail),
+            },
+            ("while", [test, body @ ..]) => self.block(v, "nil", tail, |c| {
+                let top = c.proto().code.len();
+                c.expr(test, false)?;
+                let to_end = c.emit(Op::JumpIfNil(0), v);
+                c.effects(v, body)?;
+                c.emit(Op::Jump(top), v);
+                c.patch(to_end);
+                c.nil(v, tail);
+                Ok(())
+            })?,
+            ("dotimes" | "dolist", [spec, body @ ..]) if loop_spec("dotimes", spec).is_ok() => {
+                let spec = loop_spec("dotimes", spec).unwrap();
+                self.block(v, "nil", tail, |c| c.each(v, name, &spec, body, tail))?
+            }
+            ("do", [bindings, end, body @ ..])
+                if do_bindings(bindings).is_ok() && matches!(&**end, Atom::Cons(_)) =>
+            {
+                let bindings = do_bindings(bindings).unwrap();
+                let Atom::Cons(SExpr {
+                    car: end_test,
+                    cdr: results,
+                }) = &**end
+                else {
+                    unreachable!()
+                };
+                let results: Vec<SAtom> = match &**results {
+                    Atom::Cons(results) => results.iter().collect(),
+                    _ => Vec::new(),
+                };
+                self.block(v, "nil", tail, |c| {
+                    c.do_loop(v, &bindings, end_test, &results, body, tail)
+                })?
+            }
+            ("flet" | "labels", [defs, body @ ..]) => {
+                let form = if name == "flet" { "flet" } else { "labels" };
+                match parse_fun_bindings(form, defs) {
+                    Ok(defs) if defs.iter().all(|def| self.compiles_fun(def)) => {
+                        let mut names = Vec::new();
+                        for (_, params, _) in &defs {
+                            names.extend(lambda_params(params, self.env).unwrap());
+                        }
+                        self.unless_special(v, &names, tail, |c| {
+                            c.local_funs(v, &defs, body, name == "labels", tail)
+                        })?
+                    }
+                    _ => self.interp(v, tail),
+                }
+            }
+            _ if SPECIAL_FORMS.contains(&name) => self.interp(v, tail),
+            _ => {
+                self.function(v, name);
+                for arg in &args {
+                    self.expr(arg, false)?;
+                }
+                self.call(v, args.len(), tail);
+            }
+        }
+        Ok(())
+    }
+
+    fn nil(&mut self, v: &SAtom, tail: bool) {
+        let c = self.constant(SAtom::new(Atom::Nil));
+        self.value(Op::Const(c), v, tail);
+    }
+
+    /// Compile `(if test then else)`, where a missing branch is nil.
+    fn branch(
+        &mut self,
+        v: &SAtom,
+        test: &SAtom,
+        then: Option<&SAtom>,
+        otherwise: Option<&SAtom>,
+        tail: bool,
+    ) -> Result<(), EvalError> {
+        self.expr(test, false)?;
+        let to_else = self.emit(Op::JumpIfNil(0), v);
+        match then {
+            Some(then) => self.expr(then, tail)?,
+            None => self.nil(v, tail),
+        }
+        let to_end = (!tail).then(|| self.emit(Op::Jump(0), v));
+        self.patch(to_else);
+        match otherwise {
+            Some(otherwise) => self.expr(otherwise, tail)?,
+            None => self.nil(v, tail),
+        }
+        if let Some(to_end) = to_end {
+            self.patch(to_end);
+        }
+        Ok(())
+    }
+
+    /// Compile `cond` clauses `(test body...)`. A clause without a body
+    /// yields the value of its test, so the test value is kept with `Dup`.
+    fn cond(&mut self, v: &SAtom, clauses: &[Vec<SAtom>], tail: bool) -> Result<(), EvalError> {
+        let mut to_end = Vec::new();
+        for clause in clauses {
+            let (test, body) = clause.split_first().unwrap();
+            self.expr(test, false)?;
+            match body_form(body) {
+                Some(body) => {
+                    let to_next = self.emit(Op::JumpIfNil(0), v);
+                    self.expr(&body, tail)?;
+                    if !tail {
+                        to_end.push(self.emit(Op::Jump(0), v));
+                    }
+                    self.patch(to_next);
+                }
+                None => {
+                    self.emit(Op::Dup, v);
+                    let to_next = self.emit(Op::JumpIfNil(0), v);
+                    match tail {
+                        true => {
+                            self.emit(Op::Return, v);
+                        }
+                        false => to_end.push(self.emit(Op::Jump(0), v)),
+                    }
+                    self.patch(to_next);
+                    self.emit(Op::Pop, v);
+                }
+            }
+        }
+        self.nil(v, tail);
+        for at in to_end {
+            self.patch(at);
+        }
+        Ok(())
+    }
+
+    /// Compile `case` clauses `(keys body...)` like `cond` ones, testing a
+    /// copy of the key kept on the stack until a clause matches.
+    fn case(
+        &mut self,
+        v: &SAtom,
+        key: &SAtom,
+        clauses: &[SAtom],
+        exhaustive: bool,
+        tail: bool,
+    ) -> Result<(), EvalError> {
+        self.expr(key, false)?;
+        let mut to_end = Vec::new();
+        for clause in clauses {
+            let Atom::Cons(SExpr {
+                car: keys,
+                cdr: body,
+            }) = &**clause
+            else {
+                unreachable!()
+            };
+            self.emit(Op::Dup, v);
+            let keys = self.constant(keys.clone());
+            self.emit(Op::Case(keys, exhaustive), v);
+            let to_next = self.emit(Op::JumpIfNil(0), v);
+            self.emit(Op::Pop, v);
+            let body: Vec<SAtom> = match &**body {
+                Atom::Cons(body) => body.iter().collect(),
+                _ => Vec::new(),
+            };
+            match body_form(&body) {
+                Some(body) => self.expr(&body, tail)?,
+                None => self.nil(v, tail),
+            }
+            if !tail {
+                to_end.push(self.emit(Op::Jump(0), v));
+            }
+            self.patch(to_next);
+        }
+        match exhaustive {
+            true => {
+                self.emit(Op::EcaseError, v);
+            }
+            false => {
+                self.emit(Op::Pop, v);
+                self.nil(v, tail);
+            }
+        }
+        for at in to_end {
+            self.patch(at);
+        }
+        Ok(())
+    }
+
+    /// Compile `v`, which binds the variables `names`, with `compile`, behind
+    /// a check that none of them has been declared special since: the
+    /// interpreter, which binds special variables dynamically, then runs `v`
+    /// instead. Like it, this decides specialness when the binding is made.
+    fn unless_special(
+        &mut self,
+        v: &SAtom,
+        names: &[String],
+        tail: bool,
+        compile: impl FnOnce(&mut Self) -> Result<(), EvalError>,
+    ) -> Result<(), EvalError> {
+        if names.is_empty() {
+            return compile(self);
+        }
+        let names = names.iter().map(|n| SAtom::new(Atom::Sym(n.clone())));
+        let names = self.constant(SAtom::new(Atom::Cons(names.collect())));
+        let to_interp = self.emit(Op::JumpIfSpecial(names, 0), v);
+        compile(self)?;
+        let to_end = (!tail).then(|| self.emit(Op::Jump(0), v));
+        self.patch(to_interp);
+        self.interp(v, tail);
+        if let Some(to_end) = to_end {
+            self.patch(to_end);
+        }
+        Ok(())
+    }
+
+    /// Compile a `let`, `let*` or `letrec` form, binding its variables in
+    /// new slots that are in scope for the body only.
+    fn bindings(
+        &mut self,
+        v: &SAtom,
+        form: &str,
+        bindings: &[(String, Option<SAtom>)],
+        body: &[SAtom],
+        tail: bool,
+    ) -> Result<(), EvalError> {
+        let depth = self.protos.len() - 1;
+        let outer = self.visible[depth].len();
+        let slots: Vec<usize> = bindings
+            .iter()
+            .map(|(name, _)| self.slot(Slot::Var(name.clone())))
+            .collect();
+        match form {
+            // the inits see the enclosing scope only
+            "let" => {
+                for (_, init) in bindings {
+                    self.init(v, init.as_ref())?;
+                }
+                for slot in slots.iter().rev() {
+                    self.emit(Op::Bind(*slot), v);
+                }
+                self.visible[depth].extend(&slots);
+            }
+            "let*" => {
+                for ((_, init), slot) in bindings.iter().zip(&slots) {
+                    self.init(v, init.as_ref())?;
+                    self.emit(Op::Bind(*slot), v);
+                    self.visible[depth].push(*slot);
+                }
+            }
+            // every init sees every variable
+            _ => {
+                for slot in &slots {
+                    self.nil(v, false);
+                    self.emit(Op::Bind(*slot), v);
+                }
+                self.visible[depth].extend(&slots);
+                for ((_, init), slot) in bindings.iter().zip(&slots) {
+                    if let Some(init) = init {
+                        self.expr(init, false)?;
+                        self.emit(Op::SetLocal(*slot), v);
+                        self.emit(Op::Pop, v);
+                    }
+                }
+            }
+        }
+        match body_form(body) {
+            Some(body) => self.expr(&body, tail)?,
+            None => self.nil(v, tail),
+        }
+        self.visible[depth].truncate(outer);
+        Ok(())
+    }
+
+    /// Compile `body` for its effects, dropping the value of each form.
+    fn effects(&mut self, v: &SAtom, body: &[SAtom]) -> Result<(), EvalError> {
+        for form in body {
+            self.expr(form, false)?;
+            self.emit(Op::Pop, v);
+        }
+        Ok(())
+    }
+
+    /// Compile a `block` named `name` around the code `body` emits, which
+    /// leaves the value of the block, or returns it in tail position.
+    fn block(
+        &mut self,
+        v: &SAtom,
+        name: &str,
+        tail: bool,
+        body: impl FnOnce(&mut Self) -> Result<(), EvalError>,
+    ) -> Result<(), EvalError> {
+        let depth = self.protos.len() - 1;
+        let slot = self.slot(Slot::Block(name.into()));
+        let at = self.emit(Op::Block(slot, 0), v);
+        self.visible[depth].push(slot);
+        body(self)?;
+        self.visible[depth].pop();
+        // a `return-from` the block goes on here
+        self.patch(at);
+        self.value(Op::Leave, v, tail);
+        Ok(())
+    }
+
+    /// Compile `(return-from name value)`. A block that isn't in scope is
+    /// left to the interpreter, which reports it.
+    fn return_from(
+        &mut self,
+        v: &SAtom,
+        name: &str,
+        value: Option<&SAtom>,
+        is_return: bool,
+        tail: bool,
+    ) -> Result<(), EvalError> {
+        match self.local(Slot::Block(name.into())) {
+            Some(slot) => {
+                self.init(v, value)?;
+                self.emit(Op::ReturnFrom(slot, is_return), v);
+            }
+            None => self.interp(v, tail),
+        }
+        Ok(())
+    }
+
+    /// Compile a `tagbody` of the tags and forms `items`, where the tags are
+    /// slots in scope throughout.
+    fn tagbody(&mut self, v: &SAtom, items: &[SAtom], tail: bool) -> Result<(), EvalError> {
+        let depth = self.protos.len() - 1;
+        let outer = self.visible[depth].len();
+        let mut tags = Vec::new();
+        for (idx, item) in items.iter().enumerate() {
+            if let Some(tag) = tag_name(item) {
+                tags.push((idx, self.slot(Slot::Tag(tag))));
+            }
+        }
+        let targets = match tags.is_empty() {
+            true => None,
+            false => {
+                let targets = &mut self.proto().targets;
+                targets.push(Vec::new());
+                let idx = targets.len() - 1;
+                self.emit(Op::Tagbody(idx), v);
+                Some(idx)
+            }
+        };
+        self.visible[depth].extend(tags.iter().map(|(_, slot)| *slot));
+
+        for (idx, item) in items.iter().enumerate() {
+            if let Some((_, slot)) = tags.iter().find(|(at, _)| *at == idx) {
+                let proto = self.proto();
+                let target = (*slot, proto.code.len());
+                proto.targets[targets.unwrap()].push(target);
+            } else if matches!(&**item, Atom::Cons(_)) {
+                self.effects(v, std::slice::from_ref(item))?;
+            }
+        }
+        if targets.is_some() {
+            self.emit(Op::Leave, v);
+        }
+        self.visible[depth].truncate(outer);
+        self.nil(v, tail);
+        Ok(())
+    }
+
+    /// Compile the loop of a `dotimes` or `dolist` with the `spec`
+    /// `(var init result)`, binding `var` to each of the numbers below or
+    /// the items of `init`. A new binding each iteration keeps closures over
+    /// `var` apart.
+    fn each(
+        &mut self,
+        v: &SAtom,
+        form: &str,
+        spec: &LoopSpec,
+        body: &[SAtom],
+        tail: bool,
+    ) -> Result<(), EvalError> {
+        let (var, init, result) = spec;
+        let depth = self.protos.len() - 1;
+        self.expr(init, false)?;
+        let slot = self.slot(Slot::Var(var.to_string()));
+        let top = match form {
+            // the counter goes on top of the count
+            "dotimes" => {
+                let zero = self.constant(num!(0).into());
+                self.emit(Op::Const(zero), v);
+                let top = self.emit(Op::Dotimes(0), v);
+                self.emit(Op::Dup, v);
+                top
+            }
+            _ => self.emit(Op::Dolist(0), v),
+        };
+        self.emit(Op::Bind(slot), v);
+        self.visible[depth].push(slot);
+        self.effects(v, body)?;
+        if form == "dotimes" {
+            self.emit(Op::Incr, v);
+        }
+        self.emit(Op::Jump(top), v);
+        self.patch(top);
+
+        // `var` is the count, or 0 if negative, for `dotimes`, nil for
+        // `dolist`
+        self.emit(Op::Bind(slot), v);
+        if form == "dotimes" {
+            self.emit(Op::Pop, v);
+        }
+        self.init(v, result.as_ref())?;
+        if tail {
+            self.emit(Op::Return, v);
+        }
+        self.visible[depth].pop();
+        Ok(())
+    }
+
+    /// Compile the loop of a `do`: new bindings of the vars to their inits,
+    /// then to their steps each iteration, computed in parallel, until
+    /// `end_test` is true and the `results` give the value.
+    fn do_loop(
+        &mut self,
+        v: &SAtom,
+        bindings: &[DoBinding],
+        end_test: &SAtom,
+        results: &[SAtom],
+        body: &[SAtom],
+        tail: bool,
+    ) -> Result<(), EvalError> {
+        let depth = self.protos.len() - 1;
+        let outer = self.visible[depth].len();
+        for (_, init, _) in bindings {
+            self.init(v, init.as_ref())?;
+        }
+        let slots: Vec<usize> = bindings
+            .iter()
+            .map(|(var, ..)| self.slot(Slot::Var(var.to_string())))
+            .collect();
+        for slot in slots.iter().rev() {
+            self.emit(Op::Bind(*slot), v);
+        }
+        self.visible[depth].extend(&slots);
+
+        let top = self.proto().code.len();
+        self.expr(end_test, false)?;
+        let to_body = self.emit(Op::JumpIfNil(0), v);
+        match body_form(results) {
+            Some(results) => self.expr(&results, tail)?,
+            None => self.nil(v, tail),
+        }
+        let to_end = (!tail).then(|| self.emit(Op::Jump(0), v));
+        self.patch(to_body);
+        self.effects(v, body)?;
+        // vars without a step keep their value
+        for ((_, _, step), slot) in bindings.iter().zip(&slots) {
+            match step {
+                Some(step) => self.expr(step, false)?,
+                None => {
+                    self.emit(Op::Local(*slot), v);
+                }
+            }
+        }
+        for slot in slots.iter().rev() {
+            self.emit(Op::Bind(*slot), v);
+        }
+        self.emit(Op::Jump(top), v);
+        if let Some(to_end) = to_end {
+            self.patch(to_end);
+        }
+        self.visible[depth].truncate(outer);
+        Ok(())
+    }
+
+    /// Whether the local function definition `(name params body)` can be
+    /// compiled: its params must be, and calls to it must not be compiled
+    /// to something else.
+    fn compiles_fun(&self, (name, params, _): &(String, SAtom, SAtom)) -> bool {
+        let special =
+            SPECIAL_FORMS.contains(&name.as_str()) || name == "funcall" || name == "apply";
+        !special && lambda_params(params, self.env).is_some()
+    }
+
+    /// Compile a `flet`, or a `labels` when `recursive`, binding its
+    /// functions in new slots that are in scope for the body, and for
+    /// `labels` for the functions as well.
+    fn local_funs(
+        &mut self,
+        v: &SAtom,
+        defs: &[(String, SAtom, SAtom)],
+        body: &[SAtom],
+        recursive: bool,
+        tail: bool,
+    ) -> Result<(), EvalError> {
+        let depth = self.protos.len() - 1;
+        let outer = self.visible[depth].len();
+        let slots: Vec<usize> = defs
+            .iter()
+            .map(|(name, ..)| self.slot(Slot::Fun(name.clone())))
+            .collect();
+        if recursive {
+            for slot in &slots {
+                self.nil(v, false);
+                self.emit(Op::Bind(*slot), v);
+            }
+            self.visible[depth].extend(&slots);
+        }
+        for ((fname, params, fbody), slot) in defs.iter().zip(&slots) {
+            self.lambda(v, fname, params, &[function_body(fname, fbody)])?;
+            if recursive {
+                self.emit(Op::SetLocal(*slot), v);
+                self.emit(Op::Pop, v);
+            }
+        }
+        if !recursive {
+            for slot in slots.iter().rev() {
+                self.emit(Op::Bind(*slot), v);
+            }
+            self.visible[depth].extend(&slots);
+        }
+        match body_form(body) {
+            Some(body) => self.expr(&body, tail)?,
+            None => self.nil(v, tail),
+        }
+        self.visible[depth].truncate(outer);
+        Ok(())
+    }
+
+    /// Push the value of a binding's init form, nil without one.
+    fn init(&mut self, v: &SAtom, init: Option<&SAtom>) -> Result<(), EvalError> {
+        match init {
+            Some(init) => self.expr(init, false),
+            None => {
+                self.nil(v, false);
+                Ok(())
+            }
+        }
+    }
+
+    /// Compile `(lambda params body...)` and emit the closure creation.
+    fn lambda(
+        &mut self,
+        v: &SAtom,
+        name: &str,
+        params: &Atom,
+        body: &[SAtom],
+    ) -> Result<(), EvalError> {
+        let params = lambda_params(params, self.env).unwrap_or_default();
+        let body = progn_form(&SAtom::new(match body.is_empty() {
+            true => Atom::Nil,
+            false => Atom::Cons(body.iter().cloned().collect()),
+        }));
+        self.visible.push((0..params.len()).collect());
+        self.protos.push(Proto::new(name, body.clone(), params));
+        let res = self.expr(&body, true);
+        let proto = self.protos.pop().unwrap();
+        self.visible.pop();
+        res?;
+
+        let protos = &mut self.proto().protos;
+        protos.push(Arc::new(proto));
+        let idx = protos.len() - 1;
+        self.emit(Op::Closure(idx), v);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lisp_parsing::parse;
+
+    use super::*;
+
+    fn compile_str(src: &str) -> Arc<Proto> {
+        compile(&parse(src).into(), &mut Env::default()).unwrap()
+    }
+
+    #[test]
+    fn test_compile_if() {
+        let proto = compile_str("(if x 1 2)");
+        assert_eq!(
+            proto.code,
+            vec![
+                Op::Global(0),
+                Op::JumpIfNil(4),
+                Op::Const(1),
+                Op::Return,
+                Op::Const(2),
+                Op::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_closure() {
+        let proto = compile_str("(lambda (a b) (lambda (c) (add a c)))");
+        // the interpreter makes the closure if a param has become special
+        let closure = vec![
+            Op::JumpIfSpecial(0, 3),
+            Op::Closure(0),
+            Op::Return,
+            Op::Interp(1, 0),
+            Op::Return,
+        ];
+        assert_eq!(proto.code, closure);
+
+        let outer = &proto.protos[0];
+        assert_eq!(outer.slots, vec!["a", "b"]);
+        assert_eq!(outer.code, closure);
+
+        // `a` is captured from slot 0 of the enclosing lambda
+        let inner = &outer.protos[0];
+        assert_eq!(inner.slots, vec!["c", "a"]);
+        assert_eq!(inner.captures, vec![(0, 1)]);
+        assert_eq!(
+            inner.code,
+            vec![Op::Function(0), Op::Local(1), Op::Local(0), Op::TailCall(2)]
+        );
+    }
+
+    #[test]
+    fn test_compile_let() {
+        let proto = compile_str("(lambda (a) (list (let ((b a)) b) b))");
+        let inner = &proto.protos[0];
+        assert_eq!(inner.slots, vec!["a", "b"]);
+        // `b` is a slot in the let body only, unless it is special by then
+        assert_eq!(
+            inner.code,
+            vec![
+                Op::Function(0),
+                Op::JumpIfSpecial(1, 6),
+                Op::Local(0),
+                Op::Bind(1),
+                Op::Local(1),
+                Op::Jump(7),
+                Op::Interp(2, 0),
+                Op::Global(3),
+                Op::TailCall(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_case() {
+        let proto = compile_str("(case x (1 a))");
+        assert_eq!(
+            proto.code,
+            vec![
+                Op::Global(0),
+                Op::Dup,
+                Op::Case(1, false),
+                Op::JumpIfNil(7),
+                Op::Pop,
+                Op::Global(2),
+                Op::Return,
+                Op::Pop,
+                Op::Const(3),
+                Op::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_loop() {
+        let proto = compile_str("(while x (f))");
+        assert_eq!(proto.slots, vec![Slot::Block("nil".into())]);
+        // a `return` from the loop leaves its value at the `Leave`
+        assert_eq!(
+            proto.code,
+            vec![
+                Op::Block(0, 9),
+                Op::Global(0),
+                Op::JumpIfNil(7),
+                Op::Function(1),
+                Op::Call(0),
+                Op::Pop,
+                Op::Jump(1),
+                Op::Const(2),
+                Op::Return,
+                Op::Leave,
+                Op::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_fallback() {
+        // forms the compiler doesn't know see every variable in scope
+        let proto = compile_str("(lambda (a) (lambda () (ignore-errors a)))");
+        let inner = &proto.protos[0].protos[0];
+        assert_eq!(inner.slots, vec!["a"]);
+        assert_eq!(inner.code, vec![Op::Interp(0, 0), Op::Return]);
+    }
+}
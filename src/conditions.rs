@@ -0,0 +1,466 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    atom::{Atom, Fun, SAtom},
+    env::Env,
+    lisp_error::{EvalError, LispError},
+    lisp_eval::{eval, Args, EvalResult, Extent, Tail, TailResult},
+    nil,
+    sexpr::SExpr,
+};
+
+/// A condition object, as created by `error`/`signal` or derived from a
+/// runtime error.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    /// Name of the condition type, e.g. `simple-error` or `type-error`.
+    pub kind: String,
+    pub message: String,
+    /// Extra arguments given to `error`/`signal`.
+    pub args: SAtom,
+    /// Errors are also matched by the `error` type.
+    pub is_error: bool,
+}
+
+impl Condition {
+    /// Whether the condition matches the type named `ty` in a handler clause.
+    pub fn is_a(&self, ty: &str) -> bool {
+        match ty {
+            "t" | "condition" => true,
+            "error" => self.is_error,
+            _ => self.kind == ty,
+        }
+    }
+}
+
+/// Entry of the dynamic handler stack kept in `Env::handlers`.
+#[derive(Clone)]
+pub enum Handler {
+    /// Established by `handler-bind`: the function is called with the
+    /// condition, and declines it by returning normally.
+    Bind(String, Arc<Fun>),
+    /// Established by `handler-case`/`ignore-errors`: a matching condition
+    /// unwinds up to the form, which then runs the clause.
+    Case(Vec<String>),
+}
+
+/// Run the handlers matching `cond`, innermost first.
+///
+/// Returns `Ok(true)` when a `handler-case` will take the condition, so the
+/// caller has to unwind, and `Ok(false)` when every handler declined.
+pub fn signal(s: &mut Env, cond: &Arc<Condition>) -> Result<bool, EvalError> {
+    let mut idx = s.handlers.len();
+    while idx > 0 {
+        idx -= 1;
+        match &s.handlers[idx] {
+            Handler::Case(types) if types.iter().any(|ty| cond.is_a(ty)) => return Ok(true),
+            Handler::Bind(ty, fun) if cond.is_a(ty) => {
+                let fun = fun.clone();
+                // The handler runs with only the handlers outside its own binding.
+                let inner = s.handlers.split_off(idx);
+                let args: SExpr = std::iter::once(Atom::Condition(cond.clone())).collect();
+                let res = fun.call(s, &Args::S(&args));
+                s.handlers.extend(inner);
+                res?;
+            }
+            _ => {}
+        }
+    }
+    Ok(false)
+}
+
+/// Give the handlers a chance to see a runtime error raised by the evaluator.
+pub fn signal_error(s: &mut Env, mut err: EvalError) -> EvalError {
+    if err.signalled || err.is_exit() {
+        return err;
+    }
+    err.signalled = true;
+    if s.handlers.is_empty() {
+        return err;
+    }
+
+    match signal(s, &err.error.condition()) {
+        Ok(_) => err,
+        Err(handler_err) => handler_err,
+    }
+}
+
+/// Build a condition from the arguments of `error`/`signal`:
+/// `(error "message" args...)`, `(error 'type "message" args...)` or
+/// `(error <condition>)`.
+fn make_condition(args: &Args, default_kind: &str, is_error: bool) -> Arc<Condition> {
+    let mut args = match args {
+        Args::S(args) => args.iter().collect::<Vec<_>>(),
+        Args::Nil => vec![],
+    };
+
+    let kind = match args.first().map(|datum| (**datum).clone()) {
+        Some(Atom::Condition(cond)) => return cond,
+        Some(Atom::Sym(kind)) => {
+            args.remove(0);
+            kind
+        }
+        _ => default_kind.to_string(),
+    };
+
+    let message = args
+        .iter()
+        .map(|arg| match &**arg {
+            Atom::Str(s) => s.clone(),
+            other => format!("{:?}", other),
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    let args = match args.is_empty() {
+        true => nil!().into(),
+        false => SAtom::new(Atom::Cons(args.into_iter().collect())),
+    };
+
+    Arc::new(Condition {
+        kind,
+        message,
+        args,
+        is_error,
+    })
+}
+
+/// Evaluate `body` forms in order, returning the value of the last one.
+pub fn eval_body(body: impl Iterator<Item = SAtom>, s: &mut Env) -> EvalResult {
+    let mut result: SAtom = nil!().into();
+    for form in body {
+        result = eval(form, s)?;
+    }
+    Ok(result)
+}
+
+fn get_sym(v: &SAtom) -> Result<String, LispError> {
+    match &**v {
+        Atom::Sym(name) => Ok(name.clone()),
+        _ => Err(LispError::type_error("symbol", v)),
+    }
+}
+
+pub fn install(fun_map: &mut HashMap<String, Arc<Fun>>) {
+    // (error <datum> <args>...) signals the condition, then unwinds
+    let error_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
+        if let Args::Nil = args {
+            return Err(LispError::arity("error", "at least 1", 0).into());
+        }
+        let cond = make_condition(args, "simple-error", true);
+        signal(s, &cond)?;
+        let mut err = EvalError::from(LispError::User(cond));
+        err.signalled = true;
+        Err(err)
+    }));
+
+    // (signal <datum> <args>...) returns nil when no handler takes the condition
+    let signal_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
+        if let Args::Nil = args {
+            return Err(LispError::arity("signal", "at least 1", 0).into());
+        }
+        let cond = make_condition(args, "simple-condition", false);
+        if signal(s, &cond)? {
+            let mut err = EvalError::from(LispError::User(cond));
+            err.signalled = true;
+            return Err(err);
+        }
+        Ok(nil!().into())
+    }));
+
+    let condition_accessor = |name: &'static str, get: fn(&Condition) -> Atom| {
+        Fun::Native(Box::new(move |_: &mut Env, args: &Args| -> EvalResult {
+            match args {
+                Args::S(SExpr { car, cdr }) if **cdr == Atom::Nil => match &**car {
+                    Atom::Condition(cond) => Ok(get(cond).into()),
+                    _ => Err(LispError::type_error("condition", car).into()),
+                },
+                Args::S(args) => Err(LispError::arity(name, "1", args.iter().count()).into()),
+                Args::Nil => Err(LispError::arity(name, "1", 0).into()),
+            }
+        }))
+    };
+
+    // (handler-case <form> (<type> ([<var>]) <body>...)...)
+    let handler_case_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
+        let (form, clauses) = match args {
+            Args::S(SExpr { car, cdr }) => (car.clone(), cdr.clone()),
+            Args::Nil => return Err(LispError::arity("handler-case", "at least 1", 0).into()),
+        };
+
+        let mut parsed = Vec::new();
+        if let Atom::Cons(clauses) = &*clauses {
+            for clause in clauses.iter() {
+                let Atom::Cons(SExpr { car: ty, cdr: rest }) = &*clause else {
+                    return Err(LispError::syntax("handler-case", "clause must be a list").into());
+                };
+                let Atom::Cons(SExpr {
+                    car: var_list,
+                    cdr: body,
+                }) = &**rest
+                else {
+                    return Err(
+                        LispError::syntax("handler-case", "clause needs a variable list").into(),
+                    );
+                };
+                let var = match &**var_list {
+                    Atom::Nil => None,
+                    Atom::Cons(vars) if *vars.cdr == Atom::Nil => Some(get_sym(&vars.car)?),
+                    _ => {
+                        return Err(LispError::syntax(
+                            "handler-case",
+                            "clause variable list takes at most one symbol",
+                        )
+                        .into())
+                    }
+                };
+                parsed.push((get_sym(ty)?, var, body.clone()));
+            }
+        }
+
+        let depth = s.handlers.len();
+        s.handlers.push(Handler::Case(
+            parsed.iter().map(|(ty, _, _)| ty.clone()).collect(),
+        ));
+        let res = eval(form, s);
+        s.handlers.truncate(depth);
+
+        let err = match res {
+            Ok(v) => return Ok(v),
+            Err(err) if err.is_exit() => return Err(err),
+            Err(err) => err,
+        };
+        let cond = err.error.condition();
+        let Some((_, var, body)) = parsed.iter().find(|(ty, _, _)| cond.is_a(ty)) else {
+            return Err(err);
+        };
+
+        let caller_val = s.val.clone();
+        if let Some(var) = var {
+            s.val
+                .insert(var.as_str().into(), Atom::Condition(cond).into());
+        }
+        let res = match &**body {
+            Atom::Cons(body) => eval_body(body.iter(), s),
+            _ => Ok(nil!().into()),
+        };
+        s.val = caller_val;
+        res
+    }));
+
+    // (handler-bind ((<type> <handler>)...) <body>...)
+    let handler_bind_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
+        let (bindings, body) = match args {
+            Args::S(SExpr { car, cdr }) => (car.clone(), cdr.clone()),
+            Args::Nil => return Err(LispError::arity("handler-bind", "at least 1", 0).into()),
+        };
+
+        let mut handlers = Vec::new();
+        if let Atom::Cons(bindings) = &*bindings {
+            for binding in bindings.iter() {
+                let Atom::Cons(binding) = &*binding else {
+                    return Err(LispError::syntax("handler-bind", "binding must be a list").into());
+                };
+                let mut iter = binding.iter();
+                let (Some(ty), Some(handler), None) = (iter.next(), iter.next(), iter.next())
+                else {
+                    return Err(LispError::syntax(
+                        "handler-bind",
+                        "binding must be (type handler)",
+                    )
+                    .into());
+                };
+                let ty = get_sym(&ty)?;
+                let handler = eval(handler, s)?;
+                match &*handler {
+                    Atom::Fun(fun) => handlers.push(Handler::Bind(ty, fun.clone())),
+                    _ => return Err(LispError::type_error("function", &handler).into()),
+                }
+            }
+        }
+
+        let depth = s.handlers.len();
+        // The first binding is the innermost one.
+        s.handlers.extend(handlers.into_iter().rev());
+        let res = match &*body {
+            Atom::Cons(body) => eval_body(body.iter(), s),
+            _ => Ok(nil!().into()),
+        };
+        s.handlers.truncate(depth);
+        res
+    }));
+
+    // (ignore-errors <body>...) returns nil if an error is signalled
+    let ignore_errors_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
+        let Args::S(body) = args else {
+            return Ok(nil!().into());
+        };
+
+        let depth = s.handlers.len();
+        s.handlers.push(Handler::Case(vec!["error".into()]));
+        let res = eval_body(body.iter(), s);
+        s.handlers.truncate(depth);
+
+        match res {
+            Err(err) if !err.is_exit() && err.error.condition().is_error => Ok(nil!().into()),
+            res => res,
+        }
+    }));
+
+    // (unwind-protect <protected> <cleanup>...) always runs the cleanup forms;
+    // the protected form is in tail position, the trampoline runs them after
+    let unwind_protect_op = Fun::Tail(Box::new(|s: &mut Env, args: &Args| -> TailResult {
+        let (protected, cleanup) = match args {
+            Args::S(SExpr { car, cdr }) => (car.clone(), cdr.clone()),
+            Args::Nil => return Err(LispError::arity("unwind-protect", "at least 1", 0).into()),
+        };
+        let cleanup = Extent::Cleanup(cleanup, s.val.clone());
+        Ok(Tail::Within(cleanup, Box::new(Tail::Eval(protected))))
+    }));
+
+    fun_map.insert("error".into(), error_op.into());
+    fun_map.insert("signal".into(), signal_op.into());
+    fun_map.insert(
+        "condition-type".into(),
+        condition_accessor("condition-type", |cond| Atom::Sym(cond.kind.clone())).into(),
+    );
+    fun_map.insert(
+        "condition-message".into(),
+        condition_accessor("condition-message", |cond| Atom::Str(cond.message.clone())).into(),
+    );
+    fun_map.insert(
+        "condition-args".into(),
+        condition_accessor("condition-args", |cond| (*cond.args).clone()).into(),
+    );
+    fun_map.insert("handler-case".into(), handler_case_op.into());
+    fun_map.insert("handler-bind".into(), handler_bind_op.into());
+    fun_map.insert("ignore-errors".into(), ignore_errors_op.into());
+    fun_map.insert("unwind-protect".into(), unwind_protect_op.into());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{env::Env, nil, num, str, sym, test_utils::run};
+
+    use super::*;
+
+    #[test]
+    fn test_error_and_handler_case() {
+        let env = &mut Env::default();
+
+        let res = run(
+            r#"(handler-case (add 1 (error "boom" 42))
+                 (error (c) (condition-message c)))"#,
+            env,
+        );
+        assert_eq!(*res.unwrap(), str!("boom 42"));
+
+        let res = run(
+            r#"(handler-case (error (quote my-error) "custom")
+                 (other-error () 1)
+                 (my-error (c) (condition-type c)))"#,
+            env,
+        );
+        assert_eq!(*res.unwrap(), sym!("my-error"));
+
+        // runtime errors are conditions too
+        let res = run(
+            "(handler-case (car 1) (unbound-variable () 1) (type-error () 2))",
+            env,
+        );
+        assert_eq!(*res.unwrap(), num!(2));
+
+        let res = run("(handler-case (missing 1) (error () 3))", env);
+        assert_eq!(*res.unwrap(), num!(3));
+
+        // no matching clause: the error keeps unwinding
+        let err = run("(handler-case (error (quote a)) (b () 1))", env).unwrap_err();
+        assert!(matches!(err.error, LispError::User(ref cond) if cond.kind == "a"));
+
+        // the value of the form is returned when nothing is signalled
+        let res = run("(handler-case (add 1 2) (error () 0))", env);
+        assert_eq!(*res.unwrap(), num!(3));
+    }
+
+    #[test]
+    fn test_signal() {
+        let env = &mut Env::default();
+
+        // unhandled signals are ignored
+        let res = run("(signal (quote note) \"hello\")", env);
+        assert_eq!(*res.unwrap(), nil!());
+
+        let res = run(
+            "(handler-case (signal (quote note) \"hello\") (note (c) (condition-message c)))",
+            env,
+        );
+        assert_eq!(*res.unwrap(), str!("hello"));
+
+        // a signalled condition is not an error
+        let res = run(
+            "(handler-case (ignore-errors (signal (quote note))) (note () 1))",
+            env,
+        );
+        assert_eq!(*res.unwrap(), num!(1));
+    }
+
+    #[test]
+    fn test_handler_bind() {
+        let env = &mut Env::default();
+        run("(defvar seen nil)", env).unwrap();
+
+        // the handler declines by returning, so the error reaches handler-case
+        let res = run(
+            r#"(handler-case
+                 (handler-bind ((error (lambda (c) (setq seen (condition-message c)))))
+                   (error "first"))
+                 (error () "caught"))"#,
+            env,
+        );
+        assert_eq!(*res.unwrap(), str!("caught"));
+        assert_eq!(*run("seen", env).unwrap(), str!("first"));
+
+        // handlers also see runtime errors, at the point they are raised
+        let res = run(
+            r#"(ignore-errors
+                 (handler-bind ((unbound-variable (lambda (c) (setq seen (condition-type c)))))
+                   (add 1 nowhere)))"#,
+            env,
+        );
+        assert_eq!(*res.unwrap(), nil!());
+        assert_eq!(*run("seen", env).unwrap(), sym!("unbound-variable"));
+
+        // a handler can replace the error by signalling another one
+        let err = run(
+            r#"(handler-bind ((error (lambda (c) (error (quote wrapped) (condition-message c)))))
+                 (error "inner"))"#,
+            env,
+        )
+        .unwrap_err();
+        assert!(matches!(err.error, LispError::User(ref cond)
+            if cond.kind == "wrapped" && cond.message == "inner"));
+        assert!(env.handlers.is_empty());
+    }
+
+    #[test]
+    fn test_ignore_errors_unwind_protect() {
+        let env = &mut Env::default();
+        run("(defvar cleaned 0)", env).unwrap();
+
+        assert_eq!(*run("(ignore-errors (error \"x\"))", env).unwrap(), nil!());
+        assert_eq!(*run("(ignore-errors 1 2)", env).unwrap(), num!(2));
+
+        let res = run(
+            "(ignore-errors (unwind-protect (error \"x\") (setq cleaned (add cleaned 1))))",
+            env,
+        );
+        assert_eq!(*res.unwrap(), nil!());
+        assert_eq!(*run("cleaned", env).unwrap(), num!(1));
+
+        let res = run(
+            "(unwind-protect (add 1 2) (setq cleaned (add cleaned 1)))",
+            env,
+        );
+        assert_eq!(*res.unwrap(), num!(3));
+        assert_eq!(*run("cleaned", env).unwrap(), num!(2));
+    }
+}
@@ -0,0 +1,316 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    atom::{Atom, Fun, SAtom},
+    env::{eval_in, progn_form, Binding, Env, Scope},
+    lisp_error::{EvalError, Exit, LispError},
+    lisp_eval::{eval, Args, EvalResult, Extent, Tail, TailResult},
+    nil,
+    sexpr::SExpr,
+};
+
+/// The exit `err` carries, if it is a non-local exit.
+fn exit_of(err: &EvalError) -> Option<&Exit> {
+    match &err.error {
+        LispError::Exit(exit) => Some(exit),
+        _ => None,
+    }
+}
+
+fn exit(exit: Exit) -> EvalResult {
+    Err(LispError::Exit(Box::new(exit)).into())
+}
+
+/// Name of a block, which must be a symbol (or `nil`).
+pub fn block_name(form: &'static str, v: &SAtom) -> Result<String, LispError> {
+    match &**v {
+        Atom::Sym(name) => Ok(name.clone()),
+        Atom::Nil => Ok("nil".into()),
+        _ => Err(LispError::syntax(form, "block name must be a symbol")),
+    }
+}
+
+/// Name of a `tagbody` tag, which is a symbol or an integer.
+pub fn tag_name(v: &Atom) -> Option<String> {
+    match v {
+        Atom::Sym(_) | Atom::Int(_) | Atom::Big(_) => Some(format!("{:?}", v)),
+        _ => None,
+    }
+}
+
+/// `return-from` the block `name` with `value`, if it is still active.
+fn return_from(s: &mut Env, form: &'static str, name: &str, value: Option<SAtom>) -> EvalResult {
+    let Some(marker) = s.val.get_block(name).cloned() else {
+        return Err(LispError::Control(format!("{form}: no block named {name}")).into());
+    };
+    let value = match value {
+        Some(value) if *marker.get() != Atom::Nil => eval(value, s)?,
+        _ => nil!().into(),
+    };
+    Err(block_exit(form, name, marker, value))
+}
+
+/// The exit of a `return-from`, or `return` as `form` says, with `value` to
+/// the block `name` marked by `marker`. It is an error once the block exited.
+pub fn block_exit(form: &str, name: &str, marker: Binding, value: SAtom) -> EvalError {
+    if *marker.get() == Atom::Nil {
+        return LispError::Control(format!("{form}: block {name} has already exited")).into();
+    }
+    LispError::Exit(Box::new(Exit::Block(marker, value))).into()
+}
+
+/// The exit of a `go` to `tag` in the tagbody marked by `marker`. It is an
+/// error once the tagbody exited.
+pub fn go_exit(marker: Binding, tag: SAtom) -> EvalError {
+    if *marker.get() == Atom::Nil {
+        let name = tag_name(&tag).unwrap();
+        return LispError::Control(format!("go: tagbody of {name} has already exited")).into();
+    }
+    LispError::Exit(Box::new(Exit::Go(marker, tag))).into()
+}
+
+/// Run `body` in `scope` extended with a block named `name`, returning the
+/// value of a `return-from` the block instead of its own if there is one.
+pub fn with_block(
+    s: &mut Env,
+    name: &str,
+    scope: &Scope,
+    body: impl FnOnce(&mut Env, Scope) -> EvalResult,
+) -> EvalResult {
+    let marker = block_marker(name);
+    let res = body(s, scope.extend_block(name.into(), marker.clone()));
+    Extent::Block(marker).close(res, s)
+}
+
+/// The marker of a new block: it holds the name while the block is active,
+/// `nil` after.
+fn block_marker(name: &str) -> Binding {
+    Binding::new(Atom::Sym(name.into()).into())
+}
+
+pub fn install(fun_map: &mut HashMap<String, Arc<Fun>>) {
+    // (block <name> <body>...) returns the value of a `return-from <name>`
+    // inside the body, or else of its last form, which is in tail position
+    let block_op = Fun::Tail(Box::new(|s: &mut Env, args: &Args| -> TailResult {
+        let Args::S(SExpr { car, cdr: body }) = args else {
+            return Err(LispError::arity("block", "at least 1", 0).into());
+        };
+        let name = block_name("block", car)?;
+        let marker = block_marker(&name);
+        let scope = s.val.extend_block(name.into(), marker.clone());
+        let body = Tail::Scope(progn_form(body), scope);
+        Ok(Tail::Within(Extent::Block(marker), Box::new(body)))
+    }));
+
+    // (return-from <name> [<value>])
+    let return_from_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
+        let args = args.to_vec();
+        let (name, value) = match &args[..] {
+            [name] => (name, None),
+            [name, value] => (name, Some(value.clone())),
+            _ => return Err(LispError::arity("return-from", "1 or 2", args.len()).into()),
+        };
+        let name = block_name("return-from", name)?;
+        return_from(s, "return-from", &name, value)
+    }));
+
+    // (return [<value>]) returns from the block named nil
+    let return_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
+        let args = args.to_vec();
+        if args.len() > 1 {
+            return Err(LispError::arity("return", "0 or 1", args.len()).into());
+        }
+        return_from(s, "return", "nil", args.first().cloned())
+    }));
+
+    // (tagbody <tag-or-form>...) evaluates the forms in order, jumping to a
+    // tag on `go`; it returns nil
+    let tagbody_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
+        let items = args.to_vec();
+        let tags: Vec<(usize, String)> = items
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, item)| Some((idx, tag_name(item)?)))
+            .collect();
+
+        // The marker is `t` while the tagbody is active, `nil` after.
+        let marker = Binding::new(Atom::T.into());
+        let mut scope = s
+            .val
+            .extend_tags(tags.iter().map(|(_, tag)| tag.as_str().into()), &marker);
+
+        let mut pc = 0;
+        let res = loop {
+            let Some(item) = items.get(pc) else {
+                break Ok(nil!().into());
+            };
+            pc += 1;
+            if !matches!(&**item, Atom::Cons(_)) {
+                continue;
+            }
+            match eval_in(item.clone(), &mut scope, s) {
+                Ok(_) => {}
+                Err(err) => match exit_of(&err) {
+                    Some(Exit::Go(target, tag)) if target.same(&marker) => {
+                        let tag = tag_name(tag);
+                        pc = tags
+                            .iter()
+                            .find(|(_, t)| Some(t) == tag.as_ref())
+                            .unwrap()
+                            .0;
+                    }
+                    _ => break Err(err),
+                },
+            }
+        };
+        marker.set(nil!().into());
+        res
+    }));
+
+    // (go <tag>) jumps to the tag of the enclosing tagbody
+    let go_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
+        let tag = match args {
+            Args::S(SExpr { car, cdr }) if **cdr == Atom::Nil => car.clone(),
+            _ => return Err(LispError::arity("go", "1", args.to_vec().len()).into()),
+        };
+        let Some(name) = tag_name(&tag) else {
+            return Err(LispError::syntax("go", "tag must be a symbol or a number").into());
+        };
+        let Some(marker) = s.val.get_tag(&name).cloned() else {
+            return Err(LispError::Control(format!("go: no tag named {name}")).into());
+        };
+        Err(go_exit(marker, tag))
+    }));
+
+    // (catch <tag> <body>...) returns the value of a `throw` to the tag from
+    // anywhere in the dynamic extent of the body, or else of its last form,
+    // which is in tail position
+    let catch_op = Fun::Tail(Box::new(|s: &mut Env, args: &Args| -> TailResult {
+        let Args::S(SExpr { car, cdr: body }) = args else {
+            return Err(LispError::arity("catch", "at least 1", 0).into());
+        };
+        let tag = eval(car.clone(), s)?;
+
+        let depth = s.catches.len();
+        s.catches.push(tag.clone());
+        let body = Tail::Eval(progn_form(body));
+        Ok(Tail::Within(Extent::Catch(tag, depth), Box::new(body)))
+    }));
+
+    // (throw <tag> <value>)
+    let throw_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
+        let args = args.to_vec();
+        let [tag, value] = &args[..] else {
+            return Err(LispError::arity("throw", "2", args.len()).into());
+        };
+        if !s.catches.contains(tag) {
+            return Err(LispError::Control(format!("throw: no catch for tag {:?}", tag)).into());
+        }
+        exit(Exit::Throw(tag.clone(), value.clone()))
+    }));
+
+    fun_map.insert("block".into(), block_op.into());
+    fun_map.insert("return-from".into(), return_from_op.into());
+    fun_map.insert("return".into(), return_op.into());
+    fun_map.insert("tagbody".into(), tagbody_op.into());
+    fun_map.insert("go".into(), go_op.into());
+    fun_map.insert("catch".into(), catch_op.into());
+    fun_map.insert("throw".into(), throw_op.into());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{num, sexpr, sym, test_utils::run};
+
+    use super::*;
+
+    #[test]
+    fn test_block_return_from() {
+        let env = &mut Env::default();
+        assert_eq!(
+            *run("(block done 1 (return-from done 2) 3)", env).unwrap(),
+            num!(2)
+        );
+        assert_eq!(*run("(block done 1 2)", env).unwrap(), num!(2));
+        assert_eq!(*run("(block nil (return))", env).unwrap(), nil!());
+
+        // return-from is lexical: a closure exits the block it was made in
+        run(
+            "(defun each (f xs) (if (eq xs nil) nil (progn (funcall f (car xs)) (each f (cdr xs)))))",
+            env,
+        )
+        .unwrap();
+        run(
+            "(defun find-first (pred xs) (block search (each (lambda (x) (if (funcall pred x) (return-from search x) nil)) xs) nil))",
+            env,
+        )
+        .unwrap();
+        assert_eq!(
+            *run("(find-first (lambda (x) (eq x 3)) (list 1 2 3 4))", env).unwrap(),
+            num!(3)
+        );
+
+        // ...but not after the block has exited
+        run(
+            "(defvar escape (block b (lambda () (return-from b 1))))",
+            env,
+        )
+        .unwrap();
+        let err = run("(funcall escape)", env).unwrap_err();
+        assert_eq!(
+            err.error.to_string(),
+            "return-from: block b has already exited"
+        );
+        let err = run("(return-from nowhere 1)", env).unwrap_err();
+        assert_eq!(err.error.kind_name(), "control-error");
+    }
+
+    #[test]
+    fn test_tagbody_go() {
+        let env = &mut Env::default();
+        run("(defvar n 0)", env).unwrap();
+        run("(defvar acc nil)", env).unwrap();
+        let res = run(
+            "(tagbody top (setq acc (cons n acc)) (setq n (add n 1)) (if (eq n 3) (go 10) nil) (go top) 10)",
+            env,
+        )
+        .unwrap();
+        assert_eq!(*res, nil!());
+        assert_eq!(*run("acc", env).unwrap(), sexpr!(num!(2), num!(1), num!(0)));
+
+        let err = run("(go top)", env).unwrap_err();
+        assert_eq!(err.error.to_string(), "go: no tag named top");
+    }
+
+    #[test]
+    fn test_catch_throw() {
+        let env = &mut Env::default();
+        run(
+            "(defun deep (n) (if (eq n 0) (throw 'found 'bottom) (deep (sub n 1))))",
+            env,
+        )
+        .unwrap();
+        assert_eq!(
+            *run("(catch 'found (deep 10) 'never)", env).unwrap(),
+            sym!("bottom")
+        );
+        assert_eq!(*run("(catch 'found 1 2)", env).unwrap(), num!(2));
+
+        // the innermost catch of the tag takes the throw
+        let res = run("(catch 'a (list (catch 'a (throw 'a 1)) 2))", env).unwrap();
+        assert_eq!(*res, sexpr!(num!(1), num!(2)));
+
+        // exits are not errors: handlers don't see them, cleanups still run
+        run("(defvar cleaned nil)", env).unwrap();
+        let res = run(
+            "(catch 'x (handler-case (ignore-errors (unwind-protect (throw 'x 'thrown) (setq cleaned t))) (t (c) 'handled)))",
+            env,
+        )
+        .unwrap();
+        assert_eq!(*res, sym!("thrown"));
+        assert_eq!(*run("cleaned", env).unwrap(), Atom::T);
+
+        let err = run("(throw 'nobody 1)", env).unwrap_err();
+        assert_eq!(err.error.to_string(), "throw: no catch for tag nobody");
+    }
+}
@@ -1,8 +1,8 @@
 #[macro_export]
 macro_rules! num {
     ($x:expr) => {{
-        use crate::atom::Atom;
-        let r: Atom = Atom::Num(($x).into());
+        use $crate::atom::Atom;
+        let r: Atom = Atom::from($x);
         r
     }};
 }
@@ -16,7 +16,7 @@ macro_rules! str {
 #[macro_export]
 macro_rules! sym {
     ($x:expr) => {{
-        use crate::atom::Atom;
+        use $crate::atom::Atom;
         let r: Atom = Atom::Sym(($x).into());
         r
     }};
@@ -24,7 +24,7 @@ macro_rules! sym {
 #[macro_export]
 macro_rules! nil {
     () => {{
-        use crate::atom::Atom;
+        use $crate::atom::Atom;
         let r: Atom = Atom::Nil;
         r
     }};
@@ -32,7 +32,7 @@ macro_rules! nil {
 #[macro_export]
 macro_rules! t {
     () => {{
-        use crate::atom::Atom;
+        use $crate::atom::Atom;
         let r: Atom = Atom::T;
         r
     }};
@@ -41,13 +41,13 @@ macro_rules! t {
 macro_rules! sexpr {
     // empty list
     () => {
-        use crate::atom::Atom;
+        use $crate::atom::Atom;
         Atom::Nil
     };
 
     // one or more elements (atoms or vals), comma-separated
     ($($x:expr),+ $(,)?) => {{
-        use crate::atom::Atom;
+        use $crate::atom::Atom;
         let r: Atom = Atom::Cons(vec![ $( ($x) ),+ ].into_iter().collect());
         r
     }};
@@ -57,8 +57,8 @@ macro_rules! sexpr {
 macro_rules! cons {
     // two elements (atoms or vals), comma-separated
     ($car:expr, $cdr:expr $(,)?) => {{
-        use crate::atom::Atom;
-        use crate::sexpr::SExpr;
+        use $crate::atom::Atom;
+        use $crate::sexpr::SExpr;
         let r: Atom = Atom::Cons(SExpr {
             car: ($car).into(),
             cdr: ($cdr).into(),
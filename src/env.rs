@@ -1,17 +1,194 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    mem,
+    sync::{Arc, Mutex},
+};
 
 use crate::{
     atom::{Atom, Fun, SAtom, UserFn},
-    lisp_eval::{eval, Args, EvalResult},
-    nil, num,
+    conditions::{self, Handler},
+    control, iteration,
+    lambda_list::LambdaList,
+    lisp_error::{EvalError, LispError},
+    lisp_eval::{eval, macroexpand_1, run, Args, EvalResult, Tail, TailResult},
+    loop_macro, math, nil,
+    number::Number,
+    quasiquote, random,
     sexpr::SExpr,
-    t,
+    strings, t,
+    vm::ProtoCache,
 };
 
+/// How `eval` runs forms.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Backend {
+    /// Walk the forms directly.
+    #[default]
+    Interpreter,
+    /// Compile each form to bytecode and run it on the VM.
+    Vm,
+}
+
+/// A variable binding. Clones share the binding, so closures over the same
+/// scope see each other's assignments.
+#[derive(Clone, Debug, Default)]
+pub struct Binding(Arc<Mutex<SAtom>>);
+
+impl Binding {
+    pub fn new(value: SAtom) -> Self {
+        Binding(Arc::new(Mutex::new(value)))
+    }
+
+    pub fn get(&self) -> SAtom {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn set(&self, value: SAtom) {
+        *self.0.lock().unwrap() = value;
+    }
+
+    /// Whether both are clones of the same binding.
+    pub fn same(&self, other: &Binding) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl From<SAtom> for Binding {
+    fn from(value: SAtom) -> Self {
+        Binding::new(value)
+    }
+}
+
+impl From<Atom> for Binding {
+    fn from(value: Atom) -> Self {
+        Binding::new(value.into())
+    }
+}
+
+/// A lexical scope: a chain of small frames, innermost first. Clones share
+/// the frames, so capturing a scope is O(1) and extending it costs O(names).
+#[derive(Clone, Default)]
+pub struct Scope(Option<Arc<Frame>>);
+
+/// Names bound in a frame, newest last.
+type Names = Vec<(Arc<str>, Binding)>;
+
+#[derive(Default)]
+struct Frame {
+    vars: Names,
+    /// Local functions of `flet`/`labels`, bound to function values.
+    funs: Names,
+    /// Names of the enclosing `block`s, bound to their exit markers.
+    blocks: Names,
+    /// Tags of the enclosing `tagbody`s, bound to the tagbody exit marker.
+    tags: Names,
+    parent: Scope,
+}
+
+impl Scope {
+    /// The binding of `name` in the namespace picked by `ns`, searching the
+    /// innermost frame first.
+    fn lookup(&self, name: &str, ns: fn(&Frame) -> &Names) -> Option<&Binding> {
+        let mut scope = self;
+        while let Some(frame) = &scope.0 {
+            if let Some((_, binding)) = ns(frame).iter().rev().find(|(n, _)| &**n == name) {
+                return Some(binding);
+            }
+            scope = &frame.parent;
+        }
+        None
+    }
+
+    /// The binding of the variable `name`.
+    pub fn get(&self, name: &str) -> Option<&Binding> {
+        self.lookup(name, |frame| &frame.vars)
+    }
+
+    /// The binding of the local function `name`.
+    pub fn get_fun(&self, name: &str) -> Option<&Binding> {
+        self.lookup(name, |frame| &frame.funs)
+    }
+
+    /// The exit marker of the `block` named `name`.
+    pub fn get_block(&self, name: &str) -> Option<&Binding> {
+        self.lookup(name, |frame| &frame.blocks)
+    }
+
+    /// The exit marker of the `tagbody` holding the tag `name`.
+    pub fn get_tag(&self, name: &str) -> Option<&Binding> {
+        self.lookup(name, |frame| &frame.tags)
+    }
+
+    /// A new scope with `frame` on top of this one.
+    fn push(&self, frame: Frame) -> Scope {
+        Scope(Some(Arc::new(Frame {
+            parent: self.clone(),
+            ..frame
+        })))
+    }
+
+    /// A new scope with `vars` bound on top of this one.
+    pub fn extend(&self, vars: impl IntoIterator<Item = (Arc<str>, Binding)>) -> Scope {
+        self.push(Frame {
+            vars: vars.into_iter().collect(),
+            ..Default::default()
+        })
+    }
+
+    /// A new scope with the local functions `funs` bound on top of this one.
+    pub fn extend_funs(&self, funs: impl IntoIterator<Item = (Arc<str>, Binding)>) -> Scope {
+        self.push(Frame {
+            funs: funs.into_iter().collect(),
+            ..Default::default()
+        })
+    }
+
+    /// A new scope with the block `name` bound to `marker` on top of this one.
+    pub fn extend_block(&self, name: Arc<str>, marker: Binding) -> Scope {
+        self.push(Frame {
+            blocks: vec![(name, marker)],
+            ..Default::default()
+        })
+    }
+
+    /// A new scope with every tag in `tags` bound to `marker` on top of this one.
+    pub fn extend_tags(&self, tags: impl IntoIterator<Item = Arc<str>>, marker: &Binding) -> Scope {
+        self.push(Frame {
+            tags: tags.into_iter().map(|tag| (tag, marker.clone())).collect(),
+            ..Default::default()
+        })
+    }
+
+    /// Bind `name` in the innermost frame, or in a new one if that frame is
+    /// shared, e.g. captured by a closure.
+    pub fn insert(&mut self, name: Arc<str>, binding: Binding) {
+        match self.0.as_mut().and_then(Arc::get_mut) {
+            Some(frame) => frame.vars.push((name, binding)),
+            None => *self = self.extend([(name, binding)]),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Env {
-    pub val: HashMap<String, SAtom>,
-    pub fun: Arc<HashMap<String, Fun>>,
+    pub val: Scope,
+    pub global: HashMap<String, SAtom>,
+    /// Special variables declared by `defvar`/`defparameter`, which `let`
+    /// binds dynamically by swapping their global value.
+    pub specials: HashSet<String>,
+    pub fun: Arc<HashMap<String, Arc<Fun>>>,
+    /// Macro transformers by name, checked before `fun` (copy-on-write).
+    pub macros: Arc<HashMap<String, Arc<Fun>>>,
+    /// Dynamic stack of condition handlers, innermost last.
+    pub handlers: Vec<Handler>,
+    /// Tags of the active `catch` forms, innermost last.
+    pub catches: Vec<SAtom>,
+    pub backend: Backend,
+    /// Forms the VM compiled, so it doesn't compile them again.
+    pub compiled: ProtoCache,
+    /// Print every form the interpreter evaluates, with its value.
+    pub trace: bool,
 }
 
 macro_rules! take_args {
@@ -32,70 +209,456 @@ fn get_args_count(args: &Args) -> usize {
     }
 }
 
-pub fn get_args_from_val(args: &Atom, s: &mut Env, eval_args: bool) -> SExpr {
+pub fn get_args_from_val(args: &Atom, s: &mut Env, eval_args: bool) -> Result<SExpr, EvalError> {
     match args {
         Atom::Cons(sexpr) => sexpr
             .iter()
-            .map(|it| {
-                if eval_args {
-                    eval(it, s).expect("Coudn't eval arg")
-                } else {
-                    it
+            .map(|it| if eval_args { eval(it, s) } else { Ok(it) })
+            .collect::<Result<SExpr, _>>(),
+        _ => Err(LispError::type_error("argument list", &args.clone().into()).into()),
+    }
+}
+
+fn get_val_form_sym(sname: &str, s: &Env) -> Result<Atom, LispError> {
+    Ok((*s
+        .get_val(sname)
+        .ok_or_else(|| LispError::UnboundVariable(sname.into()))?)
+    .clone())
+}
+
+/// The args of a call to `name`, checking there are `min` to `max` of them
+/// (`usize::MAX` for no limit).
+pub fn check_arity(
+    name: &str,
+    args: &Args,
+    min: usize,
+    max: usize,
+) -> Result<Vec<SAtom>, LispError> {
+    let args = args.to_vec();
+    if args.len() < min || args.len() > max {
+        let expected = match (min, max) {
+            (min, usize::MAX) => format!("at least {min}"),
+            (min, max) if min == max => min.to_string(),
+            (min, max) if min + 1 == max => format!("{min} or {max}"),
+            (min, max) => format!("{min} to {max}"),
+        };
+        return Err(LispError::arity(name, &expected, args.len()));
+    }
+    Ok(args)
+}
+
+/// The number `v` holds, or a type error.
+pub fn get_num(v: &SAtom) -> Result<Number, LispError> {
+    Number::from_atom(v).ok_or_else(|| LispError::type_error("number", v))
+}
+
+/// The elements of the proper list `v`.
+pub fn list_items(v: &SAtom) -> Result<Vec<SAtom>, LispError> {
+    match &**v {
+        Atom::Nil => Ok(Vec::new()),
+        Atom::Cons(list) => Ok(list.iter().collect()),
+        _ => Err(LispError::type_error("list", v)),
+    }
+}
+
+/// The single form of a body, or `(progn <body>...)` when it has several.
+pub fn progn_form(body: &SAtom) -> SAtom {
+    match &**body {
+        Atom::Cons(SExpr { car, cdr }) if **cdr == Atom::Nil => car.clone(),
+        _ => SAtom::new(Atom::Cons(SExpr {
+            car: SAtom::new(Atom::Sym("progn".into())),
+            cdr: body.clone(),
+        })),
+    }
+}
+
+/// The body of the function `name` as one form, in a block named after the
+/// function. The block is only made when the body has a `return-from name`:
+/// it keeps the body on the Rust stack, so tail calls out of it would grow it.
+pub fn function_body(name: &str, body: &SAtom) -> SAtom {
+    if !returns_from(body, name) {
+        return progn_form(body);
+    }
+    SAtom::new(Atom::Cons(SExpr {
+        car: SAtom::new(Atom::Sym("block".into())),
+        cdr: SAtom::new(Atom::Cons(SExpr {
+            car: SAtom::new(Atom::Sym(name.into())),
+            cdr: body.clone(),
+        })),
+    }))
+}
+
+/// Whether `v` has a `(return-from name ...)` form anywhere.
+fn returns_from(v: &Atom, name: &str) -> bool {
+    let Atom::Cons(list) = v else {
+        return false;
+    };
+    if let (Atom::Sym(op), Atom::Cons(args)) = (&*list.car, &*list.cdr) {
+        if op == "return-from" && matches!(&*args.car, Atom::Sym(target) if target == name) {
+            return true;
+        }
+    }
+    list.iter().any(|item| returns_from(&item, name))
+}
+
+/// Parse the bindings of a `let`-like form: `name`, `(name)` or `(name <init>)`.
+pub fn parse_bindings(
+    form: &'static str,
+    v: &Atom,
+) -> Result<Vec<(String, Option<SAtom>)>, LispError> {
+    let bindings = match v {
+        Atom::Nil => return Ok(vec![]),
+        Atom::Cons(bindings) => bindings,
+        _ => {
+            return Err(LispError::syntax(
+                form,
+                "expects a binding list as first arg",
+            ))
+        }
+    };
+
+    let mut out = Vec::new();
+    for binding in bindings.iter() {
+        let (name, init) = match &*binding {
+            Atom::Sym(name) => (name, None),
+            Atom::Cons(SExpr { car, cdr }) => match (&**car, &**cdr) {
+                (Atom::Sym(name), Atom::Nil) => (name, None),
+                (Atom::Sym(name), Atom::Cons(SExpr { car: init, cdr })) if **cdr == Atom::Nil => {
+                    (name, Some(init.clone()))
                 }
-            })
-            .collect::<SExpr>(),
-        _ => todo!(),
+                _ => return Err(LispError::syntax(form, "binding must be (symbol value)")),
+            },
+            _ => {
+                return Err(LispError::syntax(
+                    form,
+                    "binding must be a symbol or a list",
+                ))
+            }
+        };
+        out.push((name.clone(), init));
     }
+    Ok(out)
 }
 
-fn get_val_form_sym(sname: &str, s: &Env) -> Atom {
-    (*s.val[sname]).clone()
+/// Parse the definitions of `flet`/`labels`: `(name (params...) body...)`.
+pub fn parse_fun_bindings(
+    form: &'static str,
+    v: &Atom,
+) -> Result<Vec<(String, SAtom, SAtom)>, LispError> {
+    let defs = match v {
+        Atom::Nil => return Ok(vec![]),
+        Atom::Cons(defs) => defs,
+        _ => {
+            return Err(LispError::syntax(
+                form,
+                "expects a definition list as first arg",
+            ))
+        }
+    };
+
+    let mut out = Vec::new();
+    for def in defs.iter() {
+        let Atom::Cons(SExpr { car: name, cdr }) = &*def else {
+            return Err(LispError::syntax(form, "definition must be a list"));
+        };
+        match (&**name, &**cdr) {
+            (
+                Atom::Sym(name),
+                Atom::Cons(SExpr {
+                    car: params,
+                    cdr: body,
+                }),
+            ) => out.push((name.clone(), params.clone(), body.clone())),
+            _ => {
+                return Err(LispError::syntax(
+                    form,
+                    "definition must be (name (params...) body...)",
+                ))
+            }
+        }
+    }
+    Ok(out)
 }
 
-pub fn get_num(v: SAtom, s: &mut Env) -> Result<f64, &'static str> {
-    match &*v {
-        Atom::Num(n) => Ok(*n),
+/// Evaluate `form` with `scope` as the lexical bindings.
+pub fn eval_in(form: SAtom, scope: &mut Scope, s: &mut Env) -> EvalResult {
+    mem::swap(&mut s.val, scope);
+    let res = eval(form, s);
+    mem::swap(&mut s.val, scope);
+    res
+}
+
+/// A `case` key as the value it matches: the symbols `nil` and `t` stand for
+/// themselves, everything else is matched literally.
+fn case_key(key: &Atom) -> Atom {
+    match key {
+        Atom::Sym(name) if name == "nil" => Atom::Nil,
+        Atom::Sym(name) if name == "t" => Atom::T,
+        key => key.clone(),
+    }
+}
+
+/// Whether `key` is one of the `keys` of a `case` clause. `t` and `otherwise`
+/// match any key, except in an `ecase` (`exhaustive`).
+pub fn case_matches(keys: &Atom, key: &Atom, exhaustive: bool) -> bool {
+    match keys {
+        Atom::Sym(name) if name == "nil" => false,
+        Atom::Sym(name) if !exhaustive && (name == "t" || name == "otherwise") => true,
+        Atom::Cons(keys) => keys.iter().any(|k| case_key(&k) == *key),
+        _ => case_key(keys) == *key,
+    }
+}
 
-        Atom::Sym(sym) => {
-            let bound = s.val.get(sym).ok_or("Unknown symbol")?;
-            match bound.as_ref() {
-                Atom::Num(n) => Ok(*n),
-                _ => Err("Unsupported variable type"),
+/// Bind the `let` `bindings` lexically in `scope`, or dynamically for special
+/// variables, recording their old values in `saved` even if an init fails.
+fn bind_let(
+    bindings: Vec<(String, Option<SAtom>)>,
+    sequential: bool,
+    scope: &mut Scope,
+    saved: &mut Vec<(String, Option<SAtom>)>,
+    s: &mut Env,
+) -> Result<(), EvalError> {
+    let mut specials = Vec::new();
+    for (name, init) in bindings {
+        let value = match init {
+            Some(init) if sequential => eval_in(init, scope, s)?,
+            Some(init) => eval(init, s)?,
+            None => nil!().into(),
+        };
+        match s.is_special(&name) {
+            true if sequential => saved.push(s.bind_special(name, value)),
+            true => specials.push((name, value)),
+            false => scope.insert(name.into(), value.into()),
+        }
+    }
+    for (name, value) in specials {
+        saved.push(s.bind_special(name, value));
+    }
+    Ok(())
+}
+
+/// The function named `name`, as a value.
+fn fun_value(s: &Env, name: &str) -> EvalResult {
+    match s.get_fun(name) {
+        Some(fun) => Ok(Atom::Fun(fun).into()),
+        None => Err(LispError::UnboundFunction(name.into()).into()),
+    }
+}
+
+/// The global function a symbol designates. Unlike a function name in a
+/// call or in `function`, a symbol value never refers to a local function
+/// of `flet`/`labels`.
+fn global_fun(s: &Env, name: &str) -> Result<Arc<Fun>, LispError> {
+    s.fun
+        .get(name)
+        .cloned()
+        .ok_or_else(|| LispError::UnboundFunction(name.into()))
+}
+
+/// The function a `funcall`/`apply` designator stands for: a function, a
+/// symbol naming a global function, or a form evaluating to a function.
+pub fn designated_fun(s: &mut Env, fun: &SAtom) -> Result<Arc<Fun>, EvalError> {
+    match &**fun {
+        Atom::Fun(fun) => Ok(fun.clone()),
+        Atom::Sym(name) => Ok(global_fun(s, name)?),
+        Atom::Cons(_) => {
+            let value = eval(fun.clone(), s)?;
+            match &*value {
+                Atom::Fun(fun) => Ok(fun.clone()),
+                _ => Err(LispError::type_error("function", &value).into()),
             }
         }
+        _ => Err(LispError::type_error("function", fun).into()),
+    }
+}
 
-        Atom::Cons(_) => match *eval(v, s)? {
-            Atom::Num(n) => Ok(n),
-            _ => Err("Unsupported type"),
-        },
+/// Build the user function `name` closing over the lexical scope `captured`.
+/// Its params that are special variables of `s` now are bound dynamically.
+fn make_lambda(
+    name: &str,
+    s: &Env,
+    captured: &Scope,
+    params_val: &Atom,
+    body_val: SAtom,
+) -> Result<Fun, LispError> {
+    let mut params = LambdaList::parse(params_val)?;
+    params.find_specials(s);
+    let name = name.to_string();
+    // Lexical capture: the bindings are shared with the enclosing scope
+    let captured = captured.clone();
 
-        _ => Err("Unsupported type"),
+    let user_fn: UserFn = Box::new((
+        body_val.clone(),
+        Box::new(move |s: &mut Env, call_args: &Args| -> TailResult {
+            // The body runs in the lambda lexical env + bound params
+            let mut saved = Vec::new();
+            let res = params.bind(&name, call_args.to_vec(), &captured, &mut saved, s);
+            if saved.is_empty() {
+                return Ok(Tail::Call(body_val.clone(), res?));
+            }
+
+            // dynamic bindings are undone when the body exits, so it can't
+            // be left to the caller as a tail call
+            let res = res.and_then(|bindings| run(Tail::Call(body_val.clone(), bindings), s));
+            s.restore_specials(saved);
+            res.map(Tail::Done)
+        }),
+    ));
+    Ok(Fun::User(user_fn))
+}
+
+impl Env {
+    /// Look a variable up in the lexical bindings first, then in the globals.
+    /// Keywords like `:key` evaluate to themselves.
+    pub fn get_val(&self, name: &str) -> Option<SAtom> {
+        match self.val.get(name) {
+            Some(binding) => Some(binding.get()),
+            None if name.starts_with(':') => Some(Atom::Sym(name.into()).into()),
+            None => self.global.get(name).cloned(),
+        }
+    }
+
+    /// Look a function up in the local functions first, then in the globals.
+    pub fn get_fun(&self, name: &str) -> Option<Arc<Fun>> {
+        if let Some(binding) = self.val.get_fun(name) {
+            if let Atom::Fun(fun) = &*binding.get() {
+                return Some(fun.clone());
+            }
+        }
+        self.fun.get(name).cloned()
+    }
+
+    /// Install `fun` as the function named `name`, replacing a macro of the
+    /// same name.
+    pub fn set_fun(&mut self, name: String, fun: Arc<Fun>) {
+        if self.macros.contains_key(&name) {
+            Arc::make_mut(&mut self.macros).remove(&name);
+        }
+        Arc::make_mut(&mut self.fun).insert(name, fun);
+    }
+
+    /// Whether `name` was declared special by `defvar`/`defparameter`.
+    pub fn is_special(&self, name: &str) -> bool {
+        self.specials.contains(name)
+    }
+
+    /// Give the special variable `name` a new dynamic value, returning the
+    /// old one for `restore_specials`.
+    pub fn bind_special(&mut self, name: String, value: SAtom) -> (String, Option<SAtom>) {
+        let old = self.global.insert(name.clone(), value);
+        (name, old)
+    }
+
+    /// Undo `bind_special`s, newest last.
+    pub fn restore_specials(&mut self, saved: Vec<(String, Option<SAtom>)>) {
+        for (name, old) in saved.into_iter().rev() {
+            match old {
+                Some(value) => self.global.insert(name, value),
+                None => self.global.remove(&name),
+            };
+        }
+    }
+
+    /// Assign an existing lexical binding, or fall back to a global one.
+    pub fn set_val(&mut self, name: String, value: SAtom) {
+        match self.val.get(&name) {
+            Some(binding) => binding.set(value),
+            None => {
+                self.global.insert(name, value);
+            }
+        }
     }
 }
 
 impl Default for Env {
     fn default() -> Self {
-        let mut fun_map: HashMap<String, Fun> = HashMap::new();
+        let mut fun_map: HashMap<String, Arc<Fun>> = HashMap::new();
 
-        let binary_ops = |op: fn(f64, f64) -> f64| {
-            Fun::Native(Box::new(move |s: &mut Env, args: &Args| {
-                if get_args_count(args) < 2 {
-                    return Err("Expected at least 2 args");
+        // Arithmetic folds `op` over the args, starting from `identity` when
+        // there are fewer than 2 of them: `(+)` is 0, `(- x)` is `(- 0 x)` and
+        // `(/ x)` is `(/ 1 x)`. `min` is the smallest number of args.
+        let arith_ops = |name: &'static str,
+                         op: fn(Number, Number) -> Result<Number, LispError>,
+                         identity: i64,
+                         min: usize| {
+            Fun::Native(Box::new(move |_: &mut Env, args: &Args| -> EvalResult {
+                let nums = args
+                    .to_vec()
+                    .iter()
+                    .map(get_num)
+                    .collect::<Result<Vec<_>, _>>()?;
+                if nums.len() < min {
+                    return Err(
+                        LispError::arity(name, &format!("at least {min}"), nums.len()).into(),
+                    );
+                }
+                let mut nums = nums.into_iter();
+                let mut acc = match nums.len() {
+                    0 | 1 => Number::Int(identity),
+                    _ => nums.next().unwrap(),
                 };
-                match args {
-                    Args::S(args) => {
-                        let mut iter = args.iter();
-                        let first = iter.next().ok_or("Expected at least 2 args")?;
-                        let mut acc = get_num(first, s)?;
+                for n in nums {
+                    acc = op(acc, n)?;
+                }
+                Ok(acc.into_atom().into())
+            }))
+        };
+
+        // Variadic comparisons hold when `test` holds for the ordering of every
+        // pair of neighbouring args, or of every pair of args for `/=`.
+        let compare_ops = |name: &'static str, all_pairs: bool, test: fn(Ordering) -> bool| {
+            Fun::Native(Box::new(move |_: &mut Env, args: &Args| -> EvalResult {
+                let nums = args
+                    .to_vec()
+                    .iter()
+                    .map(get_num)
+                    .collect::<Result<Vec<_>, _>>()?;
+                if nums.is_empty() {
+                    return Err(LispError::arity(name, "at least 1", 0).into());
+                }
+                // NaN is unordered, so no comparison with it holds
+                let holds = |a: &Number, b: &Number| a.compare(b).is_some_and(test);
+                let res = match all_pairs {
+                    true => nums
+                        .iter()
+                        .enumerate()
+                        .all(|(idx, a)| nums[idx + 1..].iter().all(|b| holds(a, b))),
+                    false => nums.windows(2).all(|pair| holds(&pair[0], &pair[1])),
+                };
+                Ok(if res { t!() } else { nil!() }.into())
+            }))
+        };
 
-                        for v in iter {
-                            let b = get_num(v, s)?;
-                            acc = op(acc, b);
-                        }
+        // `test` is `None` for numbers the predicate doesn't apply to.
+        let number_predicates = |name: &'static str, test: fn(&Number) -> Option<bool>| {
+            Fun::Native(Box::new(move |_: &mut Env, args: &Args| -> EvalResult {
+                let arg = match args {
+                    Args::S(SExpr { car, cdr }) if **cdr == Atom::Nil => car.clone(),
+                    _ => return Err(LispError::arity(name, "1", get_args_count(args)).into()),
+                };
+                let n = get_num(&arg)?;
+                match test(&n) {
+                    Some(true) => Ok(t!().into()),
+                    Some(false) => Ok(nil!().into()),
+                    None => Err(LispError::type_error("integer", &n.into_atom().into()).into()),
+                }
+            }))
+        };
 
-                        Ok(num!(acc).into())
-                    }
-                    Args::Nil => Err("Calling binary operator with less then 2 args"),
+        // `min`/`max`: the first arg that no other is ordered `keep` of
+        let extreme_ops = |name: &'static str, keep: Ordering| {
+            Fun::Native(Box::new(move |_: &mut Env, args: &Args| -> EvalResult {
+                let mut best: Option<Number> = None;
+                for v in args.to_vec() {
+                    let n = get_num(&v)?;
+                    best = match best {
+                        Some(best) if n.compare(&best) != Some(keep) => Some(best),
+                        _ => Some(n),
+                    };
+                }
+                match best {
+                    Some(best) => Ok(best.into_atom().into()),
+                    None => Err(LispError::arity(name, "at least 1", 0).into()),
                 }
             }))
         };
@@ -107,11 +670,11 @@ impl Default for Env {
                         // (car <sexpr>)
                         Atom::Cons(SExpr { car, .. }) => Ok(car.clone()),
                         // (car <symbol>)
-                        Atom::Sym(sym) => match get_val_form_sym(sym, s) {
+                        Atom::Sym(sym) => match get_val_form_sym(sym, s)? {
                             Atom::Cons(SExpr { car, .. }) => Ok(car),
-                            _ => Err("Unsupported type of symbol"),
+                            other => Err(LispError::type_error("list", &other.into()).into()),
                         },
-                        _ => Err("Unsupported type"),
+                        _ => Err(LispError::type_error("list", &sexpr.car).into()),
                     }
                 }
                 Args::Nil => Ok(nil!().into()),
@@ -125,11 +688,11 @@ impl Default for Env {
                         // (cdr <sexpr>)
                         Atom::Cons(SExpr { cdr, .. }) => Ok(cdr.clone()),
                         // (cdr <symbol>)
-                        Atom::Sym(sym) => match get_val_form_sym(sym, s) {
+                        Atom::Sym(sym) => match get_val_form_sym(sym, s)? {
                             Atom::Cons(SExpr { cdr, .. }) => Ok(cdr),
-                            _ => Err("Unsupported type of symbol"),
+                            other => Err(LispError::type_error("list", &other.into()).into()),
                         },
-                        _ => Err("Unsupported type"),
+                        _ => Err(LispError::type_error("list", &args.car).into()),
                     }
                 }
                 Args::Nil => Ok(nil!().into()),
@@ -137,136 +700,221 @@ impl Default for Env {
         }));
 
         let lambda_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| {
-            fn parse_lambda_params(v: &Atom) -> Result<Vec<String>, &'static str> {
-                match v {
-                    Atom::Cons(param_list) => {
-                        let mut out = Vec::new();
-                        for p in param_list.iter() {
-                            match &*p {
-                                Atom::Sym(sname) => out.push(sname.clone()),
-                                _ => return Err("lambda params must be symbols"),
-                            }
-                        }
-                        Ok(out)
-                    }
-                    Atom::Nil => Ok(vec![]),
-                    _ => Err("lambda expects param list as first arg"),
-                }
-            }
+            // Expect: (lambda (<params>) <body>...)
+            let Args::S(SExpr {
+                car: params_val,
+                cdr: body,
+            }) = args
+            else {
+                return Err(LispError::arity("lambda", "at least 1", 0).into());
+            };
 
-            // Expect exactly: (lambda (<params>) <body>)
-            if get_args_count(args) != 2 {
-                return Err("Expects exactly 2 args: params and body");
-            }
+            let user_fn = make_lambda("lambda", s, &s.val, params_val, progn_form(body))?;
+            Ok(Atom::Fun(user_fn.into()).into())
+        }));
 
-            let args = match args {
-                Args::S(args) => *args,
-                _ => unreachable!(),
-            };
+        // `defun` installs a function, `defmacro` a macro transformer.
+        let defun_ops = |form: &'static str, is_macro: bool| {
+            Fun::Native(Box::new(move |s: &mut Env, args: &Args| -> EvalResult {
+                // Expect: (defun <name> (<params>) <body>...)
+                let (name, params_val, body) = match args {
+                    Args::S(SExpr { car, cdr }) => match &**cdr {
+                        Atom::Cons(SExpr {
+                            car: params_val,
+                            cdr: body,
+                        }) => (car.clone(), params_val.clone(), body.clone()),
+                        _ => return Err(LispError::arity(form, "at least 2", 1).into()),
+                    },
+                    Args::Nil => return Err(LispError::arity(form, "at least 2", 0).into()),
+                };
 
-            let (params_val, body_val): (SAtom, SAtom) = take_args!(args; params_val, body_val)
-                .ok_or_else(|| "Expects exactly 2 args: params and body")?;
+                let fname = match &*name {
+                    Atom::Sym(fname) => fname.clone(),
+                    _ => return Err(LispError::type_error("symbol", &name).into()),
+                };
 
-            let params = parse_lambda_params(&params_val)?;
-            let captured_env: Env = s.clone(); // lexical capture
+                let user_fn =
+                    make_lambda(&fname, s, &s.val, &params_val, function_body(&fname, &body))?;
+                if is_macro {
+                    Arc::make_mut(&mut s.macros).insert(fname, user_fn.into());
+                } else {
+                    s.set_fun(fname, user_fn.into());
+                }
 
-            let user_fn: UserFn = Box::new((
-                body_val.clone(),
-                Box::new(
-                    move |call_state: &mut Env, call_args: &Args| -> EvalResult {
-                        match call_args {
-                            Args::S(args) => {
-                                if get_args_count(call_args) != params.len() {
-                                    return Err("wrong number of arguments");
-                                };
+                Ok(name)
+            }))
+        };
 
-                                // Evaluate arguments in caller environment (call-by-value)
-                                let evaluated_args = args.iter().map(|a| a).collect::<Vec<_>>();
+        // `macroexpand-1` expands a macro form once, `macroexpand` repeatedly.
+        let macroexpand_ops = |form: &'static str, repeat: bool| {
+            Fun::Native(Box::new(move |s: &mut Env, args: &Args| -> EvalResult {
+                let expr = match args {
+                    Args::S(SExpr { car, cdr }) if **cdr == Atom::Nil => car.clone(),
+                    _ => return Err(LispError::arity(form, "1", get_args_count(args)).into()),
+                };
 
-                                // Switch to lambda lexical env + bound params
-                                let saved_env = call_state.val.clone();
-                                call_state.val = captured_env.val.clone();
+                let mut expr = expr;
+                while let Some(expansion) = macroexpand_1(&expr, s)? {
+                    expr = expansion;
+                    if !repeat {
+                        break;
+                    }
+                }
+                Ok(expr)
+            }))
+        };
 
-                                for (name, value) in params.iter().zip(evaluated_args.into_iter()) {
-                                    call_state.val.insert(name.clone(), value);
-                                }
+        // `defvar` only binds unbound globals, `defparameter` always assigns.
+        let defvar_ops = |fname: &'static str, overwrite: bool| {
+            Fun::Native(Box::new(move |s: &mut Env, args: &Args| -> EvalResult {
+                let expected = if overwrite { "2" } else { "1 or 2" };
+                let count = get_args_count(args);
+                let args = match args {
+                    Args::S(args) if count <= 2 => *args,
+                    _ => return Err(LispError::arity(fname, expected, count).into()),
+                };
 
-                                let result = eval(body_val.clone(), call_state);
+                let mut iter = args.iter();
+                let name = iter
+                    .next()
+                    .ok_or_else(|| LispError::arity(fname, expected, count))?;
+                let value = iter.next();
 
-                                // Restore caller env
-                                call_state.val = saved_env;
+                let vname = match &*name {
+                    Atom::Sym(vname) => vname.clone(),
+                    _ => return Err(LispError::type_error("symbol", &name).into()),
+                };
+                s.specials.insert(vname.clone());
+
+                match value {
+                    Some(value) if overwrite || !s.global.contains_key(&vname) => {
+                        let value = eval(value, s)?;
+                        s.global.insert(vname, value);
+                    }
+                    None if overwrite => {
+                        return Err(LispError::arity(fname, expected, count).into())
+                    }
+                    _ => {}
+                }
 
-                                result
-                            }
-                            Args::Nil => {
-                                if 0 != params.len() {
-                                    return Err("wrong number of arguments");
-                                };
+                Ok(name)
+            }))
+        };
 
-                                // Switch to lambda lexical env + bound params
-                                let saved_env = call_state.val.clone();
-                                call_state.val = captured_env.val.clone();
+        let setq_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
+            let args = match args {
+                Args::S(args) => *args,
+                Args::Nil => return Ok(nil!().into()),
+            };
 
-                                let result = eval(body_val.clone(), call_state);
+            // (setq <sym> <val> <sym> <val> ...) returns the last assigned value
+            let mut result: SAtom = nil!().into();
+            let mut iter = args.iter();
+            while let Some(name) = iter.next() {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| LispError::syntax("setq", "expects an even number of args"))?;
+                let vname = match &*name {
+                    Atom::Sym(vname) => vname.clone(),
+                    _ => return Err(LispError::type_error("symbol", &name).into()),
+                };
 
-                                // Restore caller env
-                                call_state.val = saved_env;
+                result = eval(value, s)?;
+                s.set_val(vname, result.clone());
+            }
 
-                                result
-                            }
-                        }
-                    },
-                ),
-            ));
-            Ok(Atom::Fun(Fun::User(user_fn).into()).into())
+            Ok(result)
         }));
 
-        let apply_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
-            match args {
-                Args::S(SExpr { car, cdr }) => {
-                    // car == fun
-                    // cdr == args
-                    let args = match &**cdr {
-                        Atom::Cons(sexpr) => Args::S(sexpr),
-                        Atom::Nil => Args::Nil,
-                        _ => todo!(),
-                    };
-                    match &**car {
-                        Atom::Fun(fun) => fun.call(s, &args),
-                        Atom::Cons(_) => match eval(car.clone(), s)?.as_ref() {
-                            Atom::Fun(fun) => fun.call(s, &args),
-                            _ => todo!(),
-                        },
-                        _ => Err("first element is not callable"),
+        // (apply <fun> <args>...) and (funcall <fun> <args>...)
+        let call_ops = |fname: &'static str| {
+            Fun::Tail(Box::new(move |s: &mut Env, args: &Args| -> TailResult {
+                match args {
+                    Args::S(SExpr { car, cdr }) => {
+                        let args = Args::try_from(&**cdr)?;
+                        designated_fun(s, car)?.call_tail(s, &args)
                     }
+                    Args::Nil => Err(LispError::arity(fname, "at least 1", 0).into()),
+                }
+            }))
+        };
+
+        // (function <name>) or (function (lambda ...))
+        let function_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
+            let name = match args {
+                Args::S(SExpr { car, cdr }) if **cdr == Atom::Nil => car,
+                _ => return Err(LispError::arity("function", "1", get_args_count(args)).into()),
+            };
+            match &**name {
+                Atom::Sym(name) => fun_value(s, name),
+                Atom::Cons(SExpr { car, .. }) if matches!(&**car, Atom::Sym(l) if l == "lambda") => {
+                    eval(name.clone(), s)
                 }
-                Args::Nil => Err("calling apply with no args"),
+                _ => Err(LispError::type_error("function name", name).into()),
             }
         }));
 
-        let funcall_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
+        // (symbol-function <symbol>)
+        let symbol_function_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
             match args {
-                Args::S(SExpr { car, cdr }) => {
-                    // car == fun
-                    // cdr == args
-                    let args = match &**cdr {
-                        Atom::Cons(sexpr) => Args::S(sexpr),
-                        Atom::Nil => Args::Nil,
-                        _ => todo!(),
-                    };
-                    match &**car {
-                        Atom::Fun(fun) => fun.call(s, &args),
-                        Atom::Cons(_) => match eval(car.clone(), s)?.as_ref() {
-                            Atom::Fun(fun) => fun.call(s, &args),
-                            _ => todo!(),
-                        },
-                        _ => Err("first element is not callable"),
-                    }
-                }
-                Args::Nil => Err("calling apply with no args"),
+                Args::S(SExpr { car, cdr }) if **cdr == Atom::Nil => match &**car {
+                    Atom::Sym(name) => Ok(Atom::Fun(global_fun(s, name)?).into()),
+                    _ => Err(LispError::type_error("symbol", car).into()),
+                },
+                _ => Err(LispError::arity("symbol-function", "1", get_args_count(args)).into()),
+            }
+        }));
+
+        // (fset <symbol> <function>) installs the function under the name
+        let fset_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
+            if get_args_count(args) != 2 {
+                return Err(LispError::arity("fset", "2", get_args_count(args)).into());
             }
+            let Args::S(args) = args else { unreachable!() };
+            let (name, fun) =
+                take_args!(args; name, fun).ok_or_else(|| LispError::arity("fset", "2", 0))?;
+
+            match (&*name, &*fun) {
+                (Atom::Sym(fname), Atom::Fun(f)) => s.set_fun(fname.clone(), f.clone()),
+                (Atom::Sym(_), _) => return Err(LispError::type_error("function", &fun).into()),
+                _ => return Err(LispError::type_error("symbol", &name).into()),
+            }
+            Ok(fun)
         }));
 
+        // (fmakunbound <symbol>) removes the function or macro of that name
+        let fmakunbound_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
+            let name = match args {
+                Args::S(SExpr { car, cdr }) if **cdr == Atom::Nil => car,
+                _ => return Err(LispError::arity("fmakunbound", "1", get_args_count(args)).into()),
+            };
+            let Atom::Sym(fname) = &**name else {
+                return Err(LispError::type_error("symbol", name).into());
+            };
+            if s.macros.contains_key(fname) {
+                Arc::make_mut(&mut s.macros).remove(fname);
+            }
+            if s.fun.contains_key(fname) {
+                Arc::make_mut(&mut s.fun).remove(fname);
+            }
+            Ok(name.clone())
+        }));
+
+        // (fboundp <symbol>) and (boundp <symbol>)
+        let symbol_ops = |fname: &'static str, op: fn(&Env, &str) -> bool| {
+            Fun::Native(Box::new(move |s: &mut Env, args: &Args| -> EvalResult {
+                let name = match args {
+                    Args::S(SExpr { car, cdr }) if **cdr == Atom::Nil => car,
+                    _ => return Err(LispError::arity(fname, "1", get_args_count(args)).into()),
+                };
+                match &**name {
+                    Atom::Sym(sym) if op(s, sym) => Ok(t!().into()),
+                    Atom::Sym(_) => Ok(nil!().into()),
+                    _ => Err(LispError::type_error("symbol", name).into()),
+                }
+            }))
+        };
+
         let list_op = Fun::Native(Box::new(|_: &mut Env, args: &Args| -> EvalResult {
             match args {
                 Args::S(sexpr) => Ok(SAtom::new((*sexpr).clone().into())),
@@ -276,93 +924,443 @@ impl Default for Env {
 
         let quote_op = Fun::Native(Box::new(|_: &mut Env, args: &Args| -> EvalResult {
             if get_args_count(args) != 1 {
-                return Err("Expects exactly 1 arg");
+                return Err(LispError::arity("quote", "1", get_args_count(args)).into());
             }
             match args {
                 Args::S(SExpr { car, cdr }) => {
                     if **cdr == Atom::Nil {
                         Ok(car.clone())
                     } else {
-                        Err("Expected only 1 arg")
+                        Err(LispError::arity("quote", "1", get_args_count(args)).into())
                     }
                 }
-                Args::Nil => Err("Expected 1 arg"),
+                Args::Nil => Err(LispError::arity("quote", "1", 0).into()),
             }
         }));
 
         let cons_op = Fun::Native(Box::new(|_: &mut Env, args: &Args| -> EvalResult {
             if get_args_count(args) != 2 {
-                return Err("Expected 2 arg");
+                return Err(LispError::arity("cons", "2", get_args_count(args)).into());
             }
             match args {
                 Args::S(args) => {
-                    let (car, cdr) = take_args!(args; car, cdr).ok_or_else(|| "Expected 2 arg")?;
+                    let (car, cdr) = take_args!(args; car, cdr)
+                        .ok_or_else(|| LispError::arity("cons", "2", 0))?;
+
+                    Ok(Atom::Cons(SExpr { car, cdr }).into())
+                }
+                Args::Nil => Err(LispError::arity("cons", "2", 0).into()),
+            }
+        }));
+
+        let reverse_op = Fun::Native(Box::new(|_: &mut Env, args: &Args| -> EvalResult {
+            let args = args.to_vec();
+            let [list] = &args[..] else {
+                return Err(LispError::arity("reverse", "1", args.len()).into());
+            };
+            let reversed = list_items(list)?
+                .into_iter()
+                .fold(SAtom::new(nil!()), |cdr, car| {
+                    Atom::Cons(SExpr { car, cdr }).into()
+                });
+            Ok(reversed)
+        }));
 
-                    Ok(Atom::Cons(SExpr { car: car, cdr: cdr }).into())
+        // (append <list>... <last>) copies the lists, sharing <last> as the tail
+        let append_op = Fun::Native(Box::new(|_: &mut Env, args: &Args| -> EvalResult {
+            let mut args = args.to_vec();
+            let Some(mut tail) = args.pop() else {
+                return Ok(nil!().into());
+            };
+            for list in args.iter().rev() {
+                for car in list_items(list)?.into_iter().rev() {
+                    tail = Atom::Cons(SExpr { car, cdr: tail }).into();
                 }
-                Args::Nil => Err("Expected 2 arg"),
             }
+            Ok(tail)
         }));
 
-        let if_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
-            if get_args_count(args) != 3 {
-                return Err("Expected 3 arg");
+        // (if <test> <then> [<else>]), the else branch defaults to nil
+        let if_op = Fun::Tail(Box::new(|s: &mut Env, args: &Args| -> TailResult {
+            let args = args.to_vec();
+            let (test, then, otherwise) = match &args[..] {
+                [test, then] => (test, then, None),
+                [test, then, otherwise] => (test, then, Some(otherwise)),
+                _ => return Err(LispError::arity("if", "2 or 3", args.len()).into()),
+            };
+
+            if *eval(test.clone(), s)? != Atom::Nil {
+                Ok(Tail::Eval(then.clone()))
+            } else {
+                match otherwise {
+                    Some(otherwise) => Ok(Tail::Eval(otherwise.clone())),
+                    None => Ok(Tail::Done(nil!().into())),
+                }
             }
-            match args {
-                Args::S(sexpr) => {
-                    let (test, t_body, f_body) =
-                        take_args!(sexpr; test, t_body, f_body).ok_or_else(|| "Expected 3 arg")?;
+        }));
 
-                    if (*eval(test.clone(), s)?) != Atom::Nil {
-                        eval(t_body, s)
-                    } else {
-                        eval(f_body, s)
-                    }
+        // `when` runs its body if the test is true, `unless` if it is nil.
+        let when_ops = |form: &'static str, run_if: bool| {
+            Fun::Tail(Box::new(move |s: &mut Env, args: &Args| -> TailResult {
+                let Args::S(SExpr {
+                    car: test,
+                    cdr: body,
+                }) = args
+                else {
+                    return Err(LispError::arity(form, "at least 1", 0).into());
+                };
+                if (*eval(test.clone(), s)? != Atom::Nil) == run_if {
+                    Ok(Tail::Eval(progn_form(body)))
+                } else {
+                    Ok(Tail::Done(nil!().into()))
                 }
-                Args::Nil => Err("Expected 3 arg"),
+            }))
+        };
+
+        // (cond (<test> <body>...)...) runs the body of the first true test,
+        // or returns the test value when the body is empty
+        let cond_op = Fun::Tail(Box::new(|s: &mut Env, args: &Args| -> TailResult {
+            for clause in args.to_vec() {
+                let Atom::Cons(SExpr {
+                    car: test,
+                    cdr: body,
+                }) = &*clause
+                else {
+                    return Err(LispError::syntax("cond", "clause must be (test body...)").into());
+                };
+                let value = eval(test.clone(), s)?;
+                if *value == Atom::Nil {
+                    continue;
+                }
+                return match **body {
+                    Atom::Nil => Ok(Tail::Done(value)),
+                    _ => Ok(Tail::Eval(progn_form(body))),
+                };
             }
+            Ok(Tail::Done(nil!().into()))
         }));
 
+        // `and` stops at the first nil, `or` at the first true value, and
+        // both return the value they stopped at, or the value of the last form.
+        let and_or_ops = |stop_if_nil: bool| {
+            Fun::Tail(Box::new(move |s: &mut Env, args: &Args| -> TailResult {
+                let mut forms = args.to_vec();
+                let Some(last) = forms.pop() else {
+                    let empty = if stop_if_nil { t!() } else { nil!() };
+                    return Ok(Tail::Done(empty.into()));
+                };
+                for form in forms {
+                    let value = eval(form, s)?;
+                    if (*value == Atom::Nil) == stop_if_nil {
+                        return Ok(Tail::Done(value));
+                    }
+                }
+                Ok(Tail::Eval(last))
+            }))
+        };
+
+        // (case <key> (<keys> <body>...)...) runs the first clause listing
+        // the value of <key>; `t`/`otherwise` match anything. `ecase` has no
+        // catch-all and fails when nothing matches.
+        let case_ops = |form: &'static str, exhaustive: bool| {
+            Fun::Tail(Box::new(move |s: &mut Env, args: &Args| -> TailResult {
+                let Args::S(SExpr {
+                    car: key,
+                    cdr: clauses,
+                }) = args
+                else {
+                    return Err(LispError::arity(form, "at least 1", 0).into());
+                };
+                let key = eval(key.clone(), s)?;
+
+                let clauses = match &**clauses {
+                    Atom::Cons(clauses) => clauses.iter().collect(),
+                    _ => Vec::new(),
+                };
+                for clause in clauses {
+                    let Atom::Cons(SExpr {
+                        car: keys,
+                        cdr: body,
+                    }) = &*clause
+                    else {
+                        return Err(LispError::syntax(form, "clause must be (keys body...)").into());
+                    };
+                    if case_matches(keys, &key, exhaustive) {
+                        return Ok(Tail::Eval(progn_form(body)));
+                    }
+                }
+
+                match exhaustive {
+                    true => Err(LispError::type_error("a key of an ecase clause", &key).into()),
+                    false => Ok(Tail::Done(nil!().into())),
+                }
+            }))
+        };
+
         let eq_op = Fun::Native(Box::new(|_: &mut Env, args: &Args| -> EvalResult {
-            if get_args_count(&args) != 2 {
-                return Err("Expected 2 arg");
+            if get_args_count(args) != 2 {
+                return Err(LispError::arity("eq", "2", get_args_count(args)).into());
             }
             match args {
                 Args::S(sexpr) => {
                     let (x, y): (SAtom, SAtom) =
-                        take_args!(sexpr; x, y).ok_or_else(|| "Expected 2 arg")?;
-                    if &*x == &*y {
+                        take_args!(sexpr; x, y).ok_or_else(|| LispError::arity("eq", "2", 0))?;
+                    if *x == *y {
                         Ok(t!().into())
                     } else {
                         Ok(nil!().into())
                     }
                 }
-                Args::Nil => Err("Expected 3 arg"),
+                Args::Nil => Err(LispError::arity("eq", "2", 0).into()),
             }
         }));
 
-        fun_map.insert("add".into(), binary_ops(|a, b| a + b));
-        fun_map.insert("mul".into(), binary_ops(|a, b| a * b));
-        fun_map.insert("sub".into(), binary_ops(|a, b| a - b));
-        fun_map.insert("div".into(), binary_ops(|a, b| a / b));
-        fun_map.insert("car".into(), car_op);
-        fun_map.insert("cdr".into(), cdr_op);
-        fun_map.insert("list".into(), list_op);
-        fun_map.insert("quote".into(), quote_op);
-        fun_map.insert("lambda".into(), lambda_op);
-        fun_map.insert("apply".into(), apply_op);
-        fun_map.insert("funcall".into(), funcall_op);
-        fun_map.insert("cons".into(), cons_op);
-        fun_map.insert("if".into(), if_op);
-        fun_map.insert("eq".into(), eq_op);
-
-        let mut val_map = HashMap::new();
-        val_map.insert("nil".into(), nil!().into());
-        val_map.insert("t".into(), t!().into());
+        let progn_op = Fun::Tail(Box::new(|s: &mut Env, args: &Args| -> TailResult {
+            let Args::S(body) = args else {
+                return Ok(Tail::Done(nil!().into()));
+            };
+
+            // (progn <form>... <last>) returns the value of <last>
+            let mut iter = body.iter().peekable();
+            while let Some(form) = iter.next() {
+                if iter.peek().is_none() {
+                    return Ok(Tail::Eval(form));
+                }
+                eval(form, s)?;
+            }
+            Ok(Tail::Done(nil!().into()))
+        }));
+
+        // `let` evaluates every init first, `let*` sees the earlier bindings.
+        // Special variables are rebound dynamically and restored on exit,
+        // so their body isn't a tail call.
+        let let_ops = |form: &'static str, sequential: bool| {
+            Fun::Tail(Box::new(move |s: &mut Env, args: &Args| -> TailResult {
+                let Args::S(SExpr { car, cdr: body }) = args else {
+                    return Err(LispError::arity(form, "at least 1", 0).into());
+                };
+                let bindings = parse_bindings(form, car)?;
+
+                let mut scope = s.val.extend([]);
+                let mut saved = Vec::new();
+                let res = bind_let(bindings, sequential, &mut scope, &mut saved, s);
+                if saved.is_empty() {
+                    res?;
+                    return Ok(Tail::Scope(progn_form(body), scope));
+                }
+
+                let res = res.and_then(|()| run(Tail::Scope(progn_form(body), scope), s));
+                s.restore_specials(saved);
+                res.map(Tail::Done)
+            }))
+        };
+
+        // (letrec ((<name> <init>)...) <body>...): every name is in scope for
+        // the inits, so the lambdas among them can call each other.
+        let letrec_op = Fun::Tail(Box::new(|s: &mut Env, args: &Args| -> TailResult {
+            let Args::S(SExpr { car, cdr: body }) = args else {
+                return Err(LispError::arity("letrec", "at least 1", 0).into());
+            };
+            let bindings = parse_bindings("letrec", car)?;
+
+            let mut scope = s.val.extend(
+                bindings
+                    .iter()
+                    .map(|(name, _)| (name.as_str().into(), Binding::default())),
+            );
+            for (name, init) in bindings {
+                if let Some(init) = init {
+                    let value = eval_in(init, &mut scope, s)?;
+                    scope.get(&name).unwrap().set(value);
+                }
+            }
+
+            Ok(Tail::Scope(progn_form(body), scope))
+        }));
+
+        // `flet` functions see the enclosing scope, `labels` functions also
+        // see each other, so they can be recursive.
+        let flet_ops = |form: &'static str, recursive: bool| {
+            Fun::Tail(Box::new(move |s: &mut Env, args: &Args| -> TailResult {
+                let Args::S(SExpr { car, cdr: body }) = args else {
+                    return Err(LispError::arity(form, "at least 1", 0).into());
+                };
+                let defs = parse_fun_bindings(form, car)?;
+
+                let bindings: Vec<Binding> = defs.iter().map(|_| Binding::default()).collect();
+                let scope = s.val.extend_funs(
+                    defs.iter()
+                        .zip(&bindings)
+                        .map(|((name, ..), binding)| (name.as_str().into(), binding.clone())),
+                );
+                let captured = if recursive { &scope } else { &s.val };
+                for ((name, params, fbody), binding) in defs.iter().zip(&bindings) {
+                    let fun = make_lambda(name, s, captured, params, function_body(name, fbody))?;
+                    binding.set(Atom::Fun(fun.into()).into());
+                }
+
+                Ok(Tail::Scope(progn_form(body), scope))
+            }))
+        };
+
+        for name in ["add", "+"] {
+            fun_map.insert(
+                name.into(),
+                arith_ops(name, |a, b| Ok(a.add(b)), 0, 0).into(),
+            );
+        }
+        for name in ["mul", "*"] {
+            fun_map.insert(
+                name.into(),
+                arith_ops(name, |a, b| Ok(a.mul(b)), 1, 0).into(),
+            );
+        }
+        for name in ["sub", "-"] {
+            fun_map.insert(
+                name.into(),
+                arith_ops(name, |a, b| Ok(a.sub(b)), 0, 1).into(),
+            );
+        }
+        for name in ["div", "/"] {
+            fun_map.insert(name.into(), arith_ops(name, Number::div, 1, 1).into());
+        }
+        fun_map.insert(
+            "=".into(),
+            compare_ops("=", false, |o| o == Ordering::Equal).into(),
+        );
+        fun_map.insert(
+            "/=".into(),
+            compare_ops("/=", true, |o| o != Ordering::Equal).into(),
+        );
+        fun_map.insert(
+            "<".into(),
+            compare_ops("<", false, |o| o == Ordering::Less).into(),
+        );
+        fun_map.insert(
+            ">".into(),
+            compare_ops(">", false, |o| o == Ordering::Greater).into(),
+        );
+        fun_map.insert(
+            "<=".into(),
+            compare_ops("<=", false, |o| o != Ordering::Greater).into(),
+        );
+        fun_map.insert(
+            ">=".into(),
+            compare_ops(">=", false, |o| o != Ordering::Less).into(),
+        );
+        fun_map.insert(
+            "zerop".into(),
+            number_predicates("zerop", |n| Some(n.is_zero())).into(),
+        );
+        fun_map.insert(
+            "plusp".into(),
+            number_predicates("plusp", |n| {
+                Some(n.compare(&Number::Int(0)) == Some(Ordering::Greater))
+            })
+            .into(),
+        );
+        fun_map.insert(
+            "minusp".into(),
+            number_predicates("minusp", |n| {
+                Some(n.compare(&Number::Int(0)) == Some(Ordering::Less))
+            })
+            .into(),
+        );
+        fun_map.insert(
+            "evenp".into(),
+            number_predicates("evenp", Number::is_even).into(),
+        );
+        fun_map.insert(
+            "oddp".into(),
+            number_predicates("oddp", |n| n.is_even().map(|even| !even)).into(),
+        );
+        fun_map.insert("min".into(), extreme_ops("min", Ordering::Less).into());
+        fun_map.insert("max".into(), extreme_ops("max", Ordering::Greater).into());
+        fun_map.insert("car".into(), car_op.into());
+        fun_map.insert("cdr".into(), cdr_op.into());
+        fun_map.insert("list".into(), list_op.into());
+        fun_map.insert("quote".into(), quote_op.into());
+        fun_map.insert("lambda".into(), lambda_op.into());
+        fun_map.insert("apply".into(), call_ops("apply").into());
+        fun_map.insert("funcall".into(), call_ops("funcall").into());
+        fun_map.insert("cons".into(), cons_op.into());
+        fun_map.insert("reverse".into(), reverse_op.into());
+        fun_map.insert("append".into(), append_op.into());
+        fun_map.insert("if".into(), if_op.into());
+        fun_map.insert("when".into(), when_ops("when", true).into());
+        fun_map.insert("unless".into(), when_ops("unless", false).into());
+        fun_map.insert("cond".into(), cond_op.into());
+        fun_map.insert("and".into(), and_or_ops(true).into());
+        fun_map.insert("or".into(), and_or_ops(false).into());
+        fun_map.insert("case".into(), case_ops("case", false).into());
+        fun_map.insert("ecase".into(), case_ops("ecase", true).into());
+        fun_map.insert("progn".into(), progn_op.into());
+        fun_map.insert("let".into(), let_ops("let", false).into());
+        fun_map.insert("let*".into(), let_ops("let*", true).into());
+        fun_map.insert("letrec".into(), letrec_op.into());
+        fun_map.insert("flet".into(), flet_ops("flet", false).into());
+        fun_map.insert("labels".into(), flet_ops("labels", true).into());
+        fun_map.insert("defun".into(), defun_ops("defun", false).into());
+        fun_map.insert("defmacro".into(), defun_ops("defmacro", true).into());
+        fun_map.insert(
+            "macroexpand-1".into(),
+            macroexpand_ops("macroexpand-1", false).into(),
+        );
+        fun_map.insert(
+            "macroexpand".into(),
+            macroexpand_ops("macroexpand", true).into(),
+        );
+        fun_map.insert("defvar".into(), defvar_ops("defvar", false).into());
+        fun_map.insert(
+            "defparameter".into(),
+            defvar_ops("defparameter", true).into(),
+        );
+        fun_map.insert("setq".into(), setq_op.into());
+        fun_map.insert("eq".into(), eq_op.into());
+        fun_map.insert("function".into(), function_op.into());
+        fun_map.insert("symbol-function".into(), symbol_function_op.into());
+        fun_map.insert("fset".into(), fset_op.into());
+        fun_map.insert(
+            "fboundp".into(),
+            symbol_ops("fboundp", |s, name| {
+                s.fun.contains_key(name) || s.macros.contains_key(name)
+            })
+            .into(),
+        );
+        fun_map.insert(
+            "boundp".into(),
+            // only global and special variables: a lexical variable can't be
+            // named by a symbol value
+            symbol_ops("boundp", |s, name| {
+                s.global.contains_key(name) || name.starts_with(':') || name == "nil" || name == "t"
+            })
+            .into(),
+        );
+        fun_map.insert("fmakunbound".into(), fmakunbound_op.into());
+        conditions::install(&mut fun_map);
+        control::install(&mut fun_map);
+        iteration::install(&mut fun_map);
+        math::install(&mut fun_map);
+        random::install(&mut fun_map);
+        strings::install(&mut fun_map);
+        quasiquote::install(&mut fun_map);
+        let mut macros = HashMap::new();
+        loop_macro::install(&mut fun_map, &mut macros);
+
+        let val =
+            Scope::default().extend([("nil".into(), nil!().into()), ("t".into(), t!().into())]);
 
         Self {
             fun: fun_map.into(),
-            val: val_map,
+            val,
+            global: HashMap::from([(random::RANDOM_STATE.into(), random::default_state())]),
+            specials: HashSet::from([random::RANDOM_STATE.into()]),
+            macros: macros.into(),
+            handlers: Vec::new(),
+            catches: Vec::new(),
+            backend: Backend::default(),
+            compiled: ProtoCache::default(),
+            trace: false,
         }
     }
 }
@@ -0,0 +1,319 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    atom::{Atom, Fun, SAtom},
+    control::with_block,
+    env::{eval_in, Binding, Env, Scope},
+    lisp_error::LispError,
+    lisp_eval::{eval, Args, EvalResult},
+    nil, num,
+    sexpr::SExpr,
+};
+
+/// Evaluate the forms of the list `body` in `scope`, returning the last value.
+fn eval_body_in(body: &Atom, scope: &mut Scope, s: &mut Env) -> EvalResult {
+    let mut result: SAtom = nil!().into();
+    if let Atom::Cons(body) = body {
+        for form in body.iter() {
+            result = eval_in(form, scope, s)?;
+        }
+    }
+    Ok(result)
+}
+
+/// The `(<var> <form> [<result>])` spec of `dotimes`/`dolist`.
+pub type LoopSpec = (Arc<str>, SAtom, Option<SAtom>);
+
+/// Parse the spec of `dotimes`/`dolist`.
+pub fn loop_spec(form: &'static str, v: &Atom) -> Result<LoopSpec, LispError> {
+    let Atom::Cons(spec) = v else {
+        return Err(LispError::syntax(
+            form,
+            "expects (var form [result]) as first arg",
+        ));
+    };
+    let mut iter = spec.iter();
+    let (Some(var), Some(init), result, None) =
+        (iter.next(), iter.next(), iter.next(), iter.next())
+    else {
+        return Err(LispError::syntax(
+            form,
+            "expects (var form [result]) as first arg",
+        ));
+    };
+    match &*var {
+        Atom::Sym(var) => Ok((var.as_str().into(), init, result)),
+        _ => Err(LispError::syntax(form, "loop variable must be a symbol")),
+    }
+}
+
+/// A `do` variable with its init and step forms.
+pub type DoBinding = (Arc<str>, Option<SAtom>, Option<SAtom>);
+
+/// Parse the `((<var> [<init> [<step>]])...)` bindings of `do`.
+pub fn do_bindings(v: &Atom) -> Result<Vec<DoBinding>, LispError> {
+    let bindings = match v {
+        Atom::Nil => return Ok(vec![]),
+        Atom::Cons(bindings) => bindings,
+        _ => {
+            return Err(LispError::syntax(
+                "do",
+                "expects a binding list as first arg",
+            ))
+        }
+    };
+
+    let mut out = Vec::new();
+    for binding in bindings.iter() {
+        let (var, init, step) = match &*binding {
+            Atom::Sym(var) => (var.clone(), None, None),
+            Atom::Cons(binding) => {
+                let mut iter = binding.iter();
+                match (iter.next(), iter.next(), iter.next(), iter.next()) {
+                    (Some(var), init, step, None) => match &*var {
+                        Atom::Sym(var) => (var.clone(), init, step),
+                        _ => return Err(LispError::syntax("do", "variable must be a symbol")),
+                    },
+                    _ => return Err(LispError::syntax("do", "binding must be (var init step)")),
+                }
+            }
+            _ => {
+                return Err(LispError::syntax(
+                    "do",
+                    "binding must be a symbol or a list",
+                ))
+            }
+        };
+        out.push((var.as_str().into(), init, step));
+    }
+    Ok(out)
+}
+
+pub fn install(fun_map: &mut HashMap<String, Arc<Fun>>) {
+    // (while <test> <body>...) repeats the body while the test is true
+    let while_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
+        let Args::S(SExpr {
+            car: test,
+            cdr: body,
+        }) = args
+        else {
+            return Err(LispError::arity("while", "at least 1", 0).into());
+        };
+        let scope = s.val.clone();
+        with_block(s, "nil", &scope, |s, mut scope| {
+            while *eval_in(test.clone(), &mut scope, s)? != Atom::Nil {
+                eval_body_in(body, &mut scope, s)?;
+            }
+            Ok(nil!().into())
+        })
+    }));
+
+    // (dotimes (<var> <count> [<result>]) <body>...) runs the body with
+    // <var> bound to 0, 1, ... <count> - 1
+    let dotimes_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
+        let Args::S(SExpr {
+            car: spec,
+            cdr: body,
+        }) = args
+        else {
+            return Err(LispError::arity("dotimes", "at least 1", 0).into());
+        };
+        let (var, count, result) = loop_spec("dotimes", spec)?;
+        let count = eval(count, s)?;
+        let count = match &*count {
+            Atom::Int(n) => *n,
+            _ => return Err(LispError::type_error("integer", &count).into()),
+        };
+
+        let outer = s.val.clone();
+        with_block(s, "nil", &outer, |s, outer| {
+            // Every iteration gets a fresh binding, so closures keep theirs.
+            for i in 0..count {
+                let mut scope = outer.extend([(var.clone(), Binding::new(num!(i).into()))]);
+                eval_body_in(body, &mut scope, s)?;
+            }
+            match result {
+                Some(result) => {
+                    let mut scope =
+                        outer.extend([(var.clone(), Binding::new(num!(count.max(0)).into()))]);
+                    eval_in(result, &mut scope, s)
+                }
+                None => Ok(nil!().into()),
+            }
+        })
+    }));
+
+    // (dolist (<var> <list> [<result>]) <body>...) runs the body with <var>
+    // bound to each element of <list>
+    let dolist_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
+        let Args::S(SExpr {
+            car: spec,
+            cdr: body,
+        }) = args
+        else {
+            return Err(LispError::arity("dolist", "at least 1", 0).into());
+        };
+        let (var, list, result) = loop_spec("dolist", spec)?;
+        let list = eval(list, s)?;
+        let items: Vec<SAtom> = match &*list {
+            Atom::Cons(list) => list.iter().collect(),
+            Atom::Nil => Vec::new(),
+            _ => return Err(LispError::type_error("list", &list).into()),
+        };
+
+        let outer = s.val.clone();
+        with_block(s, "nil", &outer, |s, outer| {
+            for item in items {
+                let mut scope = outer.extend([(var.clone(), Binding::new(item))]);
+                eval_body_in(body, &mut scope, s)?;
+            }
+            match result {
+                Some(result) => {
+                    let mut scope = outer.extend([(var.clone(), Binding::new(nil!().into()))]);
+                    eval_in(result, &mut scope, s)
+                }
+                None => Ok(nil!().into()),
+            }
+        })
+    }));
+
+    // (do ((<var> <init> <step>)...) (<end-test> <result>...) <body>...)
+    // binds the vars to their inits, then until <end-test> is true runs the
+    // body and rebinds the vars to their steps, all evaluated in parallel
+    let do_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
+        let args = args.to_vec();
+        let [bindings, end, body @ ..] = &args[..] else {
+            return Err(LispError::arity("do", "at least 2", args.len()).into());
+        };
+        let bindings = do_bindings(bindings)?;
+        let Atom::Cons(SExpr {
+            car: end_test,
+            cdr: results,
+        }) = &**end
+        else {
+            return Err(
+                LispError::syntax("do", "expects (end-test result...) as second arg").into(),
+            );
+        };
+        let body: Atom = match body.is_empty() {
+            true => Atom::Nil,
+            false => Atom::Cons(body.iter().cloned().collect()),
+        };
+
+        let outer = s.val.clone();
+        with_block(s, "nil", &outer, |s, outer| {
+            let mut values = Vec::new();
+            for (_, init, _) in &bindings {
+                values.push(match init {
+                    Some(init) => eval(init.clone(), s)?,
+                    None => nil!().into(),
+                });
+            }
+
+            loop {
+                let mut scope = outer.extend(
+                    bindings
+                        .iter()
+                        .zip(values)
+                        .map(|((var, ..), value)| (var.clone(), Binding::new(value))),
+                );
+                if *eval_in(end_test.clone(), &mut scope, s)? != Atom::Nil {
+                    return eval_body_in(results, &mut scope, s);
+                }
+                eval_body_in(&body, &mut scope, s)?;
+
+                // Vars without a step keep their current value.
+                values = Vec::new();
+                for (var, _, step) in &bindings {
+                    values.push(match step {
+                        Some(step) => eval_in(step.clone(), &mut scope, s)?,
+                        None => scope.get(var).unwrap().get(),
+                    });
+                }
+            }
+        })
+    }));
+
+    fun_map.insert("while".into(), while_op.into());
+    fun_map.insert("dotimes".into(), dotimes_op.into());
+    fun_map.insert("dolist".into(), dolist_op.into());
+    fun_map.insert("do".into(), do_op.into());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{sexpr, sym, t, test_utils::run};
+
+    use super::*;
+
+    #[test]
+    fn test_while_dotimes_dolist() {
+        let env = &mut Env::default();
+        run("(defvar n 0)", env).unwrap();
+        assert_eq!(
+            *run("(while (eq (eq n 5) nil) (setq n (add n 1)))", env).unwrap(),
+            nil!()
+        );
+        assert_eq!(*run("n", env).unwrap(), num!(5));
+
+        let res = run(
+            "(let ((acc nil)) (dotimes (i 4 (cons i acc)) (setq acc (cons i acc))))",
+            env,
+        );
+        assert_eq!(
+            *res.unwrap(),
+            sexpr!(num!(4), num!(3), num!(2), num!(1), num!(0))
+        );
+
+        let res = run(
+            "(let ((sum 0)) (dolist (x (list 1 2 3) sum) (setq sum (add sum x))))",
+            env,
+        );
+        assert_eq!(*res.unwrap(), num!(6));
+        assert_eq!(
+            *run("(dolist (x nil) (error \"never\"))", env).unwrap(),
+            nil!()
+        );
+
+        // the loops are in a block named nil
+        let res = run(
+            "(dolist (x (list 1 2 3 4)) (when (eq x 3) (return (mul x 10))))",
+            env,
+        );
+        assert_eq!(*res.unwrap(), num!(30));
+        assert_eq!(*run("(while t (return 'out))", env).unwrap(), sym!("out"));
+
+        // each iteration has its own binding
+        let res = run(
+            "(let ((fs nil)) (dotimes (i 3) (setq fs (cons (lambda () i) fs))) (list (funcall (car fs)) (funcall (car (cdr (cdr fs))))))",
+            env,
+        );
+        assert_eq!(*res.unwrap(), sexpr!(num!(2), num!(0)));
+
+        let err = run("(dotimes (i 'x) i)", env).unwrap_err();
+        assert_eq!(err.error.kind_name(), "type-error");
+    }
+
+    #[test]
+    fn test_do() {
+        let env = &mut Env::default();
+        // steps are evaluated in parallel
+        let res = run(
+            "(do ((i 0 (add i 1)) (a 0 b) (b 1 (add a b))) ((eq i 10) a))",
+            env,
+        );
+        assert_eq!(*res.unwrap(), num!(55));
+
+        let res = run(
+            "(do ((i 0 (add i 1)) (acc nil)) ((eq i 3) acc) (setq acc (cons i acc)))",
+            env,
+        );
+        assert_eq!(*res.unwrap(), sexpr!(num!(2), num!(1), num!(0)));
+        assert_eq!(
+            *run("(do ((i 0 (add i 1))) ((eq i 2)))", env).unwrap(),
+            nil!()
+        );
+        assert_eq!(*run("(do () (t))", env).unwrap(), nil!());
+        assert_eq!(*run("(do () (nil) (return t))", env).unwrap(), t!());
+    }
+}
@@ -0,0 +1,390 @@
+use std::sync::Arc;
+
+use crate::{
+    atom::{Atom, SAtom},
+    env::{eval_in, Binding, Env, Scope},
+    lisp_error::{EvalError, LispError},
+    nil,
+    sexpr::SExpr,
+    t,
+};
+
+/// An `&optional` or `&key` parameter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptParam {
+    pub name: Arc<str>,
+    /// Form evaluated when the argument is missing, `nil` if absent.
+    pub default: Option<SAtom>,
+    /// Variable bound to whether the argument was supplied.
+    pub supplied: Option<Arc<str>>,
+    /// Keyword naming the argument, for `&key` parameters.
+    pub keyword: String,
+}
+
+/// A parsed lambda list:
+/// `(req... &optional opt... &rest rest &key key...)`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LambdaList {
+    pub required: Vec<Arc<str>>,
+    pub optional: Vec<OptParam>,
+    pub rest: Option<Arc<str>>,
+    /// Whether `&key` was given, even without keys.
+    pub has_keys: bool,
+    pub keys: Vec<OptParam>,
+    /// Params that were special variables when the function was made, which
+    /// calls bind dynamically like `let` does.
+    pub specials: Vec<Arc<str>>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Section {
+    Required,
+    Optional,
+    Rest,
+    Key,
+}
+
+fn param_name(v: &SAtom) -> Result<Arc<str>, LispError> {
+    match &**v {
+        Atom::Sym(name) if !name.starts_with('&') && !name.starts_with(':') => {
+            Ok(name.as_str().into())
+        }
+        _ => Err(LispError::syntax("lambda", "params must be symbols")),
+    }
+}
+
+/// Parse `name`, `(name default)` or `(name default supplied-p)`. For keys,
+/// the name can also be `(:keyword name)`.
+fn opt_param(v: &SAtom, is_key: bool) -> Result<OptParam, LispError> {
+    let (spec, default, supplied) = match &**v {
+        Atom::Cons(list) => {
+            let mut iter = list.iter();
+            let spec = iter.next().unwrap_or_default();
+            let default = iter.next();
+            let supplied = iter.next().map(|p| param_name(&p)).transpose()?;
+            if iter.next().is_some() {
+                return Err(LispError::syntax(
+                    "lambda",
+                    "optional param must be (name default supplied-p)",
+                ));
+            }
+            (spec, default, supplied)
+        }
+        _ => (v.clone(), None, None),
+    };
+
+    let (name, keyword) = match &*spec {
+        Atom::Cons(SExpr { car, cdr }) if is_key => match (&**car, &**cdr) {
+            (Atom::Sym(keyword), Atom::Cons(name)) if keyword.starts_with(':') => {
+                (param_name(&name.car)?, keyword.clone())
+            }
+            _ => return Err(LispError::syntax("lambda", "key must be (:keyword name)")),
+        },
+        _ => {
+            let name = param_name(&spec)?;
+            let keyword = format!(":{name}");
+            (name, keyword)
+        }
+    };
+
+    Ok(OptParam {
+        name,
+        default,
+        supplied,
+        keyword,
+    })
+}
+
+impl LambdaList {
+    pub fn parse(v: &Atom) -> Result<Self, LispError> {
+        let params = match v {
+            Atom::Nil => return Ok(LambdaList::default()),
+            Atom::Cons(params) => params,
+            _ => {
+                return Err(LispError::syntax(
+                    "lambda",
+                    "expects param list as first arg",
+                ))
+            }
+        };
+
+        let mut list = LambdaList::default();
+        let mut section = Section::Required;
+        for p in params.iter() {
+            let next = match &*p {
+                Atom::Sym(marker) if marker == "&optional" => Some(Section::Optional),
+                Atom::Sym(marker) if marker == "&rest" || marker == "&body" => Some(Section::Rest),
+                Atom::Sym(marker) if marker == "&key" => Some(Section::Key),
+                _ => None,
+            };
+            if let Some(next) = next {
+                if next as u8 <= section as u8 || (section == Section::Rest && list.rest.is_none())
+                {
+                    return Err(LispError::syntax("lambda", "misplaced lambda list keyword"));
+                }
+                list.has_keys |= next == Section::Key;
+                section = next;
+                continue;
+            }
+
+            match section {
+                Section::Required => list.required.push(param_name(&p)?),
+                Section::Optional => list.optional.push(opt_param(&p, false)?),
+                Section::Rest if list.rest.is_none() => list.rest = Some(param_name(&p)?),
+                Section::Rest => {
+                    return Err(LispError::syntax("lambda", "&rest takes a single param"))
+                }
+                Section::Key => list.keys.push(opt_param(&p, true)?),
+            }
+        }
+        if section == Section::Rest && list.rest.is_none() {
+            return Err(LispError::syntax("lambda", "&rest needs a param"));
+        }
+        Ok(list)
+    }
+
+    /// Every variable the list binds.
+    fn names(&self) -> impl Iterator<Item = &Arc<str>> {
+        fn opt_names(p: &OptParam) -> [Option<&Arc<str>>; 2] {
+            [Some(&p.name), p.supplied.as_ref()]
+        }
+        self.required
+            .iter()
+            .chain(self.optional.iter().flat_map(opt_names).flatten())
+            .chain(self.rest.iter())
+            .chain(self.keys.iter().flat_map(opt_names).flatten())
+    }
+
+    /// Record which params are special variables in `s`.
+    pub fn find_specials(&mut self, s: &Env) {
+        self.specials = self.names().filter(|n| s.is_special(n)).cloned().collect();
+    }
+
+    /// Whether the list is only required params.
+    pub fn is_simple(&self) -> bool {
+        self.optional.is_empty() && self.rest.is_none() && !self.has_keys
+    }
+
+    /// The number of arguments accepted, for arity errors.
+    pub fn expected(&self) -> String {
+        let min = self.required.len();
+        let max = min + self.optional.len();
+        match () {
+            _ if self.rest.is_some() || self.has_keys => format!("at least {min}"),
+            _ if min == max => min.to_string(),
+            _ => format!("{min} to {max}"),
+        }
+    }
+
+    /// Bind `args` on top of `captured`, for a call to the function `name`.
+    /// Default forms are evaluated in the scope of the params before them.
+    /// Special params are bound dynamically, their old values recorded in
+    /// `saved` for `restore_specials`, even if binding fails.
+    pub fn bind(
+        &self,
+        name: &str,
+        args: Vec<SAtom>,
+        captured: &Scope,
+        saved: &mut Vec<(String, Option<SAtom>)>,
+        s: &mut Env,
+    ) -> Result<Scope, EvalError> {
+        let count = args.len();
+        let max = self.required.len() + self.optional.len();
+        if count < self.required.len() || (count > max && self.rest.is_none() && !self.has_keys) {
+            return Err(LispError::arity(name, &self.expected(), count).into());
+        }
+
+        let mut args = args.into_iter();
+        let mut scope = match self.specials.is_empty() {
+            true => captured.extend(
+                self.required
+                    .iter()
+                    .cloned()
+                    .zip(args.by_ref().map(Binding::new)),
+            ),
+            false => {
+                let mut scope = captured.extend([]);
+                for param in &self.required {
+                    self.bind_var(param, args.next().unwrap(), &mut scope, saved, s);
+                }
+                scope
+            }
+        };
+        if self.is_simple() {
+            return Ok(scope);
+        }
+
+        for param in &self.optional {
+            let arg = args.next();
+            self.bind_opt(param, arg, &mut scope, saved, s)?;
+        }
+
+        let rest: Vec<SAtom> = args.collect();
+        if let Some(rest_name) = &self.rest {
+            let list = match rest.is_empty() {
+                true => nil!(),
+                false => Atom::Cons(rest.iter().cloned().collect()),
+            };
+            self.bind_var(rest_name, list.into(), &mut scope, saved, s);
+        }
+
+        if self.has_keys {
+            if !rest.len().is_multiple_of(2) {
+                return Err(LispError::Keyword {
+                    name: name.into(),
+                    message: "odd number of keyword arguments".into(),
+                }
+                .into());
+            }
+            for pair in rest.chunks(2) {
+                let known = match &*pair[0] {
+                    Atom::Sym(keyword) => self.keys.iter().any(|key| &key.keyword == keyword),
+                    _ => false,
+                };
+                if !known {
+                    let keys: Vec<&str> = self.keys.iter().map(|k| k.keyword.as_str()).collect();
+                    return Err(LispError::Keyword {
+                        name: name.into(),
+                        message: format!(
+                            "unknown keyword {:?}, expected one of ({})",
+                            pair[0],
+                            keys.join(" ")
+                        ),
+                    }
+                    .into());
+                }
+            }
+            for param in &self.keys {
+                // The first occurrence of a keyword wins.
+                let arg = rest
+                    .chunks(2)
+                    .find(|pair| matches!(&*pair[0], Atom::Sym(k) if *k == param.keyword))
+                    .map(|pair| pair[1].clone());
+                self.bind_opt(param, arg, &mut scope, saved, s)?;
+            }
+        }
+
+        Ok(scope)
+    }
+
+    fn bind_var(
+        &self,
+        name: &Arc<str>,
+        value: SAtom,
+        scope: &mut Scope,
+        saved: &mut Vec<(String, Option<SAtom>)>,
+        s: &mut Env,
+    ) {
+        match self.specials.contains(name) {
+            true => saved.push(s.bind_special(name.to_string(), value)),
+            false => scope.insert(name.clone(), value.into()),
+        }
+    }
+
+    fn bind_opt(
+        &self,
+        param: &OptParam,
+        arg: Option<SAtom>,
+        scope: &mut Scope,
+        saved: &mut Vec<(String, Option<SAtom>)>,
+        s: &mut Env,
+    ) -> Result<(), EvalError> {
+        let supplied = arg.is_some();
+        let value = match (arg, &param.default) {
+            (Some(arg), _) => arg,
+            (None, Some(default)) => eval_in(default.clone(), scope, s)?,
+            (None, None) => nil!().into(),
+        };
+        self.bind_var(&param.name, value, scope, saved, s);
+        if let Some(supplied_name) = &param.supplied {
+            let flag = if supplied { t!() } else { nil!() };
+            self.bind_var(supplied_name, flag.into(), scope, saved, s);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{lisp_parsing::parse, num, sexpr, sym, test_utils::run};
+
+    use super::*;
+
+    #[test]
+    fn test_parse_lambda_list() {
+        let list = LambdaList::parse(&parse(
+            "(a &optional (b 2 b_p) c &rest r &key d ((:e ee) 5))",
+        ))
+        .unwrap();
+        assert_eq!(list.required, vec!["a".into()]);
+        assert_eq!(list.optional.len(), 2);
+        assert_eq!(list.optional[0].supplied, Some("b_p".into()));
+        assert_eq!(list.rest, Some("r".into()));
+        assert_eq!(list.keys[0].keyword, ":d");
+        assert_eq!((&*list.keys[1].name, &*list.keys[1].keyword), ("ee", ":e"));
+        assert_eq!(list.expected(), "at least 1");
+
+        let list = LambdaList::parse(&parse("(a &optional b)")).unwrap();
+        assert_eq!(list.expected(), "1 to 2");
+
+        for bad in ["(&rest)", "(&key a &optional b)", "(&rest a b)", "(1)"] {
+            assert!(LambdaList::parse(&parse(bad)).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_optional_rest_key() {
+        let env = &mut Env::default();
+        run(
+            "(defun opt (a &optional (b (add a 1) b_p) c) (list a b b_p c))",
+            env,
+        )
+        .unwrap();
+        assert_eq!(
+            *run("(opt 1)", env).unwrap(),
+            sexpr!(num!(1), num!(2), nil!(), nil!())
+        );
+        assert_eq!(
+            *run("(opt 1 5 6)", env).unwrap(),
+            sexpr!(num!(1), num!(5), t!(), num!(6))
+        );
+
+        run("(defun rest (a &rest more) (list a more))", env).unwrap();
+        assert_eq!(
+            *run("(rest 1 2 3)", env).unwrap(),
+            sexpr!(num!(1), sexpr!(num!(2), num!(3)))
+        );
+        assert_eq!(*run("(rest 1)", env).unwrap(), sexpr!(num!(1), nil!()));
+
+        run(
+            "(defun keys (&key (width 10) height ((:depth d) 1 d_p)) (list width height d d_p))",
+            env,
+        )
+        .unwrap();
+        assert_eq!(
+            *run("(keys :height 2 :depth 3 :height 4)", env).unwrap(),
+            sexpr!(num!(10), num!(2), num!(3), t!())
+        );
+        assert_eq!(
+            *run("(keys)", env).unwrap(),
+            sexpr!(num!(10), nil!(), num!(1), nil!())
+        );
+        assert_eq!(*run(":height", env).unwrap(), sym!(":height"));
+
+        let err = run("(opt)", env).unwrap_err();
+        assert_eq!(
+            err.error.to_string(),
+            "`opt` expects 1 to 3 argument(s), but was called with 0"
+        );
+        let err = run("(keys :width)", env).unwrap_err();
+        assert_eq!(
+            err.error.to_string(),
+            "`keys`: odd number of keyword arguments"
+        );
+        let err = run("(keys :size 1)", env).unwrap_err();
+        assert_eq!(
+            err.error.to_string(),
+            "`keys`: unknown keyword :size, expected one of (:width :height :depth)"
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use std::{fmt, sync::Arc};
+
+use crate::{
+    atom::{Atom, SAtom},
+    conditions::Condition,
+    env::Binding,
+    lisp_parsing::ParseError,
+    span::{self, Span},
+};
+
+/// Everything that can go wrong while reading or evaluating Lisp code.
+#[derive(Debug, Clone)]
+pub enum LispError {
+    /// A symbol was evaluated without being bound to a value.
+    UnboundVariable(String),
+    /// A symbol was called without being bound to a function.
+    UnboundFunction(String),
+    /// A function was called with the wrong number of arguments.
+    Arity {
+        name: String,
+        expected: String,
+        got: usize,
+    },
+    /// A function was called with bad keyword arguments.
+    Keyword { name: String, message: String },
+    /// A value of the wrong type was handed to a function.
+    Type {
+        expected: &'static str,
+        actual: SAtom,
+    },
+    /// A number was divided by zero.
+    DivisionByZero,
+    /// A special form was written with an invalid shape.
+    Syntax {
+        form: &'static str,
+        message: &'static str,
+    },
+    /// A condition raised by Lisp code with `error` or `signal`.
+    User(Arc<Condition>),
+    /// A host I/O operation failed.
+    Io(String),
+    /// Source text couldn't be read.
+    Parse(Box<ParseError>),
+    /// A `return-from`, `go` or `throw` with no active target.
+    Control(String),
+    /// A non-local exit unwinding to its target. It isn't an error: it only
+    /// travels as one so `?` carries it, and handlers never see it.
+    Exit(Box<Exit>),
+}
+
+/// Where a non-local exit is going, see `control.rs`.
+#[derive(Debug, Clone)]
+pub enum Exit {
+    /// `return-from` the block with this marker, with a value.
+    Block(Binding, SAtom),
+    /// `go` to a tag of the tagbody with this marker.
+    Go(Binding, SAtom),
+    /// `throw` to the innermost `catch` of a tag, with a value.
+    Throw(SAtom, SAtom),
+}
+
+impl LispError {
+    pub fn arity(name: &str, expected: &str, got: usize) -> Self {
+        LispError::Arity {
+            name: name.into(),
+            expected: expected.into(),
+            got,
+        }
+    }
+
+    pub fn type_error(expected: &'static str, actual: &SAtom) -> Self {
+        LispError::Type {
+            expected,
+            actual: actual.clone(),
+        }
+    }
+
+    pub fn syntax(form: &'static str, message: &'static str) -> Self {
+        LispError::Syntax { form, message }
+    }
+
+    /// Name of the condition type this error is signalled as.
+    pub fn kind_name(&self) -> &str {
+        match self {
+            LispError::UnboundVariable(_) => "unbound-variable",
+            LispError::UnboundFunction(_) => "undefined-function",
+            LispError::Arity { .. } | LispError::Keyword { .. } | LispError::Syntax { .. } => {
+                "program-error"
+            }
+            LispError::Type { .. } => "type-error",
+            LispError::DivisionByZero => "division-by-zero",
+            LispError::User(cond) => &cond.kind,
+            LispError::Io(_) => "file-error",
+            LispError::Parse(_) => "reader-error",
+            LispError::Control(_) | LispError::Exit(_) => "control-error",
+        }
+    }
+
+    /// The condition object handlers see for this error.
+    pub fn condition(&self) -> Arc<Condition> {
+        match self {
+            LispError::User(cond) => cond.clone(),
+            _ => Arc::new(Condition {
+                kind: self.kind_name().to_string(),
+                message: self.to_string(),
+                args: SAtom::new(Atom::Nil),
+                is_error: true,
+            }),
+        }
+    }
+}
+
+impl fmt::Display for LispError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LispError::UnboundVariable(name) => write!(f, "unbound variable `{name}`"),
+            LispError::UnboundFunction(name) => write!(f, "undefined function `{name}`"),
+            LispError::Arity {
+                name,
+                expected,
+                got,
+            } => write!(
+                f,
+                "`{name}` expects {expected} argument(s), but was called with {got}"
+            ),
+            LispError::Keyword { name, message } => write!(f, "`{name}`: {message}"),
+            LispError::Type { expected, actual } => {
+                write!(f, "type error: expected {expected}, got {:?}", actual)
+            }
+            LispError::Syntax { form, message } => write!(f, "malformed `{form}`: {message}"),
+            LispError::DivisionByZero => write!(f, "division by zero"),
+            LispError::User(cond) if cond.message.is_empty() => write!(f, "{}", cond.kind),
+            LispError::User(cond) => write!(f, "{}", cond.message),
+            LispError::Io(msg) => write!(f, "I/O error: {msg}"),
+            LispError::Parse(err) => write!(f, "{err}"),
+            LispError::Control(msg) => write!(f, "{msg}"),
+            LispError::Exit(exit) => match &**exit {
+                Exit::Block(marker, _) => write!(f, "return from block {:?}", marker.get()),
+                Exit::Go(_, tag) => write!(f, "go to tag {tag:?}"),
+                Exit::Throw(tag, _) => write!(f, "throw to tag {tag:?}"),
+            },
+        }
+    }
+}
+
+/// A `LispError` together with the location of the form that raised it and
+/// the call frames that were active, innermost first.
+#[derive(Debug, Clone)]
+pub struct EvalError {
+    pub error: LispError,
+    pub span: Option<Box<Span>>,
+    pub backtrace: Vec<String>,
+    /// Whether the handlers have already seen this error.
+    pub signalled: bool,
+}
+
+impl EvalError {
+    /// Whether this is a non-local exit rather than an error.
+    pub fn is_exit(&self) -> bool {
+        matches!(self.error, LispError::Exit(_))
+    }
+
+    /// Attach the location of `form`, unless a more precise one is known.
+    pub fn at(mut self, form: &SAtom) -> Self {
+        if self.span.is_none() {
+            self.span = span::lookup(form).map(Box::new);
+        }
+        self
+    }
+
+    /// Record one more frame while the error unwinds through a call.
+    /// Non-local exits don't keep a backtrace.
+    pub fn with_frame(mut self, frame: String) -> Self {
+        if !self.is_exit() {
+            self.backtrace.push(frame);
+        }
+        self
+    }
+}
+
+impl From<LispError> for EvalError {
+    fn from(error: LispError) -> Self {
+        EvalError {
+            error,
+            span: None,
+            backtrace: Vec::new(),
+            signalled: false,
+        }
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(span) = &self.span {
+            write!(f, "{span}: ")?;
+        }
+        write!(f, "{}", self.error)?;
+        if !self.backtrace.is_empty() {
+            write!(f, "\nBacktrace:")?;
+            for (depth, frame) in self.backtrace.iter().enumerate() {
+                write!(f, "\n  {depth}: {frame}")?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,217 +1,694 @@
+use std::{collections::VecDeque, mem, sync::Arc};
+
 use crate::{
-    atom::{Atom, SAtom},
+    atom::{Atom, Fun, SAtom},
+    conditions::{self, eval_body},
     cons,
-    env::{get_args_from_val, Env},
-    sexpr::{SExpr},
+    env::{get_args_from_val, Backend, Binding, Env, Scope},
+    lisp_error::{EvalError, Exit, LispError},
+    sexpr::SExpr,
+    span,
+    vm::{self, Closure},
 };
 
-pub type EvalResult = Result<SAtom, &'static str>;
+pub type EvalResult = Result<SAtom, EvalError>;
+pub type TailResult = Result<Tail, EvalError>;
+
+/// Outcome of one evaluation step: a value, or the form to continue with.
+///
+/// Forms in tail position are handed back to `run` instead of being
+/// evaluated recursively, so tail calls don't grow the Rust stack.
+pub enum Tail {
+    Done(SAtom),
+    /// Evaluate the form with the current bindings.
+    Eval(SAtom),
+    /// Evaluate a function body with `bindings` as the lexical environment.
+    Call(SAtom, Scope),
+    /// Like `Call`, but for a local scope such as `let` rather than a
+    /// function body, so no call frame is recorded.
+    Scope(SAtom, Scope),
+    /// Call a function compiled for the VM.
+    Compiled(Arc<Closure>, Vec<SAtom>),
+    /// Continue with the step inside `Extent`, which is closed once the
+    /// step has a value, so the last form of a `block`, `catch` or
+    /// `unwind-protect` is in tail position too.
+    Within(Extent, Box<Tail>),
+}
+
+/// A dynamic extent entered by a step handed back to `run`.
+pub enum Extent {
+    /// A `block`, whose marker is set to nil on exit.
+    Block(Binding),
+    /// A `catch` of the tag, pushed at this depth of `Env::catches`.
+    Catch(SAtom, usize),
+    /// The cleanup forms of an `unwind-protect`, with the scope they run in.
+    Cleanup(SAtom, Scope),
+}
+
+impl Extent {
+    /// Leave the extent with `res`, the value or error of the step run in it:
+    /// an exit to a `block` or `catch` becomes its value, and the cleanup
+    /// forms of an `unwind-protect` run either way.
+    pub fn close(self, res: EvalResult, s: &mut Env) -> EvalResult {
+        match self {
+            Extent::Block(marker) => {
+                marker.set(Atom::Nil.into());
+                match res {
+                    Err(err) => match &err.error {
+                        LispError::Exit(exit) => match &**exit {
+                            Exit::Block(target, value) if target.same(&marker) => Ok(value.clone()),
+                            _ => Err(err),
+                        },
+                        _ => Err(err),
+                    },
+                    res => res,
+                }
+            }
+            Extent::Catch(tag, depth) => {
+                s.catches.truncate(depth);
+                match res {
+                    Err(err) => match &err.error {
+                        LispError::Exit(exit) => match &**exit {
+                            Exit::Throw(target, value) if *target == tag => Ok(value.clone()),
+                            _ => Err(err),
+                        },
+                        _ => Err(err),
+                    },
+                    res => res,
+                }
+            }
+            Extent::Cleanup(cleanup, scope) => {
+                let caller_val = mem::replace(&mut s.val, scope);
+                let cleaned = match &*cleanup {
+                    Atom::Cons(cleanup) => eval_body(cleanup.iter(), s),
+                    _ => Ok(Atom::Nil.into()),
+                };
+                s.val = caller_val;
+                cleaned?;
+                res
+            }
+        }
+    }
+}
+
+/// How many tail-called frames are kept for backtraces.
+pub const TAIL_FRAMES: usize = 16;
+
+/// Forms whose arguments are handed to the builtin unevaluated.
+pub const SPECIAL_FORMS: &[&str] = &[
+    "lambda",
+    "quote",
+    "function",
+    "if",
+    "when",
+    "unless",
+    "cond",
+    "and",
+    "or",
+    "case",
+    "ecase",
+    "progn",
+    "let",
+    "let*",
+    "letrec",
+    "flet",
+    "labels",
+    "defun",
+    "defmacro",
+    "defvar",
+    "defparameter",
+    "setq",
+    "block",
+    "return-from",
+    "return",
+    "tagbody",
+    "go",
+    "catch",
+    "while",
+    "dotimes",
+    "dolist",
+    "do",
+    "handler-case",
+    "handler-bind",
+    "ignore-errors",
+    "unwind-protect",
+    "quasiquote",
+    "unquote",
+    "unquote-splicing",
+];
 
 pub enum Args<'a> {
     S(&'a SExpr),
     Nil,
 }
 
-#[derive(Debug)]
-pub enum TypeError {
-    ExpectedSExprOrNil,
-}
-
-impl From<TypeError> for &str {
-    fn from(value: TypeError) -> Self {
-        match value {
-            TypeError::ExpectedSExprOrNil => "Expected SExpr | Nil",
+impl Args<'_> {
+    pub fn to_vec(&self) -> Vec<SAtom> {
+        match self {
+            Args::S(args) => args.iter().collect(),
+            Args::Nil => Vec::new(),
         }
     }
 }
 
 impl<'a> TryFrom<&'a Atom> for Args<'a> {
-    type Error = TypeError;
+    type Error = LispError;
 
     fn try_from(v: &'a Atom) -> Result<Self, Self::Error> {
         match v {
             Atom::Cons(s) => Ok(Args::S(s)),
             Atom::Nil => Ok(Args::Nil),
-            _ => Err(TypeError::ExpectedSExprOrNil),
+            _ => Err(LispError::type_error("list", &v.clone().into())),
         }
     }
 }
 
+/// Describe a call frame for backtraces, with its location when known.
+pub fn frame(call: String, form: &SAtom) -> String {
+    match span::lookup(form) {
+        Some(span) => format!("{call} at {span}"),
+        None => call,
+    }
+}
+
+/// Evaluate `v` with the backend selected in `s`.
 pub fn eval(v: SAtom, s: &mut Env) -> EvalResult {
+    match s.backend {
+        Backend::Interpreter => interpret(v, s),
+        Backend::Vm => vm::eval(v, s),
+    }
+}
+
+/// Evaluate `v` by walking the form.
+pub fn interpret(v: SAtom, s: &mut Env) -> EvalResult {
+    if !s.trace {
+        return run(Tail::Eval(v), s);
+    }
+
     let eval_body = format!("{:#?}", &*v);
-    let res = match &*v {
-        Atom::Sym(sym) => Ok(s.val.get(sym).ok_or_else(|| "Argument not found")?.clone()),
+    let res = run(Tail::Eval(v), s);
+
+    if let Ok(res) = &res {
+        println!("eval: \n{}\n=>{:?}", eval_body, res);
+    }
+
+    res
+}
+
+/// Drive `tail` to a value. The caller's lexical bindings are restored once
+/// at the end, however many function bodies were entered on the way.
+pub fn run(tail: Tail, s: &mut Env) -> EvalResult {
+    match drive(tail, s, false)? {
+        Tail::Done(v) => Ok(v),
+        _ => unreachable!(),
+    }
+}
+
+/// Like `run`, but stop at a tail call to a compiled function and return it
+/// as `Tail::Compiled`, so the VM can run it in place of its current frame.
+/// The extents it runs in are left open, wrapped around it as `Tail::Within`,
+/// outermost first, for the VM to close.
+pub fn run_to_vm(tail: Tail, s: &mut Env) -> TailResult {
+    drive(tail, s, true)
+}
+
+fn drive(mut tail: Tail, s: &mut Env, to_vm: bool) -> TailResult {
+    let mut caller_val = None;
+    // (call form, evaluated call) of the bodies entered, newest last
+    let mut frames = VecDeque::new();
+    // extents entered on the way, innermost last
+    let mut extents: Vec<Extent> = Vec::new();
+
+    let res = loop {
+        tail = match tail {
+            Tail::Done(v) => break Ok(Tail::Done(v)),
+            tail @ Tail::Compiled(..) if to_vm => {
+                let extents = mem::take(&mut extents).into_iter().rev();
+                break Ok(extents.fold(tail, |tail, extent| Tail::Within(extent, Box::new(tail))));
+            }
+            Tail::Eval(form) => match eval_form(&form, s, &mut frames) {
+                Ok(next) => next,
+                Err(e) => break Err(conditions::signal_error(s, e.at(&form))),
+            },
+            Tail::Call(body, bindings) | Tail::Scope(body, bindings) => {
+                let val = mem::replace(&mut s.val, bindings);
+                caller_val.get_or_insert(val);
+                Tail::Eval(body)
+            }
+            Tail::Compiled(closure, args) => match vm::call(&closure, args, s) {
+                Ok(v) => Tail::Done(v),
+                Err(e) => break Err(e),
+            },
+            Tail::Within(extent, next) => {
+                extents.push(extent);
+                *next
+            }
+        };
+    };
+
+    if let Some(val) = caller_val {
+        s.val = val;
+    }
+    let res = extents.into_iter().rev().fold(res, |res, extent| {
+        let value = res.map(|tail| match tail {
+            Tail::Done(v) => v,
+            _ => unreachable!("extents are handed to the VM with its call"),
+        });
+        extent.close(value, s).map(Tail::Done)
+    });
+    res.map_err(|e| {
+        frames.into_iter().rev().fold(e, |e, (form, call)| {
+            e.with_frame(frame(format!("{:?}", call), &form))
+        })
+    })
+}
+
+/// Expand `form` once if it is a call to a macro, `None` otherwise.
+pub fn macroexpand_1(form: &SAtom, s: &mut Env) -> Result<Option<SAtom>, EvalError> {
+    let Atom::Cons(SExpr { car, cdr }) = &**form else {
+        return Ok(None);
+    };
+    let Atom::Sym(name) = &**car else {
+        return Ok(None);
+    };
+    // Local functions shadow global macros.
+    if s.val.get_fun(name).is_some() {
+        return Ok(None);
+    }
+    let Some(transformer) = s.macros.get(name).cloned() else {
+        return Ok(None);
+    };
+
+    let args = Args::try_from(&**cdr)?;
+    transformer
+        .call(s, &args)
+        .map(Some)
+        .map_err(|e| e.with_frame(frame(format!("macroexpand {:?}", form), form)))
+}
+
+/// Call `fun`, keeping a frame for the body it enters, if any.
+fn call_fun(
+    fun: &Fun,
+    s: &mut Env,
+    args: &Args,
+    v: &SAtom,
+    call: impl Fn() -> Atom,
+    frames: &mut VecDeque<(SAtom, Atom)>,
+) -> TailResult {
+    match fun.call_tail(s, args) {
+        Ok(tail @ (Tail::Call(..) | Tail::Compiled(..))) => {
+            if frames.len() == TAIL_FRAMES {
+                frames.pop_front();
+            }
+            frames.push_back((v.clone(), call()));
+            Ok(tail)
+        }
+        Ok(tail) => Ok(tail),
+        Err(e) => Err(e.with_frame(frame(format!("{:?}", call()), v))),
+    }
+}
+
+fn eval_form(v: &SAtom, s: &mut Env, frames: &mut VecDeque<(SAtom, Atom)>) -> TailResult {
+    if let Some(expansion) = macroexpand_1(v, s)? {
+        return Ok(Tail::Eval(expansion));
+    }
+
+    match &**v {
+        Atom::Sym(sym) => Ok(Tail::Done(
+            s.get_val(sym)
+                .ok_or_else(|| LispError::UnboundVariable(sym.clone()))?,
+        )),
         Atom::Cons(SExpr { car, cdr }) => {
             let fname = match &**car {
                 Atom::Sym(f) => Ok(f),
                 Atom::Fun(fun) => {
-                    let args: Result<Args, TypeError> = (&**cdr).try_into();
-                    match args {
-                        Ok(args) => return fun.call(s, &args),
-                        Err(err) => return Err(err.into()),
-                    }
+                    let args = Args::try_from(&**cdr)?;
+                    return call_fun(fun, s, &args, v, || (**v).clone(), frames);
                 }
                 Atom::Cons(_) => {
                     let car_eval = eval(car.clone(), s)?;
-                    let eval_res = eval(SAtom::new(cons!(car_eval, cdr.clone())), s);
-                    return eval_res;
+                    return Ok(Tail::Eval(SAtom::new(cons!(car_eval, cdr.clone()))));
                 }
-                _ => Err("Only symbol can be used for calling"),
+                _ => Err(LispError::type_error("function", car)),
             }?;
 
-            let funs = s.fun.clone();
-            let fun = funs.get(fname).expect("Unknown function");
-            let args = get_args_from_val(
-                &**cdr,
-                s,
-                fname != "lambda" && fname != "quote" && fname != "if",
-            );
-
-            // println!("Calling {:?} with {:?}", fname, args);
-            let v: &Atom = &Atom::Cons(args);
-            let args = Args::try_from(v)?;
-            let res = fun.call(s, &args);
-            // println!("Called {:?} => {:?}", fname, res);
-            res
+            let fun = s
+                .get_fun(fname)
+                .ok_or_else(|| LispError::UnboundFunction(fname.clone()))?;
+            if **cdr == Atom::Nil {
+                let call = || cons!(car.clone(), Atom::Nil);
+                return call_fun(&fun, s, &Args::Nil, v, call, frames);
+            }
+            let args = get_args_from_val(cdr, s, !SPECIAL_FORMS.contains(&fname.as_str()))?;
+
+            let call = || cons!(car.clone(), Atom::Cons(args.clone()));
+            call_fun(&fun, s, &Args::S(&args), v, call, frames)
         }
-        _ => Ok(v),
-    };
-
-    println!("eval: \n{}\n=>{:?}", eval_body, res.clone().unwrap());
-
-    res
+        _ => Ok(Tail::Done(v.clone())),
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
     use super::*;
     use crate::{cons, lisp_parsing::parse, nil, num, sexpr, str, sym, t};
 
+    /// Run `test` against a fresh `Env` for each backend, so the VM is held
+    /// to the same results as the interpreter.
+    fn with_backends(test: impl Fn(&mut Env)) {
+        for backend in [Backend::Interpreter, Backend::Vm] {
+            let env = &mut Env::default();
+            env.backend = backend;
+            test(env);
+        }
+    }
+
     #[test]
     fn test_basic_eval() {
-        let env = &mut Env::default();
-        env.val.insert("a".into(), num!(1).into());
-        env.val.insert("b".into(), num!(2).into());
+        with_backends(|env| {
+            env.val.insert("a".into(), num!(1).into());
+            env.val.insert("b".into(), num!(2).into());
 
-        let parsed_input = parse("a");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(1));
+            let parsed_input = parse("a");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(1));
 
-        let parsed_input = parse("b");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(2));
+            let parsed_input = parse("b");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(2));
 
-        let parsed_input = parse("(quote a)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), sym!("a"));
+            let parsed_input = parse("(quote a)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), sym!("a"));
 
-        let parsed_input = parse("(quote b)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), sym!("b"));
+            let parsed_input = parse("(quote b)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), sym!("b"));
+        });
     }
 
     #[test]
     fn test_add() {
-        let env = &mut Env::default();
-        let parsed_input = parse("(add 3 4 5)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(12.0));
+        with_backends(|env| {
+            let parsed_input = parse("(add 3 4 5)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(12));
 
-        let parsed_input = parse("(add (add 6 7) 8)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(21.0));
+            let parsed_input = parse("(add (add 6 7) 8)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(21));
 
-        let parsed_input = parse("(add 9 (add 10 11))");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(30.0));
+            let parsed_input = parse("(add 9 (add 10 11))");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(30));
+        });
     }
 
     #[test]
     fn test_mul() {
-        let env = &mut Env::default();
-        let parsed_input = parse("(mul 1 2)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(2.0));
+        with_backends(|env| {
+            let parsed_input = parse("(mul 1 2)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(2));
 
-        let parsed_input = parse("(mul 3 4 5)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(60.0));
+            let parsed_input = parse("(mul 3 4 5)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(60));
 
-        let parsed_input = parse("(mul (mul 6 7) 8)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(336.0));
+            let parsed_input = parse("(mul (mul 6 7) 8)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(336));
 
-        let parsed_input = parse("(mul 9 (mul 10 11))");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(990.0));
+            let parsed_input = parse("(mul 9 (mul 10 11))");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(990));
+        });
     }
 
     #[test]
     fn test_addmul() {
-        let env = &mut Env::default();
-        let parsed_input = parse("(add (mul 3 4) 5)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(17.0));
+        with_backends(|env| {
+            let parsed_input = parse("(add (mul 3 4) 5)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(17));
 
-        let parsed_input = parse("(mul (add 3 4) 5)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(35.0));
+            let parsed_input = parse("(mul (add 3 4) 5)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(35));
 
-        let parsed_input = parse("(add 3 (mul 4 5))");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(23.0));
+            let parsed_input = parse("(add 3 (mul 4 5))");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(23));
 
-        let parsed_input = parse("(mul 3 (add 4 5))");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(27.0));
+            let parsed_input = parse("(mul 3 (add 4 5))");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(27));
+        });
     }
 
     #[test]
     fn test_sub() {
-        let env = &mut Env::default();
-        let parsed_input = parse("(sub 1 2)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(-1.0));
+        with_backends(|env| {
+            let parsed_input = parse("(sub 1 2)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(-1));
+
+            let parsed_input = parse("(sub 3 4 5)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(-6));
+
+            let parsed_input = parse("(sub (sub 6 7) 8)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(-9));
+
+            let parsed_input = parse("(sub 9 (sub 10 11))");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(10));
+        });
+    }
+
+    #[test]
+    fn test_numeric_tower() {
+        with_backends(|env| {
+            let parsed_input = parse("(defun fact (n) (if (eq n 0) 1 (mul n (fact (sub n 1)))))");
+            eval(parsed_input.into(), env).unwrap();
+            let res = eval(parse("(fact 25)").into(), env).unwrap();
+            assert_eq!(format!("{:?}", res), "15511210043330985984000000");
+            let res = eval(parse("(div (fact 25) (fact 24))").into(), env).unwrap();
+            assert_eq!(*res, num!(25));
+
+            let cases = [
+                ("(div 1 3)", "1/3"),
+                ("(add 1/3 2/3)", "1"),
+                ("(mul 1/3 3.0)", "1.0"),
+                ("(sub 9007199254740993 1)", "9007199254740992"),
+                ("(add 0.5 1)", "1.5"),
+                ("(list 1 2.0 3/4)", "(1 2.0 3/4)"),
+            ];
+            for (src, expected) in cases {
+                let res = eval(parse(src).into(), env).unwrap();
+                assert_eq!(format!("{:?}", res), expected, "{src}");
+            }
+
+            let res = eval(parse("(eq (div 4 2) 2)").into(), env).unwrap();
+            assert_eq!(*res, t!());
+            let res = eval(parse("(eq 2 2.0)").into(), env).unwrap();
+            assert_eq!(*res, nil!());
+
+            let parsed_input = parse("(handler-case (div 1 0) (division-by-zero (c) 'caught))");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), sym!("caught"));
+        });
+    }
+
+    #[test]
+    fn test_comparisons() {
+        with_backends(|env| {
+            let cases = [
+                ("(= 2 2.0 4/2)", "T"),
+                ("(= 1 2)", "Nil"),
+                ("(/= 1 2 3)", "T"),
+                ("(/= 1 2 1)", "Nil"),
+                ("(< 1 3/2 2.0 100000000000000000000)", "T"),
+                ("(< 1 1)", "Nil"),
+                ("(<= 1 1 2)", "T"),
+                ("(> 3 2 1)", "T"),
+                ("(>= 3 3 4)", "Nil"),
+                ("(< 5)", "T"),
+                ("(zerop 0.0)", "T"),
+                ("(plusp -1/2)", "Nil"),
+                ("(minusp -1/2)", "T"),
+                ("(evenp 100000000000000000000)", "T"),
+                ("(oddp -3)", "T"),
+                ("(max 1 5/2 2)", "5/2"),
+                ("(min 3 -1.5 7)", "-1.5"),
+                ("(+ 1 2 3)", "6"),
+                ("(- 10 1 2)", "7"),
+                ("(* 2 3)", "6"),
+                ("(/ 12 3)", "4"),
+                ("(- 5)", "-5"),
+                ("(/ 4)", "1/4"),
+                ("(/ 0.5)", "2.0"),
+                ("(+)", "0"),
+                ("(*)", "1"),
+                ("(+ 7)", "7"),
+                ("(= 1/3 0.3333333333333333)", "Nil"),
+                ("(< 9007199254740992.0 9007199254740993)", "T"),
+            ];
+            for (src, expected) in cases {
+                let res = eval(parse(src).into(), env).unwrap();
+                assert_eq!(format!("{:?}", res), expected, "{src}");
+            }
+
+            let parsed_input =
+                parse("(defun fib (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))");
+            eval(parsed_input.into(), env).unwrap();
+            assert_eq!(*eval(parse("(fib 20)").into(), env).unwrap(), num!(6765));
+
+            let err = eval(parse("(evenp 1.0)").into(), env).unwrap_err();
+            assert_eq!(err.error.kind_name(), "type-error");
+            let err = eval(parse("(< 1 \"a\")").into(), env).unwrap_err();
+            assert_eq!(err.error.kind_name(), "type-error");
+            let err = eval(parse("(max)").into(), env).unwrap_err();
+            assert_eq!(err.error.kind_name(), "program-error");
+            let err = eval(parse("(-)").into(), env).unwrap_err();
+            assert_eq!(err.error.kind_name(), "program-error");
+
+            // arguments are values: symbols and lists aren't evaluated again
+            let parsed_input = parse("(setq code '(progn (setq k 100) 4))");
+            eval(parsed_input.into(), env).unwrap();
+            for src in [
+                "(< 1 code)",
+                "(< 1 'a)",
+                "(zerop code)",
+                "(max 1 code)",
+                "(+ 1 code)",
+            ] {
+                let err = eval(parse(src).into(), env).unwrap_err();
+                assert_eq!(err.error.kind_name(), "type-error", "{src}");
+            }
+            assert_eq!(
+                eval(parse("k").into(), env).unwrap_err().error.kind_name(),
+                "unbound-variable"
+            );
+        });
+    }
+
+    #[test]
+    fn test_math_library() {
+        with_backends(|env| {
+            let parsed_input = parse(
+                "(defun digits (n) (if (< n 10) (list n) (append (digits (car (floor n 10))) (list (mod n 10)))))",
+            );
+            eval(parsed_input.into(), env).unwrap();
+            let res = eval(parse("(digits (expt 2 20))").into(), env).unwrap();
+            assert_eq!(
+                *res,
+                sexpr!(
+                    num!(1),
+                    num!(0),
+                    num!(4),
+                    num!(8),
+                    num!(5),
+                    num!(7),
+                    num!(6)
+                )
+            );
+
+            let parsed_input = parse("(defun hypot (a b) (sqrt (+ (* a a) (* b b))))");
+            eval(parsed_input.into(), env).unwrap();
+            assert_eq!(*eval(parse("(hypot 3 4)").into(), env).unwrap(), num!(5.0));
 
-        let parsed_input = parse("(sub 3 4 5)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(-6.0));
+            let parsed_input = parse("(list (gcd 84 36) (logior (ash 1 4) 1) (abs -3/4))");
+            let res = eval(parsed_input.into(), env).unwrap();
+            assert_eq!(format!("{:?}", res), "(12 17 3/4)");
+
+            let parsed_input =
+                parse("(handler-case (sqrt -4) (type-error (c) (condition-message c)))");
+            let res = eval(parsed_input.into(), env).unwrap();
+            assert_eq!(
+                *res,
+                str!("type error: expected non-negative number, got -4")
+            );
+        });
+    }
 
-        let parsed_input = parse("(sub (sub 6 7) 8)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(-9.0));
+    #[test]
+    fn test_random() {
+        with_backends(|env| {
+            // a simulation seeded explicitly gives the same result every run
+            let parsed_input = parse(
+                "(defun roll-sum (seed n) (let ((*random-state* (make-random-state seed)) (sum 0)) (dotimes (i n sum) (setq sum (+ sum 1 (random 6))))))",
+            );
+            eval(parsed_input.into(), env).unwrap();
+            let first = eval(parse("(roll-sum 2024 100)").into(), env).unwrap();
+            assert_eq!(
+                *eval(parse("(roll-sum 2024 100)").into(), env).unwrap(),
+                *first
+            );
+            let parsed_input = parse("(<= 100 (roll-sum 2024 100) 600)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), t!());
+            assert_ne!(
+                *eval(parse("(roll-sum 2025 100)").into(), env).unwrap(),
+                *first
+            );
 
-        let parsed_input = parse("(sub 9 (sub 10 11))");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(10.0));
+            // the dynamic binding is undone on exit
+            let parsed_input = parse("(random-state-p *random-state*)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), t!());
+        });
+    }
+
+    #[test]
+    fn test_strings() {
+        with_backends(|env| {
+            let parsed_input = parse(
+                r#"(defun capitalize (word) (concat (string-upcase (substring word 0 1)) (substring word 1)))"#,
+            );
+            eval(parsed_input.into(), env).unwrap();
+            let parsed_input = parse(
+                r#"(let ((words nil)) (dolist (w (split "élan über ñandú")) (setq words (cons (capitalize w) words))) (join (reverse words) " "))"#,
+            );
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                str!("Élan Über Ñandú")
+            );
+
+            let parsed_input =
+                parse(r#"(+ (string->number "40") (length (symbol-name (intern "ab"))))"#);
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(42));
+        });
     }
 
     #[test]
     fn test_car() {
-        let env = &mut Env::default();
-        let parsed_input = parse("(car (list 1 (list 2 3 4 5) 6))");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(1.0));
+        with_backends(|env| {
+            let parsed_input = parse("(car (list 1 (list 2 3 4 5) 6))");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(1));
+        });
     }
 
     #[test]
     fn test_cdr() {
-        let env = &mut Env::default();
-        let parsed_input = parse("(cdr (list 1 (list 2 3 4 5) (list 6) 7))");
-        let res = sexpr!(
-            sexpr!(num!(2), num!(3), num!(4), num!(5)),
-            sexpr!(num!(6)),
-            num!(7),
-        );
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), res.into());
+        with_backends(|env| {
+            let parsed_input = parse("(cdr (list 1 (list 2 3 4 5) (list 6) 7))");
+            let res = sexpr!(
+                sexpr!(num!(2), num!(3), num!(4), num!(5)),
+                sexpr!(num!(6)),
+                num!(7),
+            );
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), res);
+        });
     }
 
     #[test]
     fn test_call_lambda() {
-        let env = &mut Env::default();
-        let parsed_input = parse("(apply (lambda (a b) (add a b)) 1 2)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(3));
+        with_backends(|env| {
+            let parsed_input = parse("(apply (lambda (a b) (add a b)) 1 2)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(3));
 
-        let parsed_input = parse("(apply (lambda () (car (list \"good\"))) )");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), str!("good"));
+            let parsed_input = parse("(apply (lambda () (car (list \"good\"))) )");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), str!("good"));
 
-        let parsed_input = parse("(apply (lambda (fun) (apply fun 1)) (lambda (n) (add 1 n)))");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(2));
+            let parsed_input = parse("(apply (lambda (fun) (apply fun 1)) (lambda (n) (add 1 n)))");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(2));
 
-        let parsed_input = parse(
-            r#"
+            let parsed_input = parse(
+                r#"
 ((lambda (n)
          ((lambda (sub_f) (apply sub_f sub_f n))
                   (lambda (rec n) (if (eq n 0)
                                       0
                                       (apply rec rec (sub n 1))))))
          100)"#,
-        );
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(0));
+            );
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(0));
 
-        for fib_n in 5..=15 {
-            let parsed_input = parse(&format!(
-                r#"
+            for fib_n in 5..=15 {
+                let parsed_input = parse(&format!(
+                    r#"
 ((lambda (n)
    ((lambda (FIB) (apply FIB FIB n)) (lambda (FIB n)
 				       (if (eq n 0)
@@ -221,167 +698,1115 @@ mod tests {
 					   (add (apply FIB FIB (sub n 1))
 						(apply FIB FIB (sub n 2))))))))
  {})"#,
-                fib_n
-            ));
-            assert_eq!(
-                *eval(parsed_input.into(), env).unwrap(),
-                num!((0..fib_n).fold((0f64, 1f64), |(a, b), _| (b, a + b)).0)
-            );
-        }
+                    fib_n
+                ));
+                assert_eq!(
+                    *eval(parsed_input.into(), env).unwrap(),
+                    num!((0..fib_n).fold((0i64, 1i64), |(a, b), _| (b, a + b)).0)
+                );
+            }
+        });
     }
 
     #[test]
     fn test_cons() {
-        let env = &mut Env::default();
-        env.val.insert("a".into(), num!(24).into());
-        env.val.insert("b".into(), num!(42).into());
-
-        let parsed_input = parse("(cons 1 2)");
-        assert_eq!(
-            *eval(parsed_input.into(), env).unwrap(),
-            cons!(num!(1), num!(2))
-        );
+        with_backends(|env| {
+            env.val.insert("a".into(), num!(24).into());
+            env.val.insert("b".into(), num!(42).into());
 
-        let parsed_input = parse("(cons (quote a) (quote b))");
-        assert_eq!(
-            *eval(parsed_input.into(), env).unwrap(),
-            cons!(sym!("a"), sym!("b"))
-        );
+            let parsed_input = parse("(cons 1 2)");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                cons!(num!(1), num!(2))
+            );
 
-        let parsed_input = parse("(cons a b)");
-        assert_eq!(
-            *eval(parsed_input.into(), env).unwrap(),
-            cons!(num!(24), num!(42))
-        );
+            let parsed_input = parse("(cons (quote a) (quote b))");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                cons!(sym!("a"), sym!("b"))
+            );
 
-        let parsed_input = parse("(cons (list a) b)");
-        assert_eq!(
-            *eval(parsed_input.into(), env).unwrap(),
-            cons!(sexpr!(num!(24)), num!(42))
-        );
-        let parsed_input = parse("(cons (list a) (list b))");
-        assert_eq!(
-            *eval(parsed_input.into(), env).unwrap(),
-            cons!(sexpr!(num!(24)), sexpr!(num!(42)))
-        );
+            let parsed_input = parse("(cons a b)");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                cons!(num!(24), num!(42))
+            );
+
+            let parsed_input = parse("(cons (list a) b)");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                cons!(sexpr!(num!(24)), num!(42))
+            );
+            let parsed_input = parse("(cons (list a) (list b))");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                cons!(sexpr!(num!(24)), sexpr!(num!(42)))
+            );
+        });
     }
 
     #[test]
     fn test_eq() {
-        let env = &mut Env::default();
-        env.val.insert("a".into(), num!(24).into());
-        env.val.insert("b".into(), num!(42).into());
+        with_backends(|env| {
+            env.val.insert("a".into(), num!(24).into());
+            env.val.insert("b".into(), num!(42).into());
 
-        let parsed_input = parse("(eq 1 2)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), nil!());
+            let parsed_input = parse("(eq 1 2)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), nil!());
 
-        let parsed_input = parse("(eq 2 1)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), nil!());
+            let parsed_input = parse("(eq 2 1)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), nil!());
 
-        let parsed_input = parse("(eq 1 1)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), t!());
+            let parsed_input = parse("(eq 1 1)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), t!());
 
-        let parsed_input = parse("(eq 2 2)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), t!());
+            let parsed_input = parse("(eq 2 2)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), t!());
 
-        let parsed_input = parse("(eq a b)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), nil!());
+            let parsed_input = parse("(eq a b)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), nil!());
 
-        let parsed_input = parse("(eq b a)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), nil!());
+            let parsed_input = parse("(eq b a)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), nil!());
 
-        let parsed_input = parse("(eq a a)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), t!());
+            let parsed_input = parse("(eq a a)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), t!());
 
-        let parsed_input = parse("(eq b b)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), t!());
+            let parsed_input = parse("(eq b b)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), t!());
 
-        let parsed_input = parse("(eq (list a b) (quote (24 42)))");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), t!());
+            let parsed_input = parse("(eq (list a b) (quote (24 42)))");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), t!());
+        });
     }
 
     #[test]
     fn test_if() {
-        let env = &mut Env::default();
-        env.val.insert("a".into(), num!(24).into());
-        env.val.insert("b".into(), num!(42).into());
+        with_backends(|env| {
+            env.val.insert("a".into(), num!(24).into());
+            env.val.insert("b".into(), num!(42).into());
 
-        let parsed_input = parse("(if (eq t nil) \"TRUE\" \"FALSE\")");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), str!("FALSE"));
+            let parsed_input = parse("(if (eq t nil) \"TRUE\" \"FALSE\")");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), str!("FALSE"));
 
-        let parsed_input = parse("(if (eq t t) \"TRUE\" \"FALSE\")");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), str!("TRUE"));
+            let parsed_input = parse("(if (eq t t) \"TRUE\" \"FALSE\")");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), str!("TRUE"));
+        });
     }
 
     #[test]
     fn test_quote_list() {
-        let env = &mut Env::default();
-        env.val.insert("a".into(), num!(1).into());
-        env.val.insert("b".into(), num!(2).into());
+        with_backends(|env| {
+            env.val.insert("a".into(), num!(1).into());
+            env.val.insert("b".into(), num!(2).into());
 
-        let parsed_input = parse("(quote 1)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(1));
+            let parsed_input = parse("(quote 1)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(1));
 
-        let parsed_input = parse("(quote (1 2))");
-        assert_eq!(
-            *eval(parsed_input.into(), env).unwrap(),
-            sexpr!(num!(1), num!(2))
-        );
+            let parsed_input = parse("(quote (1 2))");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                sexpr!(num!(1), num!(2))
+            );
 
-        let parsed_input = parse("(list 1)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), sexpr!(num!(1)));
+            let parsed_input = parse("(list 1)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), sexpr!(num!(1)));
 
-        let parsed_input = parse("(list 1 2)");
-        assert_eq!(
-            *eval(parsed_input.into(), env).unwrap(),
-            sexpr!(num!(1), num!(2))
-        );
+            let parsed_input = parse("(list 1 2)");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                sexpr!(num!(1), num!(2))
+            );
 
-        let parsed_input = parse("(quote a)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), sym!("a"));
+            let parsed_input = parse("(quote a)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), sym!("a"));
 
-        let parsed_input = parse("(quote (a b))");
-        assert_eq!(
-            *eval(parsed_input.into(), env).unwrap(),
-            sexpr!(sym!("a"), sym!("b"))
-        );
+            let parsed_input = parse("(quote (a b))");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                sexpr!(sym!("a"), sym!("b"))
+            );
 
-        let parsed_input = parse("(list a)");
-        assert_eq!(*eval(parsed_input.into(), env).unwrap(), sexpr!(num!(1)));
+            let parsed_input = parse("(list a)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), sexpr!(num!(1)));
 
-        let parsed_input = parse("(list a b)");
-        assert_eq!(
-            *eval(parsed_input.into(), env).unwrap(),
-            sexpr!(num!(1), num!(2))
-        );
-
-        let parsed_input = parse("(quote (lambda (a b) (add a b)))");
-        assert_eq!(
-            *eval(parsed_input.into(), env).unwrap(),
-            sexpr!(
-                sym!("lambda"),
-                sexpr!(sym!("a"), sym!("b")),
-                sexpr!(sym!("add"), sym!("a"), sym!("b"))
-            )
-        );
+            let parsed_input = parse("(list a b)");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                sexpr!(num!(1), num!(2))
+            );
 
-        let parsed_input = parse("(list (quote (lambda (a b) (add (add a b) b a))) a (quote b))");
-        assert_eq!(
-            *eval(parsed_input.into(), env).unwrap(),
-            sexpr!(
+            let parsed_input = parse("(quote (lambda (a b) (add a b)))");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
                 sexpr!(
                     sym!("lambda"),
                     sexpr!(sym!("a"), sym!("b")),
+                    sexpr!(sym!("add"), sym!("a"), sym!("b"))
+                )
+            );
+
+            let parsed_input =
+                parse("(list (quote (lambda (a b) (add (add a b) b a))) a (quote b))");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                sexpr!(
                     sexpr!(
-                        sym!("add"),
-                        sexpr!(sym!("add"), sym!("a"), sym!("b")),
-                        sym!("b"),
-                        sym!("a")
-                    )
+                        sym!("lambda"),
+                        sexpr!(sym!("a"), sym!("b")),
+                        sexpr!(
+                            sym!("add"),
+                            sexpr!(sym!("add"), sym!("a"), sym!("b")),
+                            sym!("b"),
+                            sym!("a")
+                        )
+                    ),
+                    num!(1),
+                    sym!("b"),
+                )
+            );
+        });
+    }
+
+    #[test]
+    fn test_defun() {
+        with_backends(|env| {
+            let parsed_input = parse("(defun plus_one (n) (add n 1))");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), sym!("plus_one"));
+
+            let parsed_input = parse("(plus_one 41)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(42));
+
+            let parsed_input = parse(
+                r#"
+(defun fib (n)
+  (if (eq n 0)
+      0
+      (if (eq n 1)
+          1
+          (add (fib (sub n 1)) (fib (sub n 2))))))"#,
+            );
+            eval(parsed_input.into(), env).unwrap();
+
+            let parsed_input = parse("(fib 10)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(55));
+
+            let parsed_input = parse("(defun answer () 42)");
+            eval(parsed_input.into(), env).unwrap();
+            let parsed_input = parse("(answer)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(42));
+        });
+    }
+
+    #[test]
+    fn test_defvar_setq() {
+        with_backends(|env| {
+            let parsed_input = parse("(defvar counter 1)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), sym!("counter"));
+
+            // defvar does not overwrite an existing binding, defparameter does
+            let parsed_input = parse("(defvar counter 2)");
+            eval(parsed_input.into(), env).unwrap();
+            assert_eq!(*eval(parse("counter").into(), env).unwrap(), num!(1));
+
+            let parsed_input = parse("(defparameter counter 3)");
+            eval(parsed_input.into(), env).unwrap();
+            assert_eq!(*eval(parse("counter").into(), env).unwrap(), num!(3));
+
+            let parsed_input = parse("(setq counter (add counter 1) other (mul counter 2))");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(8));
+            assert_eq!(*eval(parse("counter").into(), env).unwrap(), num!(4));
+            assert_eq!(*eval(parse("other").into(), env).unwrap(), num!(8));
+
+            // globals defined after a function are visible from its body
+            let parsed_input = parse("(defun get_late () late)");
+            eval(parsed_input.into(), env).unwrap();
+            let parsed_input = parse("(defvar late 7)");
+            eval(parsed_input.into(), env).unwrap();
+            assert_eq!(*eval(parse("(get_late)").into(), env).unwrap(), num!(7));
+        });
+    }
+
+    #[test]
+    fn test_errors() {
+        with_backends(|env| {
+            let err = eval(parse("missing").into(), env).unwrap_err();
+            assert!(matches!(err.error, LispError::UnboundVariable(ref name) if name == "missing"));
+
+            let err = eval(parse("(missing 1)").into(), env).unwrap_err();
+            assert!(matches!(err.error, LispError::UnboundFunction(ref name) if name == "missing"));
+
+            let err = eval(parse("(cons 1)").into(), env).unwrap_err();
+            assert!(matches!(
+                err.error,
+                LispError::Arity { ref name, got: 1, .. } if name == "cons"
+            ));
+
+            let err = eval(parse("(add 1 \"two\")").into(), env).unwrap_err();
+            assert!(matches!(
+                err.error,
+                LispError::Type { expected: "number", ref actual } if **actual == str!("two")
+            ));
+
+            let err = eval(parse("(error \"bad value:\" 42)").into(), env).unwrap_err();
+            assert!(
+                matches!(err.error, LispError::User(ref cond) if cond.message == "bad value: 42")
+            );
+            assert_eq!(
+                err.to_string(),
+                "bad value: 42\nBacktrace:\n  0: (error \"bad value:\" 42)"
+            );
+        });
+    }
+
+    #[test]
+    fn test_backtrace() {
+        with_backends(|env| {
+            eval(parse("(defun inner (x) (add x nothing))").into(), env).unwrap();
+            eval(parse("(defun outer (x) (inner (mul x 2)))").into(), env).unwrap();
+
+            let err = eval(parse("(outer 1)").into(), env).unwrap_err();
+            assert!(matches!(err.error, LispError::UnboundVariable(ref name) if name == "nothing"));
+            assert_eq!(err.backtrace, vec!["(inner 2) at 1:18", "(outer 1)"]);
+            assert_eq!(
+                err.to_string(),
+                "1:25: unbound variable `nothing`\n\
+             Backtrace:\n  0: (inner 2) at 1:18\n  1: (outer 1)"
+            );
+        });
+    }
+
+    #[test]
+    fn test_defmacro() {
+        with_backends(|env| {
+            let parsed_input =
+                parse("(defmacro my_when (test body) (list (quote if) test body nil))");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), sym!("my_when"));
+
+            let parsed_input = parse("(my_when (eq 1 1) (add 2 3))");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(5));
+
+            let parsed_input = parse("(my_when (eq 1 2) (undefined_fn))");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), nil!());
+
+            // macros can expand into other macro calls
+            let parsed_input = parse(
+            "(defmacro my_unless (test body) (list (quote my_when) (list (quote eq) test nil) body))",
+        );
+            eval(parsed_input.into(), env).unwrap();
+            let parsed_input = parse("(my_unless (eq 1 2) 7)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(7));
+
+            let parsed_input = parse("(macroexpand-1 (quote (my_unless a b)))");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                sexpr!(
+                    sym!("my_when"),
+                    sexpr!(sym!("eq"), sym!("a"), nil!()),
+                    sym!("b")
+                )
+            );
+
+            let parsed_input = parse("(macroexpand (quote (my_unless a b)))");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                sexpr!(
+                    sym!("if"),
+                    sexpr!(sym!("eq"), sym!("a"), nil!()),
+                    sym!("b"),
+                    nil!()
+                )
+            );
+
+            // non-macro forms are returned unchanged
+            let parsed_input = parse("(macroexpand (quote (add 1 2)))");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                sexpr!(sym!("add"), num!(1), num!(2))
+            );
+
+            // defun replaces a macro of the same name
+            let parsed_input = parse("(defun my_when (a b) (add a b))");
+            eval(parsed_input.into(), env).unwrap();
+            let parsed_input = parse("(my_when 1 2)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(3));
+        });
+    }
+
+    #[test]
+    fn test_let_progn() {
+        with_backends(|env| {
+            env.val.insert("x".into(), num!(10).into());
+
+            let parsed_input = parse("(progn (defvar p 1) (setq p (add p 1)) p)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(2));
+            assert_eq!(*eval(parse("(progn)").into(), env).unwrap(), nil!());
+
+            // let inits see the outer `x`, let* inits the earlier bindings
+            let parsed_input = parse("(let ((x 1) (y x)) (list x y))");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                sexpr!(num!(1), num!(10))
+            );
+            let parsed_input = parse("(let* ((x 1) (y x) z) (list x y z))");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                sexpr!(num!(1), num!(1), nil!())
+            );
+            assert_eq!(*eval(parse("x").into(), env).unwrap(), num!(10));
+
+            // lambda and defun bodies may hold several forms
+            let parsed_input = parse("(funcall (lambda (a) (setq a (add a 1)) (mul a 2)) 1)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(4));
+            let parsed_input = parse("(defun twice_plus (a) (setq a (add a 1)) (mul a 2))");
+            eval(parsed_input.into(), env).unwrap();
+            assert_eq!(*eval(parse("(twice_plus 2)").into(), env).unwrap(), num!(6));
+
+            let parsed_input = parse(
+                r#"
+(letrec ((even_p (lambda (n) (if (eq n 0) t (funcall odd_p (sub n 1)))))
+         (odd_p (lambda (n) (if (eq n 0) nil (funcall even_p (sub n 1))))))
+  (list (funcall even_p 10) (funcall odd_p 7) (funcall even_p 3)))"#,
+            );
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                sexpr!(t!(), t!(), nil!())
+            );
+
+            // let variables are only in scope in the body, and each call
+            // gets its own bindings
+            let parsed_input =
+                parse("(defun shadow (x) (list (let ((x (add x 1))) (lambda () x)) x))");
+            eval(parsed_input.into(), env).unwrap();
+            eval(parse("(defvar s1 (shadow 1))").into(), env).unwrap();
+            eval(parse("(defvar s2 (shadow 5))").into(), env).unwrap();
+            let parsed_input = parse("(list (funcall (car s1)) (car (cdr s1)) (funcall (car s2)))");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                sexpr!(num!(2), num!(1), num!(6))
+            );
+            // and seen by the forms around them
+            let parsed_input =
+                parse("(let ((y 2)) (dotimes (i 3) (setq y (mul y 2))) (let (z) (list y z)))");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                sexpr!(num!(16), nil!())
+            );
+
+            let err = eval(parse("(let ((1 2)) 3)").into(), env).unwrap_err();
+            assert!(matches!(err.error, LispError::Syntax { form: "let", .. }));
+        });
+    }
+
+    #[test]
+    fn test_shared_closure_state() {
+        with_backends(|env| {
+            // closures over the same binding see each other's assignments
+            let parsed_input =
+                parse("(defun make_acc (n) (list (lambda (x) (setq n (add n x))) (lambda () n)))");
+            eval(parsed_input.into(), env).unwrap();
+            eval(parse("(defvar acc (make_acc 10))").into(), env).unwrap();
+            eval(parse("(funcall (car acc) 5)").into(), env).unwrap();
+            eval(parse("(funcall (car acc) 7)").into(), env).unwrap();
+            let parsed_input = parse("(funcall (car (cdr acc)))");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(22));
+
+            // each call gets its own bindings
+            eval(parse("(defvar other (make_acc 0))").into(), env).unwrap();
+            let parsed_input = parse("(funcall (car (cdr other)))");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(0));
+
+            // assignments made by a closure are visible in the enclosing scope
+            let parsed_input =
+                parse("(let ((count 0)) (funcall (lambda () (setq count (add count 1)))) count)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(1));
+            let parsed_input = parse(
+                "(defun bump_twice (n) (let ((f (lambda () (setq n (add n 1))))) (funcall f) (funcall f) n))",
+            );
+            eval(parsed_input.into(), env).unwrap();
+            assert_eq!(*eval(parse("(bump_twice 1)").into(), env).unwrap(), num!(3));
+
+            // a counter closing over a let binding
+            let parsed_input =
+                parse("(defvar counter (let ((n 0)) (lambda () (setq n (add n 1)))))");
+            eval(parsed_input.into(), env).unwrap();
+            eval(parse("(funcall counter)").into(), env).unwrap();
+            let parsed_input = parse("(funcall counter)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(2));
+        });
+    }
+
+    #[test]
+    fn test_function_namespace() {
+        with_backends(|env| {
+            // builtins are values in the function namespace
+            let parsed_input = parse("(apply #'add 1 2)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(3));
+            let parsed_input = parse("(funcall (function mul) 3 4)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(12));
+            let parsed_input = parse("(funcall (quote sub) 3 4)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(-1));
+            let parsed_input = parse("(funcall #'(lambda (x) (add x 1)) 1)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(2));
+
+            // fset installs a function value under a name
+            let parsed_input = parse("(fset (quote inc) (lambda (x) (add x 1)))");
+            eval(parsed_input.into(), env).unwrap();
+            assert_eq!(*eval(parse("(inc 41)").into(), env).unwrap(), num!(42));
+            let parsed_input = parse("(fset (quote plus) (symbol-function (quote add)))");
+            eval(parsed_input.into(), env).unwrap();
+            assert_eq!(*eval(parse("(plus 1 2 3)").into(), env).unwrap(), num!(6));
+            let parsed_input = parse("(eq (symbol-function (quote plus)) #'add)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), t!());
+
+            let parsed_input = parse("(list (fboundp 'inc) (fboundp 'nope) (fboundp 'if))");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                sexpr!(t!(), nil!(), t!())
+            );
+            eval(parse("(fmakunbound 'inc)").into(), env).unwrap();
+            assert_eq!(*eval(parse("(fboundp 'inc)").into(), env).unwrap(), nil!());
+            let err = eval(parse("(inc 1)").into(), env).unwrap_err();
+            assert!(matches!(err.error, LispError::UnboundFunction(ref name) if name == "inc"));
+
+            eval(parse("(defvar bound_var 1)").into(), env).unwrap();
+            let parsed_input = parse("(list (boundp 'bound_var) (boundp 'unbound_var))");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                sexpr!(t!(), nil!())
+            );
+            let parsed_input = parse("(let ((x 1)) (list (boundp 'x) (boundp 'nil) (boundp :k)))");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                sexpr!(nil!(), t!(), t!())
+            );
+
+            // a symbol designates the global function, never a local one
+            eval(parse("(defun h () 'global)").into(), env).unwrap();
+            for src in [
+                "(flet ((h () 'local)) (funcall 'h))",
+                "(flet ((h () 'local)) (apply 'h))",
+                "(flet ((h () 'local)) (funcall (symbol-function 'h)))",
+            ] {
+                assert_eq!(
+                    *eval(parse(src).into(), env).unwrap(),
+                    sym!("global"),
+                    "{src}"
+                );
+            }
+            let parsed_input = parse("(flet ((h () 'local)) (list (h) (funcall #'h)))");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                sexpr!(sym!("local"), sym!("local"))
+            );
+
+            let err = eval(parse("#'nope").into(), env).unwrap_err();
+            assert!(matches!(err.error, LispError::UnboundFunction(ref name) if name == "nope"));
+        });
+    }
+
+    #[test]
+    fn test_flet_labels() {
+        with_backends(|env| {
+            // local functions shadow global ones, only inside the body
+            let parsed_input = parse("(flet ((add (a b) (mul a b))) (add 3 4))");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(12));
+            assert_eq!(*eval(parse("(add 3 4)").into(), env).unwrap(), num!(7));
+
+            // flet functions call the outer definition of their own name
+            let parsed_input = parse("(flet ((add (a b) (add a (add b 100)))) (add 1 2))");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(103));
+
+            let parsed_input = parse(
+                r#"
+(labels ((fact (n) (if (eq n 0) 1 (mul n (fact (sub n 1)))))
+         (is_even (n) (if (eq n 0) t (is_odd (sub n 1))))
+         (is_odd (n) (if (eq n 0) nil (is_even (sub n 1)))))
+  (list (fact 5) (is_even 10) (is_odd 10) #'fact))"#,
+            );
+            let res = eval(parsed_input.into(), env).unwrap();
+            let Atom::Cons(res) = &*res else {
+                panic!("expected a list")
+            };
+            let res: Vec<_> = res.iter().collect();
+            assert_eq!(*res[0], num!(120));
+            assert_eq!(*res[1], t!());
+            assert_eq!(*res[2], nil!());
+            assert!(matches!(*res[3], Atom::Fun(_)));
+
+            // closures keep the local functions in scope after the form exits
+            let parsed_input =
+                parse("(defvar shout (flet ((twice (x) (mul x 2))) (lambda (x) (twice x))))");
+            eval(parsed_input.into(), env).unwrap();
+            assert_eq!(
+                *eval(parse("(funcall shout 21)").into(), env).unwrap(),
+                num!(42)
+            );
+
+            let err = eval(parse("(twice 1)").into(), env).unwrap_err();
+            assert!(matches!(err.error, LispError::UnboundFunction(ref name) if name == "twice"));
+
+            // local functions shadow macros too
+            eval(parse("(defmacro twice (x) x)").into(), env).unwrap();
+            let parsed_input = parse("(flet ((twice (x) (mul x 2))) (twice 5))");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(10));
+
+            // and special forms, which the VM leaves to the interpreter then
+            let parsed_input = parse("(flet ((if (x) x)) (if 1))");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(1));
+
+            // calls between local functions are tail calls, and loops calling
+            // them don't compile anything again
+            let parsed_input = parse(
+                "(labels ((ev (n) (if (eq n 0) t (od (sub n 1)))) (od (n) (if (eq n 0) nil (ev (sub n 1))))) (ev 100001))",
+            );
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), nil!());
+            let parsed_input =
+                parse("(flet ((inc (x) (add x 1))) (let ((n 0)) (dotimes (i 100000 n) (setq n (inc n)))))");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(100000));
+        });
+    }
+
+    #[test]
+    fn test_lambda_lists() {
+        with_backends(|env| {
+            let parsed_input = parse("((lambda (a &optional (b a)) (list a b)) 1)");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                sexpr!(num!(1), num!(1))
+            );
+
+            let parsed_input = parse("(defun area (&key (w 1) (h 1)) (mul w h))");
+            eval(parsed_input.into(), env).unwrap();
+            let parsed_input = parse("(area :h 3 :w 2)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(6));
+
+            // arity errors name the function, compiled or not
+            let parsed_input = parse("(defun pair (a b) (list a b))");
+            eval(parsed_input.into(), env).unwrap();
+            let err = eval(parse("(pair 1)").into(), env).unwrap_err();
+            assert!(matches!(
+                err.error,
+                LispError::Arity { ref name, got: 1, .. } if name == "pair"
+            ));
+        });
+    }
+
+    #[test]
+    fn test_special_variables() {
+        with_backends(|env| {
+            for src in [
+                "(defvar *depth* 1)",
+                "(defvar *unset*)",
+                "(defun depth () *depth*)",
+            ] {
+                eval(parse(src).into(), env).unwrap();
+            }
+
+            // callees see the dynamic binding, which is gone after the let
+            let parsed_input =
+                parse("(let ((*depth* 2)) (list (depth) (let* ((*depth* 3) (d (depth))) d)))");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                sexpr!(num!(2), num!(3))
+            );
+            assert_eq!(*eval(parse("(depth)").into(), env).unwrap(), num!(1));
+
+            // setq inside the let assigns the dynamic binding only
+            let parsed_input = parse("(let ((*depth* 5)) (setq *depth* 6) (depth))");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(6));
+            assert_eq!(*eval(parse("*depth*").into(), env).unwrap(), num!(1));
+
+            // bindings are restored when an error unwinds through the let
+            let parsed_input =
+                parse(r#"(ignore-errors (let ((*depth* 7) (*unset* 1)) (error "boom")))"#);
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), nil!());
+            assert_eq!(*eval(parse("(depth)").into(), env).unwrap(), num!(1));
+            assert_eq!(
+                *eval(parse("(boundp '*unset*)").into(), env).unwrap(),
+                nil!()
+            );
+
+            // whether a variable is special is decided when it is bound, so
+            // it may be declared after the functions binding it are defined
+            for src in [
+                "(defun read-y () *y*)",
+                "(defun bind-y () (let ((*y* 1)) (read-y)))",
+                "(defun make-reader () (lambda (*y*) (read-y)))",
+                "(defvar *y* 0)",
+            ] {
+                eval(parse(src).into(), env).unwrap();
+            }
+            assert_eq!(*eval(parse("(bind-y)").into(), env).unwrap(), num!(1));
+            let parsed_input = parse("(funcall (make-reader) 2)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(2));
+            assert_eq!(*eval(parse("*y*").into(), env).unwrap(), num!(0));
+
+            // so do special params, also &optional and &key ones
+            for src in [
+                "(defun with-depth (*depth*) (depth))",
+                "(defun with-opt (&optional (*depth* 8)) (depth))",
+                "(defun with-key (&key ((:d *depth*) 9)) (depth))",
+                "(defun with-error (*depth*) (error \"boom\"))",
+            ] {
+                eval(parse(src).into(), env).unwrap();
+            }
+            let parsed_input = parse("(list (with-depth 5) (with-opt) (with-key :d 10) (depth))");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                sexpr!(num!(5), num!(8), num!(10), num!(1))
+            );
+            let parsed_input = parse("(ignore-errors (with-error 5))");
+            eval(parsed_input.into(), env).unwrap();
+            assert_eq!(*eval(parse("(depth)").into(), env).unwrap(), num!(1));
+
+            // a let of other variables stays lexical, invisible to callees
+            let parsed_input = parse("(defun get-x () x)");
+            eval(parsed_input.into(), env).unwrap();
+            let parsed_input = parse("(let ((x 1)) (get-x))");
+            assert!(eval(parsed_input.into(), env).is_err());
+        });
+    }
+
+    #[test]
+    fn test_non_local_exits() {
+        with_backends(|env| {
+            // exits unwind through compiled and interpreted calls alike
+            let parsed_input = parse("(defun call-with (f x) (funcall f x))");
+            eval(parsed_input.into(), env).unwrap();
+            let parsed_input =
+                parse("(block out (call-with (lambda (x) (return-from out (add x 1))) 1) 0)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(2));
+
+            let parsed_input = parse("(catch 'done (call-with (lambda (x) (throw 'done x)) 5) 0)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(5));
+
+            let err = eval(parse("(throw 'done 1)").into(), env).unwrap_err();
+            assert_eq!(err.error.kind_name(), "control-error");
+        });
+    }
+
+    #[test]
+    fn test_conditionals() {
+        with_backends(|env| {
+            let cases = [
+                ("(if nil 1)", nil!()),
+                ("(if t 1)", num!(1)),
+                ("(when t 1 2)", num!(2)),
+                ("(when nil 1 2)", nil!()),
+                ("(unless nil 1 2)", num!(2)),
+                ("(unless t 1)", nil!()),
+                ("(cond ((eq 1 2) 'a) ((eq 1 1) 'b 'c) (t 'd))", sym!("c")),
+                ("(cond ((eq 1 2) 'a) ((car (list 5))))", num!(5)),
+                ("(cond ((eq 1 2) 'a))", nil!()),
+                ("(and 1 2 3)", num!(3)),
+                ("(and 1 nil 3)", nil!()),
+                ("(and)", t!()),
+                ("(or nil 2 3)", num!(2)),
+                ("(or nil nil)", nil!()),
+                ("(or)", nil!()),
+                ("(case 2 (1 'one) ((2 3) 'few) (t 'many))", sym!("few")),
+                ("(case 'x ((a b) 1) (otherwise 2))", num!(2)),
+                ("(case 'z ((a b) 1))", nil!()),
+                ("(ecase 'b (a 1) (b 2))", num!(2)),
+                ("(ecase t (a 1) (t 2))", num!(2)),
+                ("(case nil (nil 1) ((nil) 2))", num!(2)),
+                (
+                    "(list (case 1 (1)) (case 1 (2 3)) 4)",
+                    sexpr!(nil!(), nil!(), num!(4)),
                 ),
-                num!(1),
-                sym!("b"),
+            ];
+            for (src, expected) in cases {
+                assert_eq!(*eval(parse(src).into(), env).unwrap(), expected, "{src}");
+            }
+
+            // and/or short-circuit
+            eval(parse("(defvar hits 0)").into(), env).unwrap();
+            let parsed_input = parse("(list (or 1 (setq hits 1)) (and nil (setq hits 2)) hits)");
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                sexpr!(num!(1), nil!(), num!(0))
+            );
+
+            // branches in tail position stay tail calls
+            let parsed_input =
+                parse("(defun down (n) (cond ((eq n 0) 'done) (t (when t (down (sub n 1))))))");
+            eval(parsed_input.into(), env).unwrap();
+            let parsed_input = parse("(down 100000)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), sym!("done"));
+            let parsed_input = parse("(defun tc (n) (case n (0 'done) (t (tc (- n 1)))))");
+            eval(parsed_input.into(), env).unwrap();
+            assert_eq!(
+                *eval(parse("(tc 100000)").into(), env).unwrap(),
+                sym!("done")
+            );
+
+            let err = eval(parse("(ecase 'c (a 1) (b 2))").into(), env).unwrap_err();
+            assert_eq!(err.error.kind_name(), "type-error");
+        });
+    }
+
+    #[test]
+    fn test_iteration() {
+        with_backends(|env| {
+            // loops assign the params of compiled functions too
+            let parsed_input = parse(
+                "(defun sum-to (n) (let ((acc 0)) (dotimes (i n acc) (setq acc (add acc i)))))",
+            );
+            eval(parsed_input.into(), env).unwrap();
+            assert_eq!(*eval(parse("(sum-to 5)").into(), env).unwrap(), num!(10));
+
+            let parsed_input =
+                parse("(defun count-down (n) (while (eq (eq n 0) nil) (setq n (sub n 1))) n)");
+            eval(parsed_input.into(), env).unwrap();
+            assert_eq!(*eval(parse("(count-down 7)").into(), env).unwrap(), num!(0));
+
+            let parsed_input =
+                parse("(block b (dolist (x (list 1 2 3)) (when (eq x 2) (return-from b x))))");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(2));
+        });
+    }
+
+    #[test]
+    fn test_function_block() {
+        with_backends(|env| {
+            eval(parse("(defun f2 (x) (return-from f2 x) 0)").into(), env).unwrap();
+            assert_eq!(*eval(parse("(f2 3)").into(), env).unwrap(), num!(3));
+
+            let parsed_input = parse(
+                "(defun first-neg (xs) (dolist (x xs) (when (< x 0) (return-from first-neg x))) 'none)",
+            );
+            eval(parsed_input.into(), env).unwrap();
+            assert_eq!(
+                *eval(parse("(first-neg (list 1 -2 -3))").into(), env).unwrap(),
+                num!(-2)
+            );
+            assert_eq!(
+                *eval(parse("(first-neg (list 1 2))").into(), env).unwrap(),
+                sym!("none")
+            );
+
+            let parsed_input = parse("(flet ((g (x) (return-from g (* x 2)) 0)) (g 4))");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(8));
+
+            // functions keep their tail calls, with a return-from or without
+            eval(
+                parse("(defun down (n) (if (eq n 0) 'done (down (- n 1))))").into(),
+                env,
             )
-        );
+            .unwrap();
+            assert_eq!(
+                *eval(parse("(down 100000)").into(), env).unwrap(),
+                sym!("done")
+            );
+            let parsed_input = parse(
+                "(defun down2 (n) (when (< n 0) (return-from down2 'neg)) (if (eq n 0) 'ok (down2 (- n 1))))",
+            );
+            eval(parsed_input.into(), env).unwrap();
+            assert_eq!(
+                *eval(parse("(down2 100000)").into(), env).unwrap(),
+                sym!("ok")
+            );
+            assert_eq!(*eval(parse("(down2 -1)").into(), env).unwrap(), sym!("neg"));
+        });
+    }
+
+    #[test]
+    fn test_extent_tail_calls() {
+        with_backends(|env| {
+            // the last form of a block, catch or unwind-protect body is in
+            // tail position, and the extents are still left in order
+            for src in [
+                "(defvar cleaned 0)",
+                "(defun in-block (n) (block b (if (eq n 0) 'ok (in-block (- n 1)))))",
+                "(defun in-catch (n) (catch 'out (if (eq n 0) (throw 'out 'thrown) (in-catch (- n 1)))))",
+                "(defun in-cleanup (n) (unwind-protect (if (eq n 0) 'ok (in-cleanup (- n 1))) (setq cleaned (add cleaned 1))))",
+                "(defun deep (n) (if (eq n 0) (throw 'out 'bottom) (deep (- n 1))))",
+            ] {
+                eval(parse(src).into(), env).unwrap();
+            }
+            for (src, expected) in [
+                ("(in-block 20000)", sym!("ok")),
+                ("(in-catch 20000)", sym!("thrown")),
+                ("(catch 'out (deep 20000))", sym!("bottom")),
+                ("(in-cleanup 20000)", sym!("ok")),
+                ("cleaned", num!(20001)),
+            ] {
+                assert_eq!(*eval(parse(src).into(), env).unwrap(), expected, "{src}");
+            }
+
+            // an exit to a block reaches it through the calls made in it
+            let parsed_input =
+                parse("(defun each (f xs) (when xs (funcall f (car xs)) (each f (cdr xs))))");
+            eval(parsed_input.into(), env).unwrap();
+            let parsed_input = parse(
+                "(list (block b (each (lambda (x) (when (eq x 3) (return-from b x))) (list 1 2 3 4))) 'after)",
+            );
+            assert_eq!(
+                *eval(parsed_input.into(), env).unwrap(),
+                sexpr!(num!(3), sym!("after"))
+            );
+
+            // cleanups run when an error unwinds through them
+            let parsed_input = parse("(ignore-errors (in-cleanup-error 3))");
+            eval(
+                parse("(defun in-cleanup-error (n) (unwind-protect (if (eq n 0) (error \"boom\") (in-cleanup-error (- n 1))) (setq cleaned (add cleaned 1))))").into(),
+                env,
+            )
+            .unwrap();
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), nil!());
+            assert_eq!(*eval(parse("cleaned").into(), env).unwrap(), num!(20005));
+        });
+    }
+
+    #[test]
+    fn test_compiled_loops() {
+        with_backends(|env| {
+            // the VM runs the loops as jumps, without compiling their bodies
+            // again each iteration
+            for (src, expected) in [
+                (
+                    "(let ((sum 0)) (dotimes (i 100000 sum) (setq sum (add sum i))))",
+                    num!(4999950000),
+                ),
+                ("(let ((n 0)) (while (< n 100000) (setq n (add n 1))) n)", num!(100000)),
+                (
+                    "(do ((i 0 (add i 1)) (acc 0 (add acc i))) ((eq i 100000) acc))",
+                    num!(4999950000),
+                ),
+                ("(loop for i from 1 to 20000 sum i)", num!(200010000)),
+                ("(dotimes (i -3 i))", num!(0)),
+                // exits unwind the stack of the expression they are in
+                ("(block b (add 1 (return-from b 5)))", num!(5)),
+                ("(let ((k 0)) (tagbody a (setq k (add k 1)) (when (< k 5) (go a))) k)", num!(5)),
+                // and reach the block or tagbody from closures
+                (
+                    "(let ((k 0)) (tagbody a (setq k (add k 1)) (funcall (lambda () (when (< k 5) (go a))))) k)",
+                    num!(5),
+                ),
+                (
+                    "(dolist (x (list 1 2 3 4)) (funcall (lambda () (when (eq x 3) (return x)))))",
+                    num!(3),
+                ),
+                (
+                    "(block nil (handler-case (return 7) (error (e) 0)))",
+                    num!(7),
+                ),
+            ] {
+                assert_eq!(*eval(parse(src).into(), env).unwrap(), expected, "{src}");
+            }
+
+            let parsed_input = parse("(funcall (block b (lambda () (return-from b 1))))");
+            let err = eval(parsed_input.into(), env).unwrap_err();
+            assert_eq!(
+                err.error.to_string(),
+                "return-from: block b has already exited"
+            );
+            let err = eval(parse("(dolist (x 'x) x)").into(), env).unwrap_err();
+            assert_eq!(err.error.kind_name(), "type-error");
+        });
+    }
+
+    #[test]
+    fn test_loop_macro() {
+        with_backends(|env| {
+            let parsed_input = parse(
+                "(defun squares-but (n xs) (loop for x in xs unless (eq x n) collect (mul x x)))",
+            );
+            eval(parsed_input.into(), env).unwrap();
+            let res = eval(parse("(squares-but 3 (list 1 2 3 4))").into(), env).unwrap();
+            assert_eq!(*res, sexpr!(num!(1), num!(4), num!(16)));
+
+            let parsed_input = parse(
+                "(defun find-index (y xs) (loop for x in xs for i from 0 when (eq x y) return i))",
+            );
+            eval(parsed_input.into(), env).unwrap();
+            let res = eval(parse("(find-index 'c (list 'a 'b 'c))").into(), env).unwrap();
+            assert_eq!(*res, num!(2));
+            let res = eval(parse("(find-index 'z (list 'a 'b 'c))").into(), env).unwrap();
+            assert_eq!(*res, nil!());
+        });
+    }
+
+    /// The evaluator as it was before chained frames, cut down to the forms
+    /// the Fibonacci example uses: a closure keeps a copy of the `HashMap`
+    /// environment it was made in, and each call copies that again to bind
+    /// its params.
+    mod cloned_env {
+        use std::{collections::HashMap, sync::Arc};
+
+        use crate::atom::{Atom, SAtom};
+
+        #[derive(Clone)]
+        pub enum Value {
+            Num(i64),
+            Fun(Arc<(Vec<String>, SAtom, Env)>),
+        }
+
+        pub type Env = HashMap<String, Value>;
+
+        fn items(form: &Atom) -> Vec<SAtom> {
+            match form {
+                Atom::Cons(list) => list.iter().collect(),
+                _ => vec![],
+            }
+        }
+
+        fn name(form: &Atom) -> String {
+            match form {
+                Atom::Sym(name) => name.clone(),
+                _ => panic!("expected a symbol"),
+            }
+        }
+
+        pub fn eval(form: &Atom, env: &Env) -> Value {
+            let list = match form {
+                Atom::Int(n) => return Value::Num(*n),
+                Atom::Sym(name) => return env[name].clone(),
+                form => items(form),
+            };
+            let num = |form: &SAtom| match eval(form, env) {
+                Value::Num(n) => n,
+                Value::Fun(_) => panic!("expected a number"),
+            };
+            match &*list[0] {
+                Atom::Sym(op) if op == "lambda" => {
+                    let params = items(&list[1]).iter().map(|p| name(p)).collect();
+                    Value::Fun(Arc::new((params, list[2].clone(), env.clone())))
+                }
+                Atom::Sym(op) if op == "let" => {
+                    let mut inner = env.clone();
+                    for binding in items(&list[1]) {
+                        let binding = items(&binding);
+                        inner.insert(name(&binding[0]), eval(&binding[1], env));
+                    }
+                    eval(&list[2], &inner)
+                }
+                Atom::Sym(op) if op == "if" => match num(&list[1]) {
+                    0 => eval(&list[3], env),
+                    _ => eval(&list[2], env),
+                },
+                Atom::Sym(op) if op == "eq" => Value::Num((num(&list[1]) == num(&list[2])) as i64),
+                Atom::Sym(op) if op == "add" => Value::Num(num(&list[1]) + num(&list[2])),
+                Atom::Sym(op) if op == "sub" => Value::Num(num(&list[1]) - num(&list[2])),
+                Atom::Sym(op) if op == "apply" => call(eval(&list[1], env), &list[2..], env),
+                _ => call(eval(&list[0], env), &list[1..], env),
+            }
+        }
+
+        fn call(fun: Value, args: &[SAtom], env: &Env) -> Value {
+            let Value::Fun(fun) = fun else {
+                panic!("expected a function")
+            };
+            let (params, body, captured) = &*fun;
+            let mut call_env = captured.clone();
+            for (param, arg) in params.iter().zip(args) {
+                call_env.insert(param.clone(), eval(arg, env));
+            }
+            eval(body, &call_env)
+        }
+    }
+
+    /// Time the README's Fibonacci example on both backends, against the
+    /// evaluator that copied its environment on every call:
+    /// `cargo test --release -- --ignored --nocapture bench_fib`
+    #[test]
+    #[ignore]
+    fn bench_fib() {
+        let fib = r#"
+((lambda (n)
+   ((lambda (FIB) (apply FIB FIB n))
+    (lambda (FIB n)
+      (if (eq n 0)
+          0
+          (if (eq n 1)
+              1
+              (add (apply FIB FIB (sub n 1))
+                   (apply FIB FIB (sub n 2))))))))
+ 20)"#;
+        // the same, with 100 more variables in the scope the lambdas close over
+        let vars: String = (0..100).map(|i| format!("(v{i} {i}) ")).collect();
+        let nested = format!("(let ({vars}) {fib})");
+
+        let average = |run: &mut dyn FnMut()| {
+            let start = std::time::Instant::now();
+            for _ in 0..5 {
+                run();
+            }
+            start.elapsed() / 5
+        };
+        for (name, src) in [("fib 20", fib), ("fib 20 in a 100-variable let", &nested)] {
+            let form: SAtom = parse(src).into();
+            let time = average(&mut || {
+                let res = cloned_env::eval(&form, &HashMap::new());
+                assert!(matches!(res, cloned_env::Value::Num(6765)));
+            });
+            println!("{name}, cloned HashMap environment: {time:?}");
+            for backend in [Backend::Interpreter, Backend::Vm] {
+                let env = &mut Env {
+                    backend,
+                    ..Default::default()
+                };
+                let time = average(&mut || {
+                    assert_eq!(*eval(form.clone(), env).unwrap(), num!(6765));
+                });
+                println!("{name}, {backend:?}: {time:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        with_backends(|env| {
+            let parsed_input =
+                parse("(defun count (n acc) (if (eq n 0) acc (count (sub n 1) (add acc 1))))");
+            eval(parsed_input.into(), env).unwrap();
+            let parsed_input = parse("(count 100000 0)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(100000));
+
+            // mutual recursion and calls through funcall are tail calls too
+            let parsed_input =
+                parse("(defun ping (n) (if (eq n 0) (quote ping) (pong (sub n 1))))");
+            eval(parsed_input.into(), env).unwrap();
+            let parsed_input =
+                parse("(defun pong (n) (if (eq n 0) (quote pong) (funcall ping_fn (sub n 1))))");
+            eval(parsed_input.into(), env).unwrap();
+            let parsed_input = parse("(defvar ping_fn (lambda (n) (ping n)))");
+            eval(parsed_input.into(), env).unwrap();
+            let parsed_input = parse("(ping 100001)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), sym!("pong"));
+
+            // so are the bodies of forms the compiler leaves to the interpreter
+            let parsed_input = parse(
+                "(defun loopf (n) (flet ((g (m) m)) (if (eq n 0) 'ok (loopf (g (sub n 1))))))",
+            );
+            eval(parsed_input.into(), env).unwrap();
+            assert_eq!(
+                *eval(parse("(loopf 100000)").into(), env).unwrap(),
+                sym!("ok")
+            );
+
+            // the body of a let is in tail position
+            let parsed_input =
+                parse("(defun loopl (n) (let ((m (- n 1))) (if (< m 0) 'ok (loopl m))))");
+            eval(parsed_input.into(), env).unwrap();
+            assert_eq!(
+                *eval(parse("(loopl 100000)").into(), env).unwrap(),
+                sym!("ok")
+            );
+            let parsed_input = parse(
+                "(defun loopr (n) (letrec ((m (- n 1))) (let* ((k m)) (if (< k 0) 'ok (loopr k)))))",
+            );
+            eval(parsed_input.into(), env).unwrap();
+            assert_eq!(
+                *eval(parse("(loopr 100000)").into(), env).unwrap(),
+                sym!("ok")
+            );
+
+            // the caller's bindings are back in place after the calls return
+            env.val.insert("n".into(), num!(7).into());
+            let parsed_input = parse("(add (count 3 0) n)");
+            assert_eq!(*eval(parsed_input.into(), env).unwrap(), num!(10));
+        });
     }
 }
@@ -1,82 +1,362 @@
 use crate::{
     atom::{Atom, SAtom},
+    number::Number,
     sexpr::SExpr,
+    span::{self, Span},
 };
 
+use std::{fmt, sync::Arc};
+
 use nom::{
     self,
     branch::alt,
-    bytes::complete::{is_not, tag},
-    character::complete::{alpha1, alphanumeric1, char, multispace0},
-    error::ParseError,
-    multi::{fold_many0, many0},
+    bytes::complete::{tag, take_till, take_while1},
+    character::complete::{char, digit1, multispace1, not_line_ending, one_of, satisfy},
+    combinator::{consumed, cut, not, opt, recognize, value, verify},
+    error::{ErrorKind, ParseError as NomParseError},
+    multi::many0,
     number::complete::double,
-    sequence::delimited,
+    sequence::{preceded, terminated},
     IResult, Parser,
 };
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::Zero;
 
-fn ws<'a, O, E, F>(inner: F) -> impl Parser<&'a str, Output = O, Error = E>
+/// Skip whitespace and `;` line comments.
+fn blank<'a, E>(input: &'a str) -> IResult<&'a str, (), E>
 where
-    E: ParseError<&'a str>,
-    F: Parser<&'a str, Output = O, Error = E>,
+    E: NomParseError<&'a str>,
 {
-    delimited(multispace0, inner, multispace0)
+    value(
+        (),
+        many0(alt((multispace1, preceded(char(';'), not_line_ending)))),
+    )
+    .parse(input)
+}
+
+/// Characters allowed in symbols besides alphanumerics.
+const SYMBOL_CHARS: &str = "_-+*/<>=!?&%$^~.:";
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_alphanumeric() || SYMBOL_CHARS.contains(c)
+}
+
+/// Digits with an optional sign, as in integers and ratios.
+fn signed_digits(input: &str) -> IResult<&str, &str> {
+    recognize((opt(one_of("+-")), digit1)).parse(input)
 }
 
 fn parse_num(input: &str) -> IResult<&str, Atom> {
-    let res: (&str, f64) = double(input)?;
-    Ok((res.0, Atom::Num(res.1)))
+    // Not the prefix of a symbol such as `1+`.
+    let end = || not(satisfy(is_symbol_char));
+
+    // an integer or a ratio such as `1/3`
+    let exact = terminated((signed_digits, opt(preceded(char('/'), digit1))), end()).parse(input);
+    if let Ok((rest, (numer, denom))) = exact {
+        let numer: BigInt = numer.parse().unwrap();
+        let number = match denom {
+            None => Number::Big(numer),
+            Some(denom) => {
+                let denom: BigInt = denom.parse().unwrap();
+                if denom.is_zero() {
+                    // `1/0` is a number that doesn't exist, not a symbol
+                    return Err(nom::Err::Failure(nom::error::Error::new(
+                        input,
+                        ErrorKind::Verify,
+                    )));
+                }
+                Number::Ratio(BigRational::new(numer, denom))
+            }
+        };
+        return Ok((rest, number.into_atom()));
+    }
+
+    // a float, in plain decimal notation only: no `inf`/`nan`
+    let res = terminated(
+        verify(consumed(double), |(text, _): &(&str, f64)| {
+            text.chars()
+                .all(|c| c.is_ascii_digit() || "+-.eE".contains(c))
+        }),
+        end(),
+    )
+    .parse(input)?;
+    Ok((res.0, Atom::Num(res.1 .1)))
+}
+
+/// The number `text` reads as, with nothing around it, or `None`.
+pub fn parse_number(text: &str) -> Option<Atom> {
+    match parse_num(text) {
+        Ok(("", n)) => Some(n),
+        _ => None,
+    }
 }
 
 fn parse_str(input: &str) -> IResult<&str, Atom> {
-    let res = delimited(char('"'), is_not("\""), char('"')).parse(input)?;
+    let res = preceded(
+        char('"'),
+        cut(terminated(take_till(|c| c == '"'), char('"'))),
+    )
+    .parse(input)?;
     Ok((res.0, Atom::Str(res.1.to_string())))
 }
 
 fn parse_sym(input: &str) -> IResult<&str, Atom> {
-    let res = (
-        alpha1,
-        fold_many0(
-            alt((alphanumeric1, tag("_"))),
-            String::default,
-            |mut acc: String, item| {
-                acc += item;
-                acc
-            },
-        ),
-    )
-        .parse(input)?;
-    Ok((res.0, Atom::Sym(res.1 .0.to_string() + &res.1 .1)))
+    let res = take_while1(is_symbol_char).parse(input)?;
+    Ok((res.0, Atom::Sym(res.1.to_string())))
+}
+
+/// Source being read, used to turn parser positions into spans.
+struct Reader<'a> {
+    src: &'a str,
+    file: Option<Arc<str>>,
+    /// Byte offset of the beginning of each line.
+    line_starts: Vec<usize>,
 }
 
-fn parse_sexp(input: &str) -> IResult<&str, Atom> {
-    let p = delimited(char('('), many0(parse_atom), char(')')).parse(input)?;
-    let args = p.1;
-    let sexpr = args
-        .into_iter()
-        .rev()
-        .fold(Atom::default(), |tail, item: Atom| {
+impl<'a> Reader<'a> {
+    fn new(src: &'a str, file: Option<Arc<str>>) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(src.match_indices('\n').map(|(idx, _)| idx + 1))
+            .collect();
+        Reader {
+            src,
+            file,
+            line_starts,
+        }
+    }
+
+    /// Byte offset of `rest`, which must be a suffix of the source.
+    fn offset(&self, rest: &str) -> usize {
+        self.src.len() - rest.len()
+    }
+
+    fn span(&self, offset: usize, len: usize) -> Span {
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let col = self.src[self.line_starts[line]..offset].chars().count() + 1;
+        Span {
+            file: self.file.clone(),
+            line: line + 1,
+            col,
+            offset,
+            len,
+        }
+    }
+
+    /// Text of the line containing `offset`, without the line terminator.
+    fn line_text(&self, offset: usize) -> &'a str {
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        self.src[self.line_starts[line]..]
+            .lines()
+            .next()
+            .unwrap_or_default()
+    }
+
+    fn parse_sexp(&self, input: &'a str) -> IResult<&'a str, Atom> {
+        let p = preceded(
+            char('('),
+            cut(terminated(many0(|i| self.parse_form(i)), char(')'))),
+        )
+        .parse(input)
+        .map_err(|err| match err {
+            // the input ended before the `)`: point at the `(` instead
+            nom::Err::Failure(e) if e.input.is_empty() => {
+                nom::Err::Failure(nom::error::Error::new(input, ErrorKind::Eof))
+            }
+            err => err,
+        })?;
+        let end = self.offset(p.0);
+        let mut args = p.1;
+
+        // `(a b . c)` ends with a dotted tail instead of nil.
+        let mut tail = SAtom::new(Atom::Nil);
+        if args.len() >= 3 && matches!(&*args[args.len() - 2], Atom::Sym(dot) if dot == ".") {
+            tail = args.pop().unwrap_or_default();
+            args.pop();
+        }
+
+        // Every inner cons cell of the list spans from its element to the
+        // closing paren; the head cell gets the span of the whole list.
+        for (idx, item) in args.into_iter().enumerate().rev() {
+            let start = span::lookup(&item).map_or(end, |span| span.offset);
+            tail = SAtom::new(Atom::Cons(SExpr {
+                car: item,
+                cdr: tail,
+            }));
+            if idx > 0 {
+                span::record(&tail, self.span(start, end - start));
+            }
+        }
+        let tail = (*tail).clone();
+
+        Ok((p.0, tail))
+    }
+
+    /// Read `'x`, `#'x`, `` `x ``, `,x` and `,@x` as `(quote x)`,
+    /// `(function x)`, `(quasiquote x)`, `(unquote x)` and `(unquote-splicing x)`.
+    fn parse_quoted(&self, input: &'a str) -> IResult<&'a str, Atom> {
+        let (input, name) = alt((
+            value("quote", char('\'')),
+            value("function", tag("#'")),
+            value("quasiquote", char('`')),
+            value("unquote-splicing", tag(",@")),
+            value("unquote", char(',')),
+        ))
+        .parse(input)?;
+        let (input, form) = cut(|i| self.parse_form(i)).parse(input)?;
+
+        let form_span = span::lookup(&form);
+        let cell = SAtom::new(Atom::Cons(SExpr {
+            car: form,
+            cdr: SAtom::new(Atom::Nil),
+        }));
+        if let Some(form_span) = form_span {
+            span::record(&cell, form_span);
+        }
+
+        Ok((
+            input,
             Atom::Cons(SExpr {
-                car: SAtom::new(item),
-                cdr: SAtom::new(tail),
-            })
-        });
+                car: SAtom::new(Atom::Sym(name.into())),
+                cdr: cell,
+            }),
+        ))
+    }
 
-    Ok((p.0, sexpr))
+    /// Parse one atom, surrounded by optional blanks, and record its span.
+    fn parse_form(&self, input: &'a str) -> IResult<&'a str, SAtom> {
+        let (input, _) = blank(input)?;
+        let start = self.offset(input);
+        let (input, atom) = alt((
+            parse_num,
+            parse_str,
+            parse_sym,
+            |i| self.parse_sexp(i),
+            |i| self.parse_quoted(i),
+        ))
+        .parse(input)?;
+        let atom = SAtom::new(atom);
+        span::record(&atom, self.span(start, self.offset(input) - start));
+        let (input, _) = blank(input)?;
+        Ok((input, atom))
+    }
 }
 
+#[cfg(test)]
 pub fn parse_atom(input: &str) -> IResult<&str, Atom> {
-    alt((ws(parse_num), ws(parse_str), ws(parse_sym), ws(parse_sexp))).parse(input)
+    let (rest, atom) = Reader::new(input, None).parse_form(input)?;
+    Ok((rest, (*atom).clone()))
 }
 
+#[cfg(test)]
 pub fn parse(input: &str) -> Atom {
     let res = parse_atom(input).unwrap();
     res.1
 }
 
+/// Error produced when a top-level form can't be read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// Byte offset of the unreadable form in the source.
+    pub offset: usize,
+    /// Location where the reader gave up.
+    pub span: Span,
+    /// Source line containing `span`, for diagnostics.
+    pub line: String,
+    /// What went wrong.
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}: {}", self.span, self.message)?;
+        writeln!(f, "  {}", self.line)?;
+        write!(f, "  {:>width$}", "^", width = self.span.col)
+    }
+}
+
+/// Iterator over every top-level form of a source text, in order.
+///
+/// Iteration stops after the first form that fails to parse; `offset` then
+/// points at the unreadable input.
+pub struct Forms<'a> {
+    reader: Reader<'a>,
+    rest: &'a str,
+    failed: bool,
+}
+
+impl<'a> Forms<'a> {
+    /// Name the source, so that spans report it.
+    pub fn with_file(mut self, file: &str) -> Self {
+        self.reader.file = Some(file.into());
+        self
+    }
+
+    /// Byte offset of `rest` in the original source.
+    pub fn offset(&self) -> usize {
+        self.reader.offset(self.rest)
+    }
+}
+
+impl Iterator for Forms<'_> {
+    type Item = Result<SAtom, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        let (rest, _) = blank::<nom::error::Error<&str>>(self.rest).ok()?;
+        self.rest = rest;
+        if rest.is_empty() {
+            return None;
+        }
+
+        match self.reader.parse_form(rest) {
+            Ok((rest, atom)) => {
+                self.rest = rest;
+                Some(Ok(atom))
+            }
+            Err(err) => {
+                self.failed = true;
+                let src = self.reader.src;
+                let (at, message) = match err {
+                    nom::Err::Error(e) | nom::Err::Failure(e) if e.code == ErrorKind::Eof => {
+                        let open = self.reader.span(self.reader.offset(e.input), 1);
+                        let message = format!(
+                            "unclosed list opened at {}:{}, expected `)` before the end of input",
+                            open.line, open.col
+                        );
+                        (src.len(), message)
+                    }
+                    nom::Err::Error(e) | nom::Err::Failure(e) => {
+                        (self.reader.offset(e.input), "couldn't parse form".into())
+                    }
+                    nom::Err::Incomplete(_) => (src.len(), "couldn't parse form".into()),
+                };
+                Some(Err(ParseError {
+                    offset: self.offset(),
+                    span: self.reader.span(at, 0),
+                    line: self.reader.line_text(at).to_string(),
+                    message,
+                }))
+            }
+        }
+    }
+}
+
+/// Read all the top-level forms of `input`.
+pub fn parse_all(input: &str) -> Forms<'_> {
+    Forms {
+        reader: Reader::new(input, None),
+        rest: input,
+        failed: false,
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::{nil, num, sexpr, str, sym};
+    use crate::{cons, nil, num, sexpr, str, sym};
 
     use super::*;
 
@@ -90,6 +370,41 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_numbers() {
+        assert_eq!(parse_atom("-42").unwrap().1, num!(-42));
+        assert_eq!(parse_atom("+7").unwrap().1, num!(7));
+        assert_eq!(parse_atom("1.0e3").unwrap().1, num!(1000.0));
+        assert_eq!(parse_atom("2.").unwrap().1, num!(2.0));
+        assert_eq!(format!("{:?}", parse("1/3")), "1/3");
+        assert_eq!(format!("{:?}", parse("-6/4")), "-3/2");
+        assert_eq!(parse_atom("4/2").unwrap().1, num!(2));
+        assert_eq!(
+            format!("{:?}", parse("123456789012345678901234567890")),
+            "123456789012345678901234567890"
+        );
+        assert_eq!(parse_atom("1/x").unwrap().1, sym!("1/x"));
+        assert_eq!(parse_atom("1/2/3").unwrap().1, sym!("1/2/3"));
+
+        let err = parse_all("(list 1/0)").next().unwrap().unwrap_err();
+        assert_eq!(err.span.col, 7);
+    }
+
+    #[test]
+    fn test_symbol_chars() {
+        assert_eq!(parse_atom("-1.5e2").unwrap().1, num!(-150.0));
+        assert_eq!(parse_atom("handler-case").unwrap().1, sym!("handler-case"));
+        assert_eq!(
+            parse_atom("*print-depth*").unwrap().1,
+            sym!("*print-depth*")
+        );
+        assert_eq!(parse_atom("<=").unwrap().1, sym!("<="));
+        assert_eq!(parse_atom("-").unwrap().1, sym!("-"));
+        assert_eq!(parse_atom("1+").unwrap().1, sym!("1+"));
+        assert_eq!(parse_atom("info").unwrap().1, sym!("info"));
+        assert_eq!(parse_atom("nan").unwrap().1, sym!("nan"));
+    }
+
     #[test]
     fn test_sexp() {
         assert_eq!(parse_atom("()").unwrap().1, nil!());
@@ -99,4 +414,137 @@ mod tests {
             sexpr!(sym!("add"), num!(1), num!(2),)
         );
     }
+
+    #[test]
+    fn test_reader_macros() {
+        assert_eq!(
+            parse_atom("'a").unwrap().1,
+            sexpr!(sym!("quote"), sym!("a"))
+        );
+        assert_eq!(
+            parse_atom("`(a ,b ,@c)").unwrap().1,
+            sexpr!(
+                sym!("quasiquote"),
+                sexpr!(
+                    sym!("a"),
+                    sexpr!(sym!("unquote"), sym!("b")),
+                    sexpr!(sym!("unquote-splicing"), sym!("c"))
+                )
+            )
+        );
+        assert_eq!(
+            parse_atom("#'car").unwrap().1,
+            sexpr!(sym!("function"), sym!("car"))
+        );
+        assert_eq!(
+            parse_atom("'(1 . 2)").unwrap().1,
+            sexpr!(sym!("quote"), cons!(num!(1), num!(2)))
+        );
+        assert_eq!(
+            parse_atom("(a b . (c))").unwrap().1,
+            sexpr!(sym!("a"), sym!("b"), sym!("c"))
+        );
+    }
+
+    #[test]
+    fn test_parse_all() {
+        let src = r#"
+; leading comment
+(defvar a 1) ; trailing comment
+(add a
+     ; comment inside a list
+     2)
+"done"
+"#;
+        let forms = parse_all(src).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(
+            forms,
+            vec![
+                SAtom::new(sexpr!(sym!("defvar"), sym!("a"), num!(1))),
+                SAtom::new(sexpr!(sym!("add"), sym!("a"), num!(2))),
+                SAtom::new(str!("done")),
+            ]
+        );
+
+        assert_eq!(parse_all("  ; only a comment\n").count(), 0);
+    }
+
+    #[test]
+    fn test_parse_all_error() {
+        let mut forms = parse_all("(add 1 2) (sub 3");
+        assert_eq!(
+            forms.next(),
+            Some(Ok(SAtom::new(sexpr!(sym!("add"), num!(1), num!(2)))))
+        );
+
+        let err = forms.next().unwrap().unwrap_err();
+        assert_eq!(err.offset, 10);
+        assert_eq!(forms.offset(), 10);
+        assert_eq!(forms.next(), None);
+    }
+
+    #[test]
+    fn test_spans() {
+        let src = "(defvar a 1)\n\n  (add a\n       (mul 2 3))";
+        let forms = parse_all(src)
+            .with_file("test.lisp")
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        let span = span::lookup(&forms[1]).unwrap();
+        assert_eq!(span.to_string(), "test.lisp:3:3");
+        assert_eq!(
+            &src[span.offset..span.offset + span.len],
+            "(add a\n       (mul 2 3))"
+        );
+
+        // (add a (mul 2 3)) -> second element `a`, third element `(mul 2 3)`
+        let Atom::Cons(add) = &*forms[1] else {
+            panic!("expected a list")
+        };
+        let elems = add.iter().collect::<Vec<_>>();
+        assert_eq!(
+            span::lookup(&elems[1]).unwrap().to_string(),
+            "test.lisp:3:8"
+        );
+        let mul = span::lookup(&elems[2]).unwrap();
+        assert_eq!((mul.line, mul.col, mul.len), (4, 8, 9));
+
+        // inner cons cells span from their element to the closing paren
+        let span = span::lookup(&add.cdr).unwrap();
+        assert_eq!(
+            &src[span.offset..span.offset + span.len],
+            "a\n       (mul 2 3))"
+        );
+    }
+
+    #[test]
+    fn test_parse_error_caret() {
+        let mut forms = parse_all("(add 1\n  (mul 2 ] 3))").with_file("bad.lisp");
+        let err = forms.next().unwrap().unwrap_err();
+        assert_eq!(err.offset, 0);
+        assert_eq!((err.span.line, err.span.col), (2, 10));
+        assert_eq!(
+            err.to_string(),
+            "bad.lisp:2:10: couldn't parse form\n    (mul 2 ] 3))\n           ^"
+        );
+
+        let err = parse_all("\"unterminated").next().unwrap().unwrap_err();
+        assert_eq!((err.span.line, err.span.col), (1, 14));
+
+        // an unclosed list names the `(` that is missing its `)`
+        let mut forms = parse_all("(ok)\n\n(add 1\n  (mul 2 3)").with_file("open.lisp");
+        assert!(forms.next().unwrap().is_ok());
+        let err = forms.next().unwrap().unwrap_err();
+        assert_eq!((err.span.line, err.span.col), (4, 12));
+        assert_eq!(
+            err.to_string(),
+            "open.lisp:4:12: unclosed list opened at 3:1, expected `)` before the end of input\n    (mul 2 3)\n             ^"
+        );
+        let err = parse_all("(a (b c").next().unwrap().unwrap_err();
+        assert_eq!(
+            err.message,
+            "unclosed list opened at 1:4, expected `)` before the end of input"
+        );
+    }
 }
@@ -0,0 +1,634 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    atom::{Atom, Fun, SAtom},
+    env::{list_items, Env},
+    lisp_error::LispError,
+    lisp_eval::{Args, EvalResult},
+    nil, t,
+};
+
+fn symbol(name: &str) -> SAtom {
+    Atom::Sym(name.into()).into()
+}
+
+/// The form `(<name> <args>...)`.
+fn call(name: &str, args: impl IntoIterator<Item = SAtom>) -> SAtom {
+    let items: Vec<SAtom> = std::iter::once(symbol(name)).chain(args).collect();
+    Atom::Cons(items.into_iter().collect()).into()
+}
+
+fn setq(var: &str, value: SAtom) -> SAtom {
+    call("setq", [symbol(var), value])
+}
+
+/// `(go <end>)` when `test` is true.
+fn exit_when(test: SAtom) -> SAtom {
+    call("when", [test, call("go", [symbol(END)])])
+}
+
+/// `(progn <forms>...)`, or `nil` without forms.
+fn progn(forms: Vec<SAtom>) -> SAtom {
+    match forms.is_empty() {
+        true => nil!().into(),
+        false => call("progn", forms),
+    }
+}
+
+const NEXT: &str = "%loop-next";
+const END: &str = "%loop-end";
+const FIRST: &str = "%loop-first";
+const RESULT: &str = "%loop-result";
+
+/// What an accumulation clause builds.
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    /// `collect`/`append`: a list, built reversed.
+    List,
+    /// `sum`/`count`: a number.
+    Number,
+    /// `maximize`/`minimize`: a number, `nil` until the first value.
+    Extreme,
+}
+
+/// The pieces of a `loop` expansion, filled in clause by clause:
+///
+/// ```lisp
+/// (block nil
+///   (let* (<bindings>...)
+///     <initially>...
+///     (tagbody
+///      %loop-next
+///       <tests>... <body>... <steps>...
+///       (go %loop-next)
+///      %loop-end
+///       <reverse the list accumulators> <finally>...)
+///     <result>))
+/// ```
+#[derive(Default)]
+struct Loop {
+    tokens: Vec<SAtom>,
+    pos: usize,
+    bindings: Vec<(String, SAtom)>,
+    initially: Vec<SAtom>,
+    /// Termination tests and variable updates at the top of each iteration.
+    tests: Vec<SAtom>,
+    body: Vec<SAtom>,
+    /// Variable updates at the bottom of each iteration.
+    steps: Vec<SAtom>,
+    finally: Vec<SAtom>,
+    /// Accumulator variables and what they build.
+    accumulators: Vec<(String, Kind)>,
+    /// Whether a `for = ... then` clause needs the first-iteration flag.
+    uses_first: bool,
+    /// Counter for the names of hidden variables.
+    hidden: usize,
+}
+
+impl Loop {
+    fn peek(&self) -> Option<&str> {
+        match self.tokens.get(self.pos).map(|t| &**t) {
+            Some(Atom::Sym(name)) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Consume the loop keyword `kw` if it is next.
+    fn accept(&mut self, kw: &str) -> bool {
+        let found = self.peek() == Some(kw);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn form(&mut self) -> Result<SAtom, LispError> {
+        let form = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or(LispError::syntax("loop", "clause is missing a form"))?;
+        self.pos += 1;
+        Ok(form)
+    }
+
+    fn var(&mut self) -> Result<String, LispError> {
+        match &*self.form()? {
+            Atom::Sym(var) => Ok(var.clone()),
+            _ => Err(LispError::syntax("loop", "variable must be a symbol")),
+        }
+    }
+
+    /// The compound forms of `do`, `initially` and `finally`, up to the next
+    /// loop keyword.
+    fn compound_forms(&mut self) -> Vec<SAtom> {
+        let mut forms = Vec::new();
+        while let Some(form) = self.tokens.get(self.pos) {
+            if !matches!(&**form, Atom::Cons(_)) {
+                break;
+            }
+            forms.push(form.clone());
+            self.pos += 1;
+        }
+        forms
+    }
+
+    /// Bind a variable only the expansion sees.
+    fn hidden_var(&mut self, role: &str, init: SAtom) -> String {
+        let name = format!("%loop-{role}-{}", self.hidden);
+        self.hidden += 1;
+        self.bindings.push((name.clone(), init));
+        name
+    }
+
+    fn parse(&mut self) -> Result<(), LispError> {
+        while self.pos < self.tokens.len() {
+            let Some(kw) = self.peek().map(str::to_string) else {
+                return Err(LispError::syntax("loop", "expected a loop keyword"));
+            };
+            self.pos += 1;
+            match kw.as_str() {
+                "for" | "as" => self.for_clause()?,
+                "with" => {
+                    let var = self.var()?;
+                    let init = match self.accept("=") {
+                        true => self.form()?,
+                        false => nil!().into(),
+                    };
+                    self.bindings.push((var, init));
+                }
+                "repeat" => {
+                    let count = self.form()?;
+                    let counter = self.hidden_var("repeat", count);
+                    self.tests.push(exit_when(call(
+                        "<=",
+                        [symbol(&counter), Atom::Int(0).into()],
+                    )));
+                    self.steps.push(setq(
+                        &counter,
+                        call("sub", [symbol(&counter), Atom::Int(1).into()]),
+                    ));
+                }
+                "while" => {
+                    let test = self.form()?;
+                    self.body.push(exit_when(call("eq", [test, nil!().into()])));
+                }
+                "until" => {
+                    let test = self.form()?;
+                    self.body.push(exit_when(test));
+                }
+                "initially" => {
+                    let forms = self.compound_forms();
+                    self.initially.extend(forms);
+                }
+                "finally" => {
+                    let forms = self.compound_forms();
+                    self.finally.extend(forms);
+                }
+                _ => {
+                    let form = self.selectable(&kw)?;
+                    self.body.push(form);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// `for <var> in|on|across|=|from...`
+    fn for_clause(&mut self) -> Result<(), LispError> {
+        let var = self.var()?;
+        let Some(kw) = self.peek().map(str::to_string) else {
+            return Err(LispError::syntax(
+                "loop",
+                "for needs in, on, across, = or from",
+            ));
+        };
+
+        match kw.as_str() {
+            "in" | "across" => {
+                self.pos += 1;
+                let mut list = self.form()?;
+                if kw == "across" {
+                    list = call("%loop-elements", [list]);
+                }
+                let tail = self.hidden_var("tail", list);
+                let step = self.list_step(&tail)?;
+                self.bindings.push((var.clone(), nil!().into()));
+                self.tests
+                    .push(exit_when(call("eq", [symbol(&tail), nil!().into()])));
+                self.tests.push(setq(&var, call("car", [symbol(&tail)])));
+                self.steps.push(step);
+            }
+            "on" => {
+                self.pos += 1;
+                let list = self.form()?;
+                self.bindings.push((var.clone(), list));
+                let step = self.list_step(&var)?;
+                self.tests
+                    .push(exit_when(call("eq", [symbol(&var), nil!().into()])));
+                self.steps.push(step);
+            }
+            "=" => {
+                self.pos += 1;
+                let init = self.form()?;
+                let value = match self.accept("then") {
+                    true => {
+                        self.uses_first = true;
+                        call("if", [symbol(FIRST), init, self.form()?])
+                    }
+                    false => init,
+                };
+                self.bindings.push((var.clone(), nil!().into()));
+                self.tests.push(setq(&var, value));
+            }
+            _ => self.numeric_for(var)?,
+        }
+        Ok(())
+    }
+
+    /// Step the list in `tail` by `cdr`, or by the function after `by`.
+    fn list_step(&mut self, tail: &str) -> Result<SAtom, LispError> {
+        let next = match self.accept("by") {
+            true => call("funcall", [self.form()?, symbol(tail)]),
+            false => call("cdr", [symbol(tail)]),
+        };
+        Ok(setq(tail, next))
+    }
+
+    /// `for <var> [from|upfrom|downfrom <n>] [to|upto|below|downto|above <n>] [by <n>]`
+    fn numeric_for(&mut self, var: String) -> Result<(), LispError> {
+        let mut from: SAtom = Atom::Int(0).into();
+        let mut limit = None;
+        let mut by: SAtom = Atom::Int(1).into();
+        let mut down = false;
+        let mut found = false;
+
+        while let Some(kw) = self.peek().map(str::to_string) {
+            match kw.as_str() {
+                "from" | "upfrom" | "downfrom" => {
+                    self.pos += 1;
+                    down |= kw == "downfrom";
+                    from = self.form()?;
+                }
+                "to" | "upto" | "below" | "downto" | "above" => {
+                    self.pos += 1;
+                    down |= kw == "downto" || kw == "above";
+                    let inclusive = !(kw == "below" || kw == "above");
+                    limit = Some((self.form()?, inclusive));
+                }
+                "by" => {
+                    self.pos += 1;
+                    by = self.form()?;
+                }
+                _ => break,
+            }
+            found = true;
+        }
+        if !found {
+            return Err(LispError::syntax(
+                "loop",
+                "for needs in, on, across, = or from",
+            ));
+        }
+
+        self.bindings.push((var.clone(), from));
+        if let Some((limit, inclusive)) = limit {
+            let limit = self.hidden_var("limit", limit);
+            // the test for having gone past the limit
+            let past = match (inclusive, down) {
+                (true, false) => ">",
+                (false, false) => ">=",
+                (true, true) => "<",
+                (false, true) => "<=",
+            };
+            self.tests
+                .push(exit_when(call(past, [symbol(&var), symbol(&limit)])));
+        }
+        let by = self.hidden_var("by", by);
+        let op = if down { "sub" } else { "add" };
+        self.steps
+            .push(setq(&var, call(op, [symbol(&var), symbol(&by)])));
+        Ok(())
+    }
+
+    /// A clause that can also appear under `when`: `do`, `return`, an
+    /// accumulation or a nested conditional.
+    fn selectable(&mut self, kw: &str) -> Result<SAtom, LispError> {
+        match kw {
+            "do" | "doing" => Ok(progn(self.compound_forms())),
+            "return" => Ok(call("return-from", [nil!().into(), self.form()?])),
+            "when" | "if" | "unless" => self.conditional(kw == "unless"),
+            "collect" | "collecting" | "append" | "appending" | "nconc" | "nconcing" | "sum"
+            | "summing" | "count" | "counting" | "maximize" | "maximizing" | "minimize"
+            | "minimizing" => self.accumulate(kw),
+            _ => Err(LispError::syntax("loop", "unknown loop clause")),
+        }
+    }
+
+    /// `when <test> <clause> [and <clause>]... [else <clause> [and <clause>]...] [end]`
+    fn conditional(&mut self, negate: bool) -> Result<SAtom, LispError> {
+        let mut test = self.form()?;
+        if negate {
+            test = call("eq", [test, nil!().into()]);
+        }
+        let then = self.selectable_group()?;
+        let otherwise = match self.accept("else") {
+            true => self.selectable_group()?,
+            false => Vec::new(),
+        };
+        self.accept("end");
+        Ok(call("if", [test, progn(then), progn(otherwise)]))
+    }
+
+    fn selectable_group(&mut self) -> Result<Vec<SAtom>, LispError> {
+        let mut forms = Vec::new();
+        loop {
+            let Some(kw) = self.peek().map(str::to_string) else {
+                return Err(LispError::syntax("loop", "conditional needs a clause"));
+            };
+            self.pos += 1;
+            forms.push(self.selectable(&kw)?);
+            if !self.accept("and") {
+                return Ok(forms);
+            }
+        }
+    }
+
+    /// `collect|append|sum|count|maximize|minimize <form> [into <var>]`
+    fn accumulate(&mut self, kw: &str) -> Result<SAtom, LispError> {
+        let value = self.form()?;
+        let kind = match kw {
+            "collect" | "collecting" | "append" | "appending" | "nconc" | "nconcing" => Kind::List,
+            "sum" | "summing" | "count" | "counting" => Kind::Number,
+            _ => Kind::Extreme,
+        };
+        let acc = match self.accept("into") {
+            true => self.var()?,
+            false => RESULT.to_string(),
+        };
+        match self.accumulators.iter().find(|(name, _)| *name == acc) {
+            Some((_, existing)) if *existing != kind => {
+                return Err(LispError::syntax(
+                    "loop",
+                    "accumulations into one variable must be of the same kind",
+                ))
+            }
+            Some(_) => {}
+            None => {
+                let init = match kind {
+                    Kind::Number => Atom::Int(0),
+                    _ => Atom::Nil,
+                };
+                self.bindings.push((acc.clone(), init.into()));
+                self.accumulators.push((acc.clone(), kind));
+            }
+        }
+
+        let acc_sym = symbol(&acc);
+        let updated = match kw {
+            "collect" | "collecting" => call("cons", [value, acc_sym]),
+            "append" | "appending" | "nconc" | "nconcing" => {
+                call("append", [call("reverse", [value]), acc_sym])
+            }
+            "sum" | "summing" => call("add", [acc_sym, value]),
+            "count" | "counting" => {
+                let incremented = call("add", [acc_sym.clone(), Atom::Int(1).into()]);
+                return Ok(call("when", [value, setq(&acc, incremented)]));
+            }
+            _ => {
+                let extreme = match kw {
+                    "maximize" | "maximizing" => "max",
+                    _ => "min",
+                };
+                // the first value is kept as is
+                let first = call("eq", [acc_sym.clone(), nil!().into()]);
+                let next = call(extreme, [acc_sym, value.clone()]);
+                call("if", [first, value, next])
+            }
+        };
+        Ok(setq(&acc, updated))
+    }
+
+    fn expand(self) -> SAtom {
+        let mut bindings: Vec<SAtom> = self
+            .bindings
+            .into_iter()
+            .map(|(name, init)| call(&name, [init]))
+            .collect();
+        if self.uses_first {
+            bindings.push(call(FIRST, [t!().into()]));
+        }
+
+        let mut items = vec![symbol(NEXT)];
+        items.extend(self.tests);
+        items.extend(self.body);
+        items.extend(self.steps);
+        if self.uses_first {
+            items.push(setq(FIRST, nil!().into()));
+        }
+        items.push(call("go", [symbol(NEXT)]));
+        items.push(symbol(END));
+        for (acc, kind) in &self.accumulators {
+            if *kind == Kind::List {
+                items.push(setq(acc, call("reverse", [symbol(acc)])));
+            }
+        }
+        items.extend(self.finally);
+
+        let result = match self.accumulators.iter().any(|(acc, _)| acc == RESULT) {
+            true => symbol(RESULT),
+            false => nil!().into(),
+        };
+
+        // collecting no bindings would give the `(nil)` of an empty SExpr
+        let bindings = match bindings.is_empty() {
+            true => nil!(),
+            false => Atom::Cons(bindings.into_iter().collect()),
+        };
+        let mut body = vec![bindings.into()];
+        body.extend(self.initially);
+        body.push(call("tagbody", items));
+        body.push(result);
+        call("block", [nil!().into(), call("let*", body)])
+    }
+}
+
+pub fn install(fun_map: &mut HashMap<String, Arc<Fun>>, macros: &mut HashMap<String, Arc<Fun>>) {
+    // (loop <clause>...) expands into block, let*, tagbody and setq; a loop
+    // of plain forms repeats them until a `return`
+    let loop_macro = Fun::Native(Box::new(|_: &mut Env, args: &Args| -> EvalResult {
+        let tokens = args.to_vec();
+        if tokens.iter().all(|form| matches!(&**form, Atom::Cons(_))) {
+            let mut items = vec![symbol(NEXT)];
+            items.extend(tokens);
+            items.push(call("go", [symbol(NEXT)]));
+            return Ok(call("block", [nil!().into(), call("tagbody", items)]));
+        }
+
+        let mut expansion = Loop {
+            tokens,
+            ..Default::default()
+        };
+        expansion.parse()?;
+        Ok(expansion.expand())
+    }));
+
+    // (%loop-elements <sequence>), called by `for ... across`: the elements of a list, or the characters
+    // of a string as one-character strings
+    let elements_op = Fun::Native(Box::new(|_: &mut Env, args: &Args| -> EvalResult {
+        let args = args.to_vec();
+        let [seq] = &args[..] else {
+            return Err(LispError::arity("%loop-elements", "1", args.len()).into());
+        };
+        match &**seq {
+            Atom::Str(text) => {
+                let chars: Vec<Atom> = text.chars().map(|c| Atom::Str(c.into())).collect();
+                match chars.is_empty() {
+                    true => Ok(nil!().into()),
+                    false => Ok(Atom::Cons(chars.into_iter().collect()).into()),
+                }
+            }
+            _ => {
+                list_items(seq)?;
+                Ok(seq.clone())
+            }
+        }
+    }));
+
+    macros.insert("loop".into(), loop_macro.into());
+    fun_map.insert("%loop-elements".into(), elements_op.into());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        num, sexpr, str, sym,
+        test_utils::{check_errors, run},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_loop_for_clauses() {
+        let env = &mut Env::default();
+        let cases = [
+            (
+                "(loop for x in (list 1 2 3) collect (mul x x))",
+                sexpr!(num!(1), num!(4), num!(9)),
+            ),
+            (
+                "(loop for x on (list 1 2 3) collect x)",
+                sexpr!(
+                    sexpr!(num!(1), num!(2), num!(3)),
+                    sexpr!(num!(2), num!(3)),
+                    sexpr!(num!(3))
+                ),
+            ),
+            (
+                "(loop for x in (list 1 2 3 4 5) by (function cdr) for i from 10 by 10 collect (list x i))",
+                sexpr!(
+                    sexpr!(num!(1), num!(10)),
+                    sexpr!(num!(2), num!(20)),
+                    sexpr!(num!(3), num!(30)),
+                    sexpr!(num!(4), num!(40)),
+                    sexpr!(num!(5), num!(50))
+                ),
+            ),
+            (
+                "(loop for i from 1 to 10 by 3 collect i)",
+                sexpr!(num!(1), num!(4), num!(7), num!(10)),
+            ),
+            ("(loop for i below 3 collect i)", sexpr!(num!(0), num!(1), num!(2))),
+            (
+                "(loop for i from 3 downto 1 collect i)",
+                sexpr!(num!(3), num!(2), num!(1)),
+            ),
+            (
+                "(loop for c across \"héllo\" collect c)",
+                sexpr!(str!("h"), str!("é"), str!("l"), str!("l"), str!("o")),
+            ),
+            (
+                "(loop for x = 1 then (mul x 2) repeat 5 collect x)",
+                sexpr!(num!(1), num!(2), num!(4), num!(8), num!(16)),
+            ),
+            (
+                "(loop with base = 100 for i from 1 to 2 collect (add base i))",
+                sexpr!(num!(101), num!(102)),
+            ),
+        ];
+        for (src, expected) in cases {
+            assert_eq!(*run(src, env).unwrap(), expected, "{src}");
+        }
+    }
+
+    #[test]
+    fn test_loop_accumulation_and_control() {
+        let env = &mut Env::default();
+        let cases = [
+            (
+                "(loop for x in (list 1 2 3 4 5 6) when (eq (car (list x)) 2) collect x else collect (mul x 10))",
+                sexpr!(num!(10), num!(2), num!(30), num!(40), num!(50), num!(60)),
+            ),
+            (
+                "(loop for x in (list (list 1 2) nil (list 3)) append x)",
+                sexpr!(num!(1), num!(2), num!(3)),
+            ),
+            ("(loop for i from 1 to 100 sum i)", num!(5050)),
+            ("(loop for x in (list 1 nil 2 nil) count x)", num!(2)),
+            ("(loop for x in (list 3 9 2) maximize x)", num!(9)),
+            ("(loop for x in (list 3 9 2) minimize x)", num!(2)),
+            ("(loop for x in nil maximize x)", nil!()),
+            (
+                "(loop for x in (list 1 2 3 4) while (eq (eq x 3) nil) collect x)",
+                sexpr!(num!(1), num!(2)),
+            ),
+            (
+                "(loop for x in (list 1 2 3 4) until (eq x 2) collect x)",
+                sexpr!(num!(1)),
+            ),
+            (
+                "(loop for x in (list 1 2 3) unless (eq x 2) collect x and sum x into total finally (return (list total)))",
+                sexpr!(num!(4)),
+            ),
+            (
+                "(loop for x in (list 1 2 3) collect x into xs finally (return (reverse xs)))",
+                sexpr!(num!(3), num!(2), num!(1)),
+            ),
+            ("(loop for x in (list 1 2 3) when (eq x 2) return 'found)", sym!("found")),
+            (
+                "(let ((n 0)) (loop (setq n (add n 1)) (if (eq n 5) (return n) nil)))",
+                num!(5),
+            ),
+            (
+                "(let ((seen nil)) (loop for x in (list 1 2) do (setq seen (cons x seen)) (setq seen (cons 0 seen))) seen)",
+                sexpr!(num!(0), num!(2), num!(0), num!(1)),
+            ),
+            (
+                "(loop for x in (list 1 2) collect (loop for y in (list 10 20) collect (add x y)))",
+                sexpr!(
+                    sexpr!(num!(11), num!(21)),
+                    sexpr!(num!(12), num!(22))
+                ),
+            ),
+            (
+                "(let ((i 0)) (loop while (< i 3) do (setq i (+ i 1))) i)",
+                num!(3),
+            ),
+            ("(loop until t)", nil!()),
+            ("(loop do (return 5))", num!(5)),
+        ];
+        for (src, expected) in cases {
+            assert_eq!(*run(src, env).unwrap(), expected, "{src}");
+        }
+
+        check_errors(
+            env,
+            &[
+                ("(loop for x frob 1)", "program-error"),
+                ("(loop collect 1 sum 2)", "program-error"),
+                ("(loop bogus)", "program-error"),
+                ("(loop for x in)", "program-error"),
+            ],
+        );
+    }
+}
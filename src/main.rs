@@ -1,20 +1,33 @@
 mod atom;
+mod compiler;
+mod conditions;
+mod control;
 mod easy_cons;
 mod env;
+mod iteration;
+mod lambda_list;
+mod lisp_error;
 mod lisp_eval;
 mod lisp_parsing;
+mod loop_macro;
+mod math;
+mod number;
+mod quasiquote;
+mod random;
 mod sexpr;
+mod span;
+mod strings;
+#[cfg(test)]
+mod test_utils;
+mod vm;
 
-use std::{
-    fs,
-    process::exit,
-    sync::Arc,
-};
+use std::{fs, process::exit, sync::Arc};
 
 use atom::Atom;
-use env::Env;
+use env::{Backend, Env};
+use lisp_error::{EvalError, LispError};
 use lisp_eval::eval;
-use lisp_parsing::parse;
+use lisp_parsing::parse_all;
 
 use rustyline::{error::ReadlineError, DefaultEditor};
 
@@ -25,9 +38,17 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         env: Env::default(),
     };
 
-    if let Some(input_file) = std::env::args().nth(1) {
-        if let Err(e) = load_file(&input_file, &mut state) {
-            eprintln!("error loading {input_file}: {e}");
+    // `--vm` runs everything on the bytecode VM instead of the interpreter
+    let (flags, files): (Vec<String>, Vec<String>) = std::env::args()
+        .skip(1)
+        .partition(|arg| arg.starts_with("--"));
+    if flags.iter().any(|flag| flag == "--vm") {
+        state.env.backend = Backend::Vm;
+    }
+
+    if let Some(input_file) = files.first() {
+        if let Err(e) = load_file(input_file, &mut state) {
+            eprintln!("error loading {input_file}:\n{e}");
             exit(1);
         }
         println!("Loaded file: {input_file}");
@@ -58,11 +79,21 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     }
                 }
 
-                let input = parse(&line);
-                let res = eval(input.into(), &mut state.env);
-                match res {
-                    Ok(atom) => println!("=> {:#?}", atom),
-                    Err(err) => println!("!> {}", err),
+                for input in parse_all(line).with_file("<repl>") {
+                    let res = match input {
+                        Ok(input) => eval(input, &mut state.env),
+                        Err(err) => {
+                            println!("!> {}", LispError::Parse(err.into()));
+                            break;
+                        }
+                    };
+                    match res {
+                        Ok(atom) => println!("=> {:#?}", atom),
+                        Err(err) => {
+                            println!("!> {}", err);
+                            break;
+                        }
+                    }
                 }
             }
             Err(ReadlineError::Interrupted) => {
@@ -88,11 +119,20 @@ struct ReplState {
     env: Env,
 }
 
-fn load_file(path: &str, state: &mut ReplState) -> Result<Arc<Atom>, &'static str> {
+/// Evaluate every top-level form of the file in order, returning the value
+/// of the last one. Stops at the first form that fails to parse or eval.
+fn load_file(path: &str, state: &mut ReplState) -> Result<Arc<Atom>, EvalError> {
     let contents = fs::read_to_string(path);
     state.loaded_file = Some(path.to_string());
-    state.loaded_text = contents.or_else(|_| Err("Coundn't load input file"))?;
-    eval(parse(&state.loaded_text).into(), &mut state.env)
+    state.loaded_text = contents.map_err(|e| LispError::Io(format!("{path}: {e}")))?;
+
+    let mut result: Arc<Atom> = Atom::Nil.into();
+    for (idx, form) in parse_all(&state.loaded_text).with_file(path).enumerate() {
+        let form = form.map_err(|e| LispError::Parse(e.into()))?;
+        let frame = format!("top-level form #{} {:?}", idx + 1, form);
+        result = eval(form, &mut state.env).map_err(|e| e.with_frame(frame))?;
+    }
+    Ok(result)
 }
 
 // Return `true` to exit the REPL.
@@ -125,7 +165,7 @@ Anything else is sent to eval()."
             };
             match load_file(path, state) {
                 Ok(r) => println!("Loaded file result:\n{r:#?}"),
-                Err(e) => eprintln!("error loading {path}: {e}"),
+                Err(e) => eprintln!("error loading {path}:\n{e}"),
             }
             false
         }
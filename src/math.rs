@@ -0,0 +1,452 @@
+use std::{cmp::Ordering, collections::HashMap, sync::Arc};
+
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_rational::BigRational;
+use num_traits::{FromPrimitive, One, Signed, ToPrimitive, Zero};
+
+use crate::{
+    atom::{Atom, Fun},
+    env::{check_arity, get_num, Env},
+    lisp_error::LispError,
+    lisp_eval::{Args, EvalResult},
+    number::Number,
+};
+
+/// The numbers in `args`, checking there are `min` to `max` of them.
+fn num_args(name: &str, args: &Args, min: usize, max: usize) -> Result<Vec<Number>, LispError> {
+    check_arity(name, args, min, max)?
+        .iter()
+        .map(get_num)
+        .collect()
+}
+
+fn type_error(expected: &'static str, n: Number) -> LispError {
+    LispError::type_error(expected, &n.into_atom().into())
+}
+
+fn integer(n: Number) -> Result<BigInt, LispError> {
+    match n {
+        Number::Int(n) => Ok(n.into()),
+        Number::Big(n) => Ok(n),
+        n => Err(type_error("integer", n)),
+    }
+}
+
+/// How `truncate`, `floor`, `ceiling` and `round` pick the integer quotient.
+#[derive(Clone, Copy)]
+enum Rounding {
+    Truncate,
+    Floor,
+    Ceiling,
+    /// To the nearest integer, and to the even one on a tie.
+    Round,
+}
+
+impl Rounding {
+    fn exact(self, q: &BigRational) -> BigInt {
+        match self {
+            Rounding::Truncate => q.trunc().to_integer(),
+            Rounding::Floor => q.floor().to_integer(),
+            Rounding::Ceiling => q.ceil().to_integer(),
+            Rounding::Round => {
+                let floor = q.floor();
+                let half = BigRational::new(1.into(), 2.into());
+                let floor_int = floor.to_integer();
+                match (q - floor).cmp(&half) {
+                    Ordering::Less => floor_int,
+                    Ordering::Greater => floor_int + 1,
+                    Ordering::Equal if floor_int.is_even() => floor_int,
+                    Ordering::Equal => floor_int + 1,
+                }
+            }
+        }
+    }
+
+    fn float(self, q: f64) -> f64 {
+        match self {
+            Rounding::Truncate => q.trunc(),
+            Rounding::Floor => q.floor(),
+            Rounding::Ceiling => q.ceil(),
+            Rounding::Round => q.round_ties_even(),
+        }
+    }
+}
+
+/// Divide `n` by `d`, rounding the quotient to an integer: the quotient and
+/// the remainder `n - quotient * d`.
+fn divide(n: Number, d: Number, rounding: Rounding) -> Result<(Number, Number), LispError> {
+    if d.is_zero() {
+        return Err(LispError::DivisionByZero);
+    }
+    if matches!((&n, &d), (Number::Float(_), _) | (_, Number::Float(_))) {
+        let (n, d) = (n.to_f64(), d.to_f64());
+        let q = rounding.float(n / d);
+        let Some(quotient) = BigInt::from_f64(q) else {
+            return Err(type_error("finite number", Number::Float(n)));
+        };
+        return Ok((Number::Big(quotient), Number::Float(n - q * d)));
+    }
+
+    let quotient = match n.clone().div(d.clone())? {
+        Number::Ratio(q) => Number::Big(rounding.exact(&q)),
+        q => q,
+    };
+    let remainder = n.sub(quotient.clone().mul(d));
+    Ok((quotient, remainder))
+}
+
+fn abs(n: Number) -> Number {
+    match n {
+        Number::Int(n) => match n.checked_abs() {
+            Some(n) => Number::Int(n),
+            None => Number::Big(BigInt::from(n).abs()),
+        },
+        Number::Big(n) => Number::Big(n.abs()),
+        Number::Ratio(n) => Number::Ratio(n.abs()),
+        Number::Float(n) => Number::Float(n.abs()),
+    }
+}
+
+/// The arguments a float function is defined for, and what they must be.
+type Domain = Option<(&'static str, fn(f64) -> bool)>;
+
+/// Largest integer `expt` and `ash` build, in bits (2 MiB of digits).
+const MAX_RESULT_BITS: u64 = 1 << 24;
+
+/// `base` to the power `power`: exact for an exact base and an integer power,
+/// a float otherwise.
+fn expt(base: Number, power: Number) -> Result<Number, LispError> {
+    let is_float = matches!(base, Number::Float(_));
+    match power {
+        Number::Int(_) | Number::Big(_) if !is_float => {
+            let power = integer(power)?;
+            let Some(exponent) = power.abs().to_u32() else {
+                return Err(type_error(
+                    "integer exponent below 2^32",
+                    Number::Big(power),
+                ));
+            };
+            // the result has at least (bits - 1) * exponent bits, so `0`, `1`
+            // and `-1` are the only bases any exponent is cheap for
+            let bits = match &base {
+                Number::Ratio(r) => r.numer().bits().max(r.denom().bits()),
+                base => integer(base.clone())?.bits(),
+            };
+            if (bits.saturating_sub(1)).saturating_mul(u64::from(exponent)) > MAX_RESULT_BITS {
+                return Err(type_error(
+                    "exponent keeping the result under 2^24 bits",
+                    Number::Big(power),
+                ));
+            }
+            let result = match base {
+                Number::Int(_) | Number::Big(_) => Number::Big(integer(base)?.pow(exponent)),
+                Number::Ratio(base) => Number::Ratio(BigRational::new_raw(
+                    base.numer().pow(exponent),
+                    base.denom().pow(exponent),
+                )),
+                Number::Float(_) => unreachable!(),
+            };
+            match power.is_negative() {
+                true => Number::Int(1).div(result),
+                false => Ok(result),
+            }
+        }
+        power => {
+            let (base, power) = (base.to_f64(), power.to_f64());
+            if base == 0.0 && power < 0.0 {
+                return Err(LispError::DivisionByZero);
+            }
+            if base < 0.0 && power.fract() != 0.0 {
+                return Err(type_error(
+                    "non-negative base for a fractional power",
+                    Number::Float(base),
+                ));
+            }
+            Ok(Number::Float(base.powf(power)))
+        }
+    }
+}
+
+pub fn install(fun_map: &mut HashMap<String, Arc<Fun>>) {
+    // (truncate|floor|ceiling|round <n> [<divisor>]) returns the list
+    // (<quotient> <remainder>), the quotient rounded to an integer
+    let rounding_ops = |name: &'static str, rounding: Rounding| {
+        Fun::Native(Box::new(move |_: &mut Env, args: &Args| -> EvalResult {
+            let mut nums = num_args(name, args, 1, 2)?.into_iter();
+            let n = nums.next().unwrap();
+            let d = nums.next().unwrap_or(Number::Int(1));
+            let (q, r) = divide(n, d, rounding)?;
+            Ok(Atom::Cons([q.into_atom(), r.into_atom()].into_iter().collect()).into())
+        }))
+    };
+
+    // (mod <n> <divisor>) / (rem <n> <divisor>): the remainder of `floor` /
+    // `truncate`, with the sign of the divisor / of <n>
+    let remainder_ops = |name: &'static str, rounding: Rounding| {
+        Fun::Native(Box::new(move |_: &mut Env, args: &Args| -> EvalResult {
+            let mut nums = num_args(name, args, 2, 2)?.into_iter();
+            let (n, d) = (nums.next().unwrap(), nums.next().unwrap());
+            Ok(divide(n, d, rounding)?.1.into_atom().into())
+        }))
+    };
+
+    // functions of one number into a float; `domain` rejects the arguments
+    // the function isn't defined for, naming what they must be
+    let float_ops = |name: &'static str, op: fn(f64) -> f64, domain: Domain| {
+        Fun::Native(Box::new(move |_: &mut Env, args: &Args| -> EvalResult {
+            let n = num_args(name, args, 1, 1)?.remove(0);
+            if let Some((expected, valid)) = domain {
+                if !valid(n.to_f64()) {
+                    return Err(type_error(expected, n).into());
+                }
+            }
+            Ok(Atom::Num(op(n.to_f64())).into())
+        }))
+    };
+
+    // (abs <n>)
+    let abs_op = Fun::Native(Box::new(|_: &mut Env, args: &Args| -> EvalResult {
+        let n = num_args("abs", args, 1, 1)?.remove(0);
+        Ok(abs(n).into_atom().into())
+    }));
+
+    // (expt <base> <power>)
+    let expt_op = Fun::Native(Box::new(|_: &mut Env, args: &Args| -> EvalResult {
+        let mut nums = num_args("expt", args, 2, 2)?.into_iter();
+        let (base, power) = (nums.next().unwrap(), nums.next().unwrap());
+        Ok(expt(base, power)?.into_atom().into())
+    }));
+
+    // (log <n> [<base>]), the natural logarithm by default
+    let log_op = Fun::Native(Box::new(|_: &mut Env, args: &Args| -> EvalResult {
+        let mut nums = num_args("log", args, 1, 2)?.into_iter();
+        let n = nums.next().unwrap();
+        if n.to_f64() <= 0.0 {
+            return Err(type_error("positive number", n).into());
+        }
+        let log = match nums.next() {
+            None => n.to_f64().ln(),
+            Some(base) if base.to_f64() > 0.0 && base.to_f64() != 1.0 => {
+                n.to_f64().ln() / base.to_f64().ln()
+            }
+            Some(base) => return Err(type_error("positive number other than 1", base).into()),
+        };
+        Ok(Atom::Num(log).into())
+    }));
+
+    // (atan <y> [<x>]), the angle of the point (x, y) with two args
+    let atan_op = Fun::Native(Box::new(|_: &mut Env, args: &Args| -> EvalResult {
+        let nums = num_args("atan", args, 1, 2)?;
+        let angle = match &nums[..] {
+            [y] => y.to_f64().atan(),
+            [y, x] => y.to_f64().atan2(x.to_f64()),
+            _ => unreachable!(),
+        };
+        Ok(Atom::Num(angle).into())
+    }));
+
+    // variadic operations on integers, folded from `identity`
+    let integer_ops = |name: &'static str, identity: i64, op: fn(BigInt, BigInt) -> BigInt| {
+        Fun::Native(Box::new(move |_: &mut Env, args: &Args| -> EvalResult {
+            let mut acc = BigInt::from(identity);
+            for n in num_args(name, args, 0, usize::MAX)? {
+                acc = op(acc, integer(n)?);
+            }
+            Ok(Number::Big(acc).into_atom().into())
+        }))
+    };
+
+    // (ash <n> <count>) shifts <n> left by <count> bits, or right (rounding
+    // down) when <count> is negative
+    let ash_op = Fun::Native(Box::new(|_: &mut Env, args: &Args| -> EvalResult {
+        let mut nums = num_args("ash", args, 2, 2)?.into_iter();
+        let n = integer(nums.next().unwrap())?;
+        let count = integer(nums.next().unwrap())?;
+        let shifted = match count.to_i64() {
+            Some(count) if count >= 0 => match n.bits().saturating_add(count as u64) {
+                _ if n.is_zero() => n,
+                bits if bits <= MAX_RESULT_BITS => n << count,
+                _ => {
+                    return Err(type_error(
+                        "shift count keeping the result under 2^24 bits",
+                        Number::Int(count),
+                    )
+                    .into())
+                }
+            },
+            // shifting right by more bits than <n> has leaves 0 or -1
+            Some(count) => n >> count.unsigned_abs().min(u64::from(u32::MAX)),
+            None if count.is_negative() => match n.is_negative() {
+                true => -BigInt::one(),
+                false => BigInt::zero(),
+            },
+            None if n.is_zero() => n,
+            None => {
+                return Err(type_error(
+                    "shift count keeping the result under 2^24 bits",
+                    Number::Big(count),
+                )
+                .into())
+            }
+        };
+        Ok(Number::Big(shifted).into_atom().into())
+    }));
+
+    let roundings = [
+        ("truncate", Rounding::Truncate),
+        ("floor", Rounding::Floor),
+        ("ceiling", Rounding::Ceiling),
+        ("round", Rounding::Round),
+    ];
+    for (name, rounding) in roundings {
+        fun_map.insert(name.into(), rounding_ops(name, rounding).into());
+    }
+    fun_map.insert("mod".into(), remainder_ops("mod", Rounding::Floor).into());
+    fun_map.insert(
+        "rem".into(),
+        remainder_ops("rem", Rounding::Truncate).into(),
+    );
+    fun_map.insert("abs".into(), abs_op.into());
+    fun_map.insert("expt".into(), expt_op.into());
+    fun_map.insert(
+        "sqrt".into(),
+        float_ops(
+            "sqrt",
+            f64::sqrt,
+            Some(("non-negative number", |n| n >= 0.0)),
+        )
+        .into(),
+    );
+    fun_map.insert("exp".into(), float_ops("exp", f64::exp, None).into());
+    fun_map.insert("log".into(), log_op.into());
+    fun_map.insert("sin".into(), float_ops("sin", f64::sin, None).into());
+    fun_map.insert("cos".into(), float_ops("cos", f64::cos, None).into());
+    fun_map.insert("tan".into(), float_ops("tan", f64::tan, None).into());
+    fun_map.insert("atan".into(), atan_op.into());
+    fun_map.insert("gcd".into(), integer_ops("gcd", 0, |a, b| a.gcd(&b)).into());
+    fun_map.insert("lcm".into(), integer_ops("lcm", 1, |a, b| a.lcm(&b)).into());
+    fun_map.insert(
+        "logand".into(),
+        integer_ops("logand", -1, |a, b| a & b).into(),
+    );
+    fun_map.insert(
+        "logior".into(),
+        integer_ops("logior", 0, |a, b| a | b).into(),
+    );
+    fun_map.insert(
+        "logxor".into(),
+        integer_ops("logxor", 0, |a, b| a ^ b).into(),
+    );
+    fun_map.insert("ash".into(), ash_op.into());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{check, check_errors, run};
+
+    use super::*;
+
+    #[test]
+    fn test_division_and_rounding() {
+        check(&[
+            ("(floor 7 2)", "(3 1)"),
+            ("(floor -7 2)", "(-4 1)"),
+            ("(truncate -7 2)", "(-3 -1)"),
+            ("(ceiling 7 2)", "(4 -1)"),
+            ("(round 5 2)", "(2 1)"),
+            ("(round 7 2)", "(4 -1)"),
+            ("(round -5/2)", "(-2 -1/2)"),
+            ("(floor 7/2)", "(3 1/2)"),
+            ("(floor 2.5)", "(2 0.5)"),
+            ("(round 2.5)", "(2 0.5)"),
+            ("(truncate 1e30)", "(1000000000000000019884624838656 0.0)"),
+            ("(mod -7 2)", "1"),
+            ("(rem -7 2)", "-1"),
+            ("(mod 7 -2)", "-1"),
+            ("(mod 5.5 2)", "1.5"),
+            ("(abs -9223372036854775808)", "9223372036854775808"),
+            ("(abs -1/2)", "1/2"),
+        ]);
+    }
+
+    #[test]
+    fn test_powers_and_transcendentals() {
+        check(&[
+            ("(expt 2 100)", "1267650600228229401496703205376"),
+            ("(expt 2 -2)", "1/4"),
+            ("(expt 2/3 3)", "8/27"),
+            ("(expt 0 0)", "1"),
+            ("(expt 2.0 3)", "8.0"),
+            ("(expt 4 1/2)", "2.0"),
+            ("(expt -1 4000000001)", "-1"),
+            ("(sqrt 16)", "4.0"),
+            ("(exp 0)", "1.0"),
+            ("(log 1)", "0.0"),
+            ("(log 8 2)", "3.0"),
+            ("(sin 0)", "0.0"),
+            ("(cos 0)", "1.0"),
+            ("(tan 0)", "0.0"),
+            ("(atan 1 1)", "0.7853981633974483"),
+        ]);
+    }
+
+    #[test]
+    fn test_integer_ops() {
+        check(&[
+            ("(gcd 12 18 8)", "2"),
+            ("(gcd -4 6)", "2"),
+            ("(gcd)", "0"),
+            ("(lcm 4 6)", "12"),
+            ("(lcm)", "1"),
+            ("(logand 12 10)", "8"),
+            ("(logand -1 5)", "5"),
+            ("(logior 12 10)", "14"),
+            ("(logxor 12 10)", "6"),
+            ("(ash 1 70)", "1180591620717411303424"),
+            ("(ash -5 -1)", "-3"),
+            ("(ash 5 -100)", "0"),
+            ("(ash -5 -100000000000000000000)", "-1"),
+            ("(ash 0 100000000000000000000)", "0"),
+        ]);
+    }
+
+    #[test]
+    fn test_invalid_inputs() {
+        let env = &mut Env::default();
+        // arguments are values: neither symbols nor lists are evaluated again
+        run("(setq k 16)", env).unwrap();
+        run("(setq code '(progn (setq k 100) 4))", env).unwrap();
+        check_errors(
+            env,
+            &[
+                ("(sqrt -1)", "type-error"),
+                ("(log 0)", "type-error"),
+                ("(log 8 1)", "type-error"),
+                ("(expt -8 1/3)", "type-error"),
+                ("(expt 0 -1)", "division-by-zero"),
+                ("(expt 0.0 -1)", "division-by-zero"),
+                ("(mod 1 0)", "division-by-zero"),
+                ("(floor 1.5 0.0)", "division-by-zero"),
+                ("(div 0.0 0)", "division-by-zero"),
+                ("(div 1 0.0)", "division-by-zero"),
+                ("(expt 2 4000000000)", "type-error"),
+                ("(expt 2/3 -20000000)", "type-error"),
+                ("(ash 1 4294967295)", "type-error"),
+                ("(ash 3 100000000000000000000)", "type-error"),
+                ("(logand 1.0 1)", "type-error"),
+                ("(gcd 1/2)", "type-error"),
+                ("(abs \"x\")", "type-error"),
+                ("(mod 1)", "program-error"),
+                ("(sqrt 'k)", "type-error"),
+                ("(sqrt code)", "type-error"),
+            ],
+        );
+        let err = run("(sqrt -1)", env).unwrap_err();
+        assert_eq!(
+            err.error.to_string(),
+            "type error: expected non-negative number, got -1"
+        );
+        assert_eq!(*run("k", env).unwrap(), Atom::Int(16));
+    }
+}
@@ -0,0 +1,295 @@
+use std::{cmp::Ordering, sync::Arc};
+
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_rational::BigRational;
+use num_traits::{ToPrimitive, Zero};
+
+use crate::{atom::Atom, lisp_error::LispError};
+
+/// A number of the numeric tower, taken out of its `Atom` for arithmetic.
+///
+/// From the lowest to the highest rank: fixnums, bignums, ratios and floats.
+/// Arithmetic on two numbers converts the lower-ranked one to the rank of the
+/// other (float contagion), and integer results too big for an `i64` become
+/// bignums instead of overflowing.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Number {
+    Int(i64),
+    Big(BigInt),
+    Ratio(BigRational),
+    Float(f64),
+}
+
+impl Number {
+    pub fn from_atom(v: &Atom) -> Option<Number> {
+        match v {
+            Atom::Int(n) => Some(Number::Int(*n)),
+            Atom::Big(n) => Some(Number::Big((**n).clone())),
+            Atom::Ratio(n) => Some(Number::Ratio((**n).clone())),
+            Atom::Num(n) => Some(Number::Float(*n)),
+            _ => None,
+        }
+    }
+
+    /// The atom for this number, in its canonical form: integers that fit an
+    /// `i64` are fixnums and ratios with a denominator of 1 are integers, so
+    /// equal exact numbers are equal atoms.
+    pub fn into_atom(self) -> Atom {
+        match self {
+            Number::Int(n) => Atom::Int(n),
+            Number::Big(n) => match n.to_i64() {
+                Some(n) => Atom::Int(n),
+                None => Atom::Big(Arc::new(n)),
+            },
+            Number::Ratio(n) if n.is_integer() => Number::Big(n.to_integer()).into_atom(),
+            Number::Ratio(n) => Atom::Ratio(Arc::new(n)),
+            Number::Float(n) => Atom::Num(n),
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            Number::Int(n) => *n as f64,
+            Number::Big(n) => n.to_f64().unwrap_or(f64::NAN),
+            Number::Ratio(n) => n.to_f64().unwrap_or(f64::NAN),
+            Number::Float(n) => *n,
+        }
+    }
+
+    fn to_ratio(&self) -> BigRational {
+        match self {
+            Number::Int(n) => BigRational::from_integer((*n).into()),
+            Number::Big(n) => BigRational::from_integer(n.clone()),
+            Number::Ratio(n) => n.clone(),
+            Number::Float(_) => unreachable!("floats are never converted to ratios"),
+        }
+    }
+
+    fn to_big(&self) -> BigInt {
+        match self {
+            Number::Int(n) => (*n).into(),
+            Number::Big(n) => n.clone(),
+            _ => unreachable!("only integers are converted to bignums"),
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        match self {
+            Number::Int(n) => *n == 0,
+            Number::Big(n) => n.is_zero(),
+            Number::Ratio(n) => n.is_zero(),
+            Number::Float(n) => *n == 0.0,
+        }
+    }
+
+    /// Whether an integer is even, `None` for other numbers.
+    pub fn is_even(&self) -> Option<bool> {
+        match self {
+            Number::Int(n) => Some(n % 2 == 0),
+            Number::Big(n) => Some(n.is_even()),
+            _ => None,
+        }
+    }
+
+    /// Apply the operation matching the common rank of `self` and `other`.
+    fn combine(
+        self,
+        other: Number,
+        fixnum: fn(i64, i64) -> Option<i64>,
+        bignum: fn(BigInt, BigInt) -> BigInt,
+        ratio: fn(BigRational, BigRational) -> BigRational,
+        float: fn(f64, f64) -> f64,
+    ) -> Number {
+        match (&self, &other) {
+            (Number::Float(_), _) | (_, Number::Float(_)) => {
+                Number::Float(float(self.to_f64(), other.to_f64()))
+            }
+            (Number::Ratio(_), _) | (_, Number::Ratio(_)) => {
+                Number::Ratio(ratio(self.to_ratio(), other.to_ratio()))
+            }
+            (Number::Int(a), Number::Int(b)) => match fixnum(*a, *b) {
+                Some(n) => Number::Int(n),
+                None => Number::Big(bignum(self.to_big(), other.to_big())),
+            },
+            _ => Number::Big(bignum(self.to_big(), other.to_big())),
+        }
+    }
+
+    pub fn add(self, other: Number) -> Number {
+        self.combine(
+            other,
+            i64::checked_add,
+            |a, b| a + b,
+            |a, b| a + b,
+            |a, b| a + b,
+        )
+    }
+
+    pub fn sub(self, other: Number) -> Number {
+        self.combine(
+            other,
+            i64::checked_sub,
+            |a, b| a - b,
+            |a, b| a - b,
+            |a, b| a - b,
+        )
+    }
+
+    pub fn mul(self, other: Number) -> Number {
+        self.combine(
+            other,
+            i64::checked_mul,
+            |a, b| a * b,
+            |a, b| a * b,
+            |a, b| a * b,
+        )
+    }
+
+    /// Division is exact unless a float is involved: dividing integers that
+    /// don't divide evenly gives a ratio. Dividing by zero is an error,
+    /// whether the divisor is exact or a float.
+    pub fn div(self, other: Number) -> Result<Number, LispError> {
+        if other.is_zero() {
+            return Err(LispError::DivisionByZero);
+        }
+        if matches!(
+            (&self, &other),
+            (Number::Float(_), _) | (_, Number::Float(_))
+        ) {
+            return Ok(Number::Float(self.to_f64() / other.to_f64()));
+        }
+        if let (Number::Int(a), Number::Int(b)) = (&self, &other) {
+            if let Some(0) = a.checked_rem(*b) {
+                return Ok(Number::Int(a / b));
+            }
+        }
+        Ok(Number::Ratio(self.to_ratio() / other.to_ratio()))
+    }
+
+    /// Compare by value across ranks; `None` only when a float is NaN.
+    ///
+    /// An exact number is compared with a float as the exact value of the
+    /// float, so `1/3` isn't equal to any float and bignums keep all their
+    /// digits.
+    pub fn compare(&self, other: &Number) -> Option<Ordering> {
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) => Some(a.cmp(b)),
+            (Number::Float(a), Number::Float(b)) => a.partial_cmp(b),
+            (Number::Float(a), b) => b.compare_float(*a).map(Ordering::reverse),
+            (a, Number::Float(b)) => a.compare_float(*b),
+            _ => Some(self.to_ratio().cmp(&other.to_ratio())),
+        }
+    }
+
+    /// Compare an exact number with a float.
+    fn compare_float(&self, float: f64) -> Option<Ordering> {
+        if float.is_nan() {
+            return None;
+        }
+        match BigRational::from_float(float) {
+            Some(float) => Some(self.to_ratio().cmp(&float)),
+            // infinities are beyond every exact number
+            None => Some(if float > 0.0 {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn num(v: &str) -> Number {
+        let atom = crate::lisp_parsing::parse(v);
+        Number::from_atom(&atom).unwrap()
+    }
+
+    #[test]
+    fn test_contagion() {
+        let cases = [
+            (num("1").add(num("2")), "3"),
+            (num("1").add(num("2.0")), "3.0"),
+            (num("1/2").add(num("1/2")), "1"),
+            (num("1/2").add(num("1")), "3/2"),
+            (num("1/2").add(num("0.25")), "0.75"),
+            (
+                num("9223372036854775807").add(num("1")),
+                "9223372036854775808",
+            ),
+            (
+                num("-9223372036854775808").sub(num("1")),
+                "-9223372036854775809",
+            ),
+            (
+                num("9223372036854775808").sub(num("1")),
+                "9223372036854775807",
+            ),
+            (
+                num("4294967296").mul(num("4294967296")),
+                "18446744073709551616",
+            ),
+            (num("2/3").mul(num("3/2")), "1"),
+        ];
+        for (res, expected) in cases {
+            assert_eq!(format!("{:?}", res.into_atom()), expected);
+        }
+    }
+
+    #[test]
+    fn test_division() {
+        let div = |a: &str, b: &str| format!("{:?}", num(a).div(num(b)).unwrap().into_atom());
+        assert_eq!(div("6", "3"), "2");
+        assert_eq!(div("1", "3"), "1/3");
+        assert_eq!(div("-2", "4"), "-1/2");
+        assert_eq!(div("1/3", "1/6"), "2");
+        assert_eq!(div("1", "4.0"), "0.25");
+        assert!(matches!(
+            num("1").div(num("0")),
+            Err(LispError::DivisionByZero)
+        ));
+        assert!(matches!(
+            num("1/2").div(num("0")),
+            Err(LispError::DivisionByZero)
+        ));
+        assert!(matches!(
+            num("1.0").div(num("0")),
+            Err(LispError::DivisionByZero)
+        ));
+        // i64::MIN / -1 overflows an i64
+        assert_eq!(div("-9223372036854775808", "-1"), "9223372036854775808");
+    }
+
+    #[test]
+    fn test_compare() {
+        assert_eq!(num("1").compare(&num("2")), Some(Ordering::Less));
+        assert_eq!(num("1/3").compare(&num("0.3")), Some(Ordering::Greater));
+        assert_eq!(num("2").compare(&num("2.0")), Some(Ordering::Equal));
+        assert_eq!(
+            num("100000000000000000000").compare(&num("99999999999999999999")),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(num("1").compare(&Number::Float(f64::NAN)), None);
+        // exact numbers compare with the exact value of a float
+        assert_eq!(
+            num("1/3").compare(&num("0.3333333333333333")),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(
+            num("9007199254740993").compare(&num("9007199254740992.0")),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(num("0.5").compare(&num("1/2")), Some(Ordering::Equal));
+        assert_eq!(
+            num("100000000000000000000").compare(&Number::Float(f64::INFINITY)),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            Number::Float(f64::NEG_INFINITY).compare(&num("-1/2")),
+            Some(Ordering::Less)
+        );
+    }
+}
@@ -0,0 +1,216 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    atom::{Atom, Fun, SAtom},
+    env::Env,
+    lisp_error::{EvalError, LispError},
+    lisp_eval::{eval, Args, EvalResult},
+    sexpr::SExpr,
+};
+
+/// If `v` is `(<name> <form>)`, return `<form>`.
+fn unary_form<'a>(v: &'a Atom, name: &str) -> Option<&'a SAtom> {
+    match v {
+        Atom::Cons(SExpr { car, cdr }) => match (&**car, &**cdr) {
+            (Atom::Sym(head), Atom::Cons(SExpr { car: arg, cdr: end }))
+                if head == name && **end == Atom::Nil =>
+            {
+                Some(arg)
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn list2(head: &str, form: SAtom) -> SAtom {
+    SAtom::new(Atom::Cons(
+        [SAtom::new(Atom::Sym(head.into())), form]
+            .into_iter()
+            .collect(),
+    ))
+}
+
+/// Build the list `items` terminated by `tail` instead of nil.
+fn list_with_tail(items: Vec<SAtom>, tail: SAtom) -> SAtom {
+    items
+        .into_iter()
+        .rev()
+        .fold(tail, |cdr, car| SAtom::new(Atom::Cons(SExpr { car, cdr })))
+}
+
+/// Expand the quasiquoted template `v`, nested `depth` backquotes deep.
+fn quasi(v: &SAtom, depth: usize, s: &mut Env) -> EvalResult {
+    if let Some(form) = unary_form(v, "unquote") {
+        return match depth {
+            1 => eval(form.clone(), s),
+            _ => Ok(list2("unquote", quasi(form, depth - 1, s)?)),
+        };
+    }
+    if let Some(form) = unary_form(v, "quasiquote") {
+        return Ok(list2("quasiquote", quasi(form, depth + 1, s)?));
+    }
+    if unary_form(v, "unquote-splicing").is_some() && depth == 1 {
+        return Err(LispError::syntax("quasiquote", ",@ must appear inside a list").into());
+    }
+
+    let Atom::Cons(_) = &**v else {
+        return Ok(v.clone());
+    };
+
+    let mut items = Vec::new();
+    let mut cur = v.clone();
+    let tail = loop {
+        match &*cur {
+            Atom::Nil => break cur.clone(),
+            // `(a . ,b)` reads as `(a unquote b)`: the tail itself is unquoted
+            Atom::Cons(_)
+                if unary_form(&cur, "unquote").is_some()
+                    || unary_form(&cur, "unquote-splicing").is_some() =>
+            {
+                break quasi(&cur, depth, s)?
+            }
+            Atom::Cons(SExpr { car, cdr }) => {
+                match unary_form(car, "unquote-splicing") {
+                    Some(form) if depth == 1 => {
+                        let spliced = eval(form.clone(), s)?;
+                        match &*spliced {
+                            Atom::Nil => {}
+                            Atom::Cons(list) => items.extend(list.iter()),
+                            _ => return Err(LispError::type_error("list", &spliced).into()),
+                        }
+                    }
+                    Some(form) => items.push(list2("unquote-splicing", quasi(form, depth - 1, s)?)),
+                    None => items.push(quasi(car, depth, s)?),
+                }
+                cur = cdr.clone();
+            }
+            _ => break cur.clone(),
+        }
+    };
+
+    Ok(list_with_tail(items, tail))
+}
+
+fn template(form: &'static str, args: &Args) -> Result<SAtom, EvalError> {
+    match args {
+        Args::S(SExpr { car, cdr }) if **cdr == Atom::Nil => Ok(car.clone()),
+        Args::S(args) => Err(LispError::arity(form, "1", args.iter().count()).into()),
+        Args::Nil => Err(LispError::arity(form, "1", 0).into()),
+    }
+}
+
+pub fn install(fun_map: &mut HashMap<String, Arc<Fun>>) {
+    // (quasiquote <template>)
+    let quasiquote_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
+        quasi(&template("quasiquote", args)?, 1, s)
+    }));
+
+    let unquote_ops = |form: &'static str| {
+        Fun::Native(Box::new(move |_: &mut Env, _: &Args| -> EvalResult {
+            Err(LispError::syntax(form, "not inside a quasiquote").into())
+        }))
+    };
+
+    fun_map.insert("quasiquote".into(), quasiquote_op.into());
+    fun_map.insert("unquote".into(), unquote_ops("unquote").into());
+    fun_map.insert(
+        "unquote-splicing".into(),
+        unquote_ops("unquote-splicing").into(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cons, env::Env, num, sexpr, sym, test_utils::run};
+
+    use super::*;
+
+    #[test]
+    fn test_quasiquote() {
+        let env = &mut Env::default();
+        run("(defvar b 2)", env).unwrap();
+        run("(defvar c (list 3 4))", env).unwrap();
+
+        assert_eq!(*run("`a", env).unwrap(), sym!("a"));
+        assert_eq!(*run("'(a b)", env).unwrap(), sexpr!(sym!("a"), sym!("b")));
+        assert_eq!(
+            *run("`(a ,b ,@c)", env).unwrap(),
+            sexpr!(sym!("a"), num!(2), num!(3), num!(4))
+        );
+        assert_eq!(
+            *run("`(,@c ,@nil ,(add b 1))", env).unwrap(),
+            sexpr!(num!(3), num!(4), num!(3))
+        );
+        assert_eq!(
+            *run("`((nested ,b) . tail)", env).unwrap(),
+            cons!(sexpr!(sym!("nested"), num!(2)), sym!("tail"))
+        );
+    }
+
+    #[test]
+    fn test_quasiquote_dotted() {
+        let env = &mut Env::default();
+        run("(defvar b 2)", env).unwrap();
+        run("(defvar c (list 3 4))", env).unwrap();
+
+        assert_eq!(*run("`(a . ,b)", env).unwrap(), cons!(sym!("a"), num!(2)));
+        assert_eq!(
+            *run("`(,@c . end)", env).unwrap(),
+            cons!(num!(3), cons!(num!(4), sym!("end")))
+        );
+        assert_eq!(
+            *run("`(a . ,c)", env).unwrap(),
+            sexpr!(sym!("a"), num!(3), num!(4))
+        );
+    }
+
+    #[test]
+    fn test_quasiquote_nesting() {
+        let env = &mut Env::default();
+        run("(defvar b 2)", env).unwrap();
+
+        // inner unquotes belong to the inner backquote
+        assert_eq!(
+            *run("`(a `(b ,(c ,b)))", env).unwrap(),
+            sexpr!(
+                sym!("a"),
+                sexpr!(
+                    sym!("quasiquote"),
+                    sexpr!(
+                        sym!("b"),
+                        sexpr!(sym!("unquote"), sexpr!(sym!("c"), num!(2)))
+                    )
+                )
+            )
+        );
+
+        let err = run(",b", env).unwrap_err();
+        assert!(matches!(
+            err.error,
+            LispError::Syntax {
+                form: "unquote",
+                ..
+            }
+        ));
+        let err = run("`(a ,@b)", env).unwrap_err();
+        assert!(matches!(
+            err.error,
+            LispError::Type {
+                expected: "list",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_quasiquote_macro() {
+        let env = &mut Env::default();
+        run(
+            "(defmacro my_unless (test body) `(if ,test nil ,body))",
+            env,
+        )
+        .unwrap();
+        assert_eq!(*run("(my_unless (eq 1 2) 5)", env).unwrap(), num!(5));
+    }
+}
@@ -0,0 +1,262 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use num_bigint::{BigInt, BigUint, Sign};
+use num_traits::{Signed, ToPrimitive};
+
+use crate::{
+    atom::{Atom, Fun, SAtom},
+    env::Env,
+    lisp_error::LispError,
+    lisp_eval::{Args, EvalResult},
+    nil,
+    number::Number,
+    t,
+};
+
+/// The special variable holding the state `random` uses by default.
+pub const RANDOM_STATE: &str = "*random-state*";
+
+/// Seed of the initial `*random-state*`, so runs are reproducible.
+const DEFAULT_SEED: u64 = 0x5eed;
+
+/// State of a xoshiro256** pseudo-random generator.
+///
+/// It only depends on its seed, never on OS entropy: the same seed always
+/// gives the same sequence.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RandomState([u64; 4]);
+
+impl RandomState {
+    /// Expand `seed` into a full state with splitmix64, as xoshiro recommends.
+    pub fn new(seed: u64) -> Self {
+        let mut x = seed;
+        let mut next = || {
+            x = x.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = x;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            z ^ (z >> 31)
+        };
+        RandomState([next(), next(), next(), next()])
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let s = &mut self.0;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
+    }
+
+    /// A uniform integer in `0..limit`, without modulo bias.
+    fn below_u64(&mut self, limit: u64) -> u64 {
+        // Lemire's multiply-and-reject method
+        let threshold = limit.wrapping_neg() % limit;
+        loop {
+            let product = u128::from(self.next_u64()) * u128::from(limit);
+            if product as u64 >= threshold {
+                return (product >> 64) as u64;
+            }
+        }
+    }
+
+    /// A uniform integer in `0..limit`, for a positive `limit` of any size.
+    pub fn below(&mut self, limit: &BigInt) -> BigInt {
+        if let Some(limit) = limit.to_u64() {
+            return self.below_u64(limit).into();
+        }
+        // draw as many bits as `limit` has until the number is below it
+        let bits = limit.bits();
+        let words = bits.div_ceil(64) as usize;
+        let top_mask = u64::MAX >> (words as u64 * 64 - bits);
+        loop {
+            let mut digits: Vec<u64> = (0..words).map(|_| self.next_u64()).collect();
+            digits[words - 1] &= top_mask;
+            let n = BigInt::from_biguint(Sign::Plus, BigUint::new(to_u32s(&digits)));
+            if &n < limit {
+                return n;
+            }
+        }
+    }
+
+    /// A uniform float in `[0, 1)`.
+    pub fn unit_float(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Little-endian 64-bit digits as little-endian 32-bit ones.
+fn to_u32s(digits: &[u64]) -> Vec<u32> {
+    digits
+        .iter()
+        .flat_map(|d| [*d as u32, (*d >> 32) as u32])
+        .collect()
+}
+
+/// The initial value of `*random-state*`.
+pub fn default_state() -> SAtom {
+    state_atom(RandomState::new(DEFAULT_SEED))
+}
+
+fn state_atom(state: RandomState) -> SAtom {
+    Atom::RandomState(Arc::new(Mutex::new(state))).into()
+}
+
+/// The random state `v` holds, or a type error.
+fn state_of(v: &SAtom) -> Result<&Arc<Mutex<RandomState>>, LispError> {
+    match &**v {
+        Atom::RandomState(state) => Ok(state),
+        _ => Err(LispError::type_error("random-state", v)),
+    }
+}
+
+/// The current value of `*random-state*`.
+fn current_state(s: &Env) -> Result<SAtom, LispError> {
+    s.get_val(RANDOM_STATE)
+        .ok_or_else(|| LispError::UnboundVariable(RANDOM_STATE.into()))
+}
+
+pub fn install(fun_map: &mut HashMap<String, Arc<Fun>>) {
+    // (random <limit> [<state>]): an integer in [0, <limit>) for a positive
+    // integer, a float in [0, <limit>) for a positive float; it advances
+    // <state>, `*random-state*` by default
+    let random_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
+        let args = args.to_vec();
+        let (limit, state) = match &args[..] {
+            [limit] => (limit, current_state(s)?),
+            [limit, state] => (limit, state.clone()),
+            _ => return Err(LispError::arity("random", "1 or 2", args.len()).into()),
+        };
+        let mut state = state_of(&state)?.lock().unwrap();
+
+        let n = match Number::from_atom(limit) {
+            Some(Number::Int(n)) if n > 0 => Number::Big(state.below(&n.into())),
+            Some(Number::Big(n)) if n.is_positive() => Number::Big(state.below(&n)),
+            Some(Number::Float(n)) if n > 0.0 && n.is_finite() => {
+                Number::Float(state.unit_float() * n)
+            }
+            _ => return Err(LispError::type_error("positive integer or float", limit).into()),
+        };
+        Ok(n.into_atom().into())
+    }));
+
+    // (make-random-state [<seed-or-state>]): a new state seeded with an
+    // integer, or a copy of a state (of `*random-state*` without an arg)
+    let make_random_state_op = Fun::Native(Box::new(|s: &mut Env, args: &Args| -> EvalResult {
+        let args = args.to_vec();
+        let from = match &args[..] {
+            [] => current_state(s)?,
+            [from] if **from == Atom::Nil => current_state(s)?,
+            [from] => from.clone(),
+            _ => return Err(LispError::arity("make-random-state", "0 or 1", args.len()).into()),
+        };
+
+        let state = match &*from {
+            // seeds are taken modulo 2^64, so negative ones work too
+            Atom::Int(seed) => RandomState::new(*seed as u64),
+            Atom::Big(seed) => {
+                let low = &**seed & BigInt::from(u64::MAX);
+                RandomState::new(low.to_u64().unwrap())
+            }
+            _ => state_of(&from)?.lock().unwrap().clone(),
+        };
+        Ok(state_atom(state))
+    }));
+
+    // (random-state-p <v>)
+    let random_state_p_op = Fun::Native(Box::new(|_: &mut Env, args: &Args| -> EvalResult {
+        let args = args.to_vec();
+        match &args[..] {
+            [v] if matches!(&**v, Atom::RandomState(_)) => Ok(t!().into()),
+            [_] => Ok(nil!().into()),
+            _ => Err(LispError::arity("random-state-p", "1", args.len()).into()),
+        }
+    }));
+
+    fun_map.insert("random".into(), random_op.into());
+    fun_map.insert("make-random-state".into(), make_random_state_op.into());
+    fun_map.insert("random-state-p".into(), random_state_p_op.into());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{check_errors, run};
+
+    use super::*;
+
+    #[test]
+    fn test_generator() {
+        // the same seed gives the same sequence
+        let (mut a, mut b) = (RandomState::new(42), RandomState::new(42));
+        let xs: Vec<u64> = (0..8).map(|_| a.next_u64()).collect();
+        let ys: Vec<u64> = (0..8).map(|_| b.next_u64()).collect();
+        assert_eq!(xs, ys);
+        assert_ne!(RandomState::new(43).next_u64(), xs[0]);
+
+        let mut state = RandomState::new(1);
+        let mut seen = [false; 6];
+        for _ in 0..1000 {
+            let n = state.below_u64(6);
+            seen[n as usize] = true;
+            let f = state.unit_float();
+            assert!((0.0..1.0).contains(&f));
+        }
+        assert_eq!(seen, [true; 6]);
+
+        let limit: BigInt = BigInt::from(1) << 100;
+        let limit = limit + 3;
+        for _ in 0..100 {
+            let n = state.below(&limit);
+            assert!(!n.is_negative() && n < limit);
+        }
+    }
+
+    #[test]
+    fn test_random_builtins() {
+        let env = &mut Env::default();
+        let draw = "(list (random 1000000) (random 1000000) (random 1.0))";
+
+        // runs are reproducible: a fresh env starts from the same state
+        let first = run(draw, env).unwrap();
+        assert_eq!(*run(draw, &mut Env::default()).unwrap(), *first);
+        assert_ne!(*run(draw, env).unwrap(), *first);
+
+        // copies are independent and replay the original's sequence
+        run("(defvar saved (make-random-state))", env).unwrap();
+        let next = run(draw, env).unwrap();
+        assert_eq!(
+            *run(&format!("(let ((*random-state* saved)) {draw})"), env).unwrap(),
+            *next
+        );
+
+        let seeded =
+            "(let ((s (make-random-state 7))) (list (random 10 s) (random 10 s) (random 10 s)))";
+        assert_eq!(*run(seeded, env).unwrap(), *run(seeded, env).unwrap());
+
+        let res = run("(random 100000000000000000000000)", env).unwrap();
+        assert!(matches!(&*res, Atom::Int(_) | Atom::Big(_)));
+        assert_eq!(
+            *run("(random-state-p *random-state*)", env).unwrap(),
+            Atom::T
+        );
+        assert_eq!(*run("(random-state-p 1)", env).unwrap(), Atom::Nil);
+
+        check_errors(
+            env,
+            &[
+                ("(random 0)", "type-error"),
+                ("(random -1.5)", "type-error"),
+                ("(random 1/2)", "type-error"),
+                ("(random 10 5)", "type-error"),
+            ],
+        );
+    }
+}
@@ -0,0 +1,75 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Weak},
+};
+
+use crate::atom::{Atom, SAtom};
+
+/// Location of a parsed form in its source text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub file: Option<Arc<str>>,
+    /// 1-based line number.
+    pub line: usize,
+    /// 1-based column, counted in chars.
+    pub col: usize,
+    /// Byte offset of the form in the source.
+    pub offset: usize,
+    /// Length of the form in bytes.
+    pub len: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}:{}", file, self.line, self.col),
+            None => write!(f, "{}:{}", self.line, self.col),
+        }
+    }
+}
+
+/// Side table from parsed atoms to their spans, keyed by `SAtom` pointer.
+///
+/// Entries hold a `Weak` to the atom, which keeps its allocation (and thus
+/// its address) reserved, so a key can never be reused by another atom.
+#[derive(Default)]
+struct SourceMap {
+    spans: HashMap<usize, (Weak<Atom>, Span)>,
+    watermark: usize,
+}
+
+impl SourceMap {
+    const MIN_WATERMARK: usize = 1024;
+
+    fn record(&mut self, atom: &SAtom, span: Span) {
+        if self.spans.len() >= self.watermark {
+            // Forget about atoms that are not referenced anymore.
+            self.spans.retain(|_, (atom, _)| atom.strong_count() > 0);
+            self.watermark = (self.spans.len() * 2).max(Self::MIN_WATERMARK);
+        }
+        self.spans
+            .insert(Arc::as_ptr(atom) as usize, (Arc::downgrade(atom), span));
+    }
+
+    fn lookup(&self, atom: &SAtom) -> Option<Span> {
+        self.spans
+            .get(&(Arc::as_ptr(atom) as usize))
+            .map(|(_, span)| span.clone())
+    }
+}
+
+thread_local! {
+    static SOURCE_MAP: RefCell<SourceMap> = RefCell::new(SourceMap::default());
+}
+
+/// Remember where `atom` was read from.
+pub fn record(atom: &SAtom, span: Span) {
+    SOURCE_MAP.with(|map| map.borrow_mut().record(atom, span));
+}
+
+/// Source location of `atom`, if it was produced by the reader.
+pub fn lookup(atom: &SAtom) -> Option<Span> {
+    SOURCE_MAP.with(|map| map.borrow().lookup(atom))
+}
@@ -0,0 +1,360 @@
+use std::{cmp::Ordering, collections::HashMap, sync::Arc};
+
+use crate::{
+    atom::{Atom, Fun, SAtom},
+    env::{check_arity, list_items, Env},
+    lisp_error::LispError,
+    lisp_eval::{Args, EvalResult},
+    lisp_parsing::parse_number,
+    nil,
+    number::Number,
+    t,
+};
+
+fn string(v: &SAtom) -> Result<&str, LispError> {
+    match &**v {
+        Atom::Str(s) => Ok(s),
+        _ => Err(LispError::type_error("string", v)),
+    }
+}
+
+fn str_atom(s: impl Into<String>) -> SAtom {
+    Atom::Str(s.into()).into()
+}
+
+/// The byte offset of the character at index `idx` of `s`; the length of
+/// `s` is a valid index too, for the end of the string.
+fn char_offset(s: &str, idx: &SAtom) -> Result<usize, LispError> {
+    let out_of_range = || LispError::type_error("index within the string", idx);
+    let Atom::Int(n) = &**idx else {
+        return Err(out_of_range());
+    };
+    let n = usize::try_from(*n).map_err(|_| out_of_range())?;
+    s.char_indices()
+        .map(|(offset, _)| offset)
+        .chain([s.len()])
+        .nth(n)
+        .ok_or_else(out_of_range)
+}
+
+/// The index, in characters, of the byte offset `offset` of `s`.
+fn char_index(s: &str, offset: usize) -> SAtom {
+    Atom::Int(s[..offset].chars().count() as i64).into()
+}
+
+pub fn install(fun_map: &mut HashMap<String, Arc<Fun>>) {
+    // (concat <string>...)
+    let concat_ops = |name: &'static str| {
+        Fun::Native(Box::new(move |_: &mut Env, args: &Args| -> EvalResult {
+            let mut out = String::new();
+            for v in check_arity(name, args, 0, usize::MAX)? {
+                out.push_str(string(&v)?);
+            }
+            Ok(str_atom(out))
+        }))
+    };
+
+    // (length <string-or-list>): characters of a string, elements of a list
+    let length_op = Fun::Native(Box::new(|_: &mut Env, args: &Args| -> EvalResult {
+        let v = check_arity("length", args, 1, 1)?.remove(0);
+        let len = match &*v {
+            Atom::Str(s) => s.chars().count(),
+            Atom::Nil | Atom::Cons(_) => list_items(&v)?.len(),
+            _ => return Err(LispError::type_error("string or list", &v).into()),
+        };
+        Ok(Atom::Int(len as i64).into())
+    }));
+
+    // (substring <string> <start> [<end>]), with character indices; <end>
+    // defaults to the end of the string
+    let substring_op = Fun::Native(Box::new(|_: &mut Env, args: &Args| -> EvalResult {
+        let args = check_arity("substring", args, 2, 3)?;
+        let s = string(&args[0])?;
+        let start = char_offset(s, &args[1])?;
+        let end = match args.get(2) {
+            Some(end) if **end != Atom::Nil => char_offset(s, end)?,
+            _ => s.len(),
+        };
+        if start > end {
+            return Err(LispError::type_error("end index not before the start", &args[2]).into());
+        }
+        Ok(str_atom(&s[start..end]))
+    }));
+
+    // (string-upcase <string>) / (string-downcase <string>)
+    let case_ops = |name: &'static str, convert: fn(&str) -> String| {
+        Fun::Native(Box::new(move |_: &mut Env, args: &Args| -> EvalResult {
+            let v = check_arity(name, args, 1, 1)?.remove(0);
+            Ok(str_atom(convert(string(&v)?)))
+        }))
+    };
+
+    // (string-trim <string> [<chars>]) removes the characters of <chars>,
+    // whitespace by default, from both ends
+    let trim_op = Fun::Native(Box::new(|_: &mut Env, args: &Args| -> EvalResult {
+        let args = check_arity("string-trim", args, 1, 2)?;
+        let s = string(&args[0])?;
+        let trimmed = match args.get(1) {
+            Some(chars) => {
+                let chars = string(chars)?;
+                s.trim_matches(|c| chars.contains(c))
+            }
+            None => s.trim(),
+        };
+        Ok(str_atom(trimmed))
+    }));
+
+    // (split <string> [<separator>]) splits on <separator>, or on runs of
+    // whitespace without one; an empty separator splits into characters
+    let split_op = Fun::Native(Box::new(|_: &mut Env, args: &Args| -> EvalResult {
+        let args = check_arity("split", args, 1, 2)?;
+        let s = string(&args[0])?;
+        let parts: Vec<String> = match args.get(1) {
+            None => s.split_whitespace().map(String::from).collect(),
+            Some(sep) => match string(sep)? {
+                "" => s.chars().map(String::from).collect(),
+                sep => s.split(sep).map(String::from).collect(),
+            },
+        };
+        if parts.is_empty() {
+            return Ok(nil!().into());
+        }
+        Ok(Atom::Cons(parts.into_iter().map(Atom::Str).collect()).into())
+    }));
+
+    // (join <list> [<separator>]) concatenates a list of strings, with
+    // <separator> between them
+    let join_op = Fun::Native(Box::new(|_: &mut Env, args: &Args| -> EvalResult {
+        let args = check_arity("join", args, 1, 2)?;
+        let sep = match args.get(1) {
+            Some(sep) => string(sep)?,
+            None => "",
+        };
+        let items = list_items(&args[0])?;
+        let parts = items.iter().map(string).collect::<Result<Vec<_>, _>>()?;
+        Ok(str_atom(parts.join(sep)))
+    }));
+
+    // (string-search <needle> <haystack> [<start>]): the character index of
+    // the first <needle> in <haystack> from <start>, or nil
+    let search_ops = |name: &'static str| {
+        Fun::Native(Box::new(move |_: &mut Env, args: &Args| -> EvalResult {
+            let args = check_arity(name, args, 2, 3)?;
+            let (needle, haystack) = (string(&args[0])?, string(&args[1])?);
+            let start = match args.get(2) {
+                Some(start) => char_offset(haystack, start)?,
+                None => 0,
+            };
+            Ok(match haystack[start..].find(needle) {
+                Some(offset) => char_index(haystack, start + offset),
+                None => nil!().into(),
+            })
+        }))
+    };
+
+    // (string-replace <from> <to> <string>) replaces every <from> in <string>
+    let replace_op = Fun::Native(Box::new(|_: &mut Env, args: &Args| -> EvalResult {
+        let args = check_arity("string-replace", args, 3, 3)?;
+        let (from, to, s) = (string(&args[0])?, string(&args[1])?, string(&args[2])?);
+        if from.is_empty() {
+            return Err(LispError::type_error("non-empty string", &args[0]).into());
+        }
+        Ok(str_atom(s.replace(from, to)))
+    }));
+
+    // (string= <string>...) and friends, comparing by code point; `string/=`
+    // holds when no two strings are equal, the others between neighbours
+    let compare_ops = |name: &'static str, all_pairs: bool, test: fn(Ordering) -> bool| {
+        Fun::Native(Box::new(move |_: &mut Env, args: &Args| -> EvalResult {
+            let args = check_arity(name, args, 1, usize::MAX)?;
+            let strs = args.iter().map(string).collect::<Result<Vec<_>, _>>()?;
+            let holds = |a: &str, b: &str| test(a.cmp(b));
+            let res = match all_pairs {
+                true => strs
+                    .iter()
+                    .enumerate()
+                    .all(|(idx, a)| strs[idx + 1..].iter().all(|b| holds(a, b))),
+                false => strs.windows(2).all(|pair| holds(pair[0], pair[1])),
+            };
+            Ok(if res { t!() } else { nil!() }.into())
+        }))
+    };
+
+    // (string->number <string>): the number the reader reads the string as,
+    // ignoring surrounding whitespace, or nil
+    let string_to_number_op = Fun::Native(Box::new(|_: &mut Env, args: &Args| -> EvalResult {
+        let v = check_arity("string->number", args, 1, 1)?.remove(0);
+        Ok(parse_number(string(&v)?.trim()).unwrap_or(nil!()).into())
+    }));
+
+    // (number->string <n>), in the syntax the reader reads back
+    let number_to_string_op = Fun::Native(Box::new(|_: &mut Env, args: &Args| -> EvalResult {
+        let v = check_arity("number->string", args, 1, 1)?.remove(0);
+        if Number::from_atom(&v).is_none() {
+            return Err(LispError::type_error("number", &v).into());
+        }
+        Ok(str_atom(format!("{:?}", v)))
+    }));
+
+    // (symbol-name <symbol>)
+    let symbol_name_op = Fun::Native(Box::new(|_: &mut Env, args: &Args| -> EvalResult {
+        let v = check_arity("symbol-name", args, 1, 1)?.remove(0);
+        match &*v {
+            Atom::Sym(name) => Ok(str_atom(name.as_str())),
+            Atom::Nil => Ok(str_atom("nil")),
+            Atom::T => Ok(str_atom("t")),
+            _ => Err(LispError::type_error("symbol", &v).into()),
+        }
+    }));
+
+    // (intern <string>): the symbol named <string>
+    let intern_op = Fun::Native(Box::new(|_: &mut Env, args: &Args| -> EvalResult {
+        let v = check_arity("intern", args, 1, 1)?.remove(0);
+        Ok(match string(&v)? {
+            "nil" => nil!(),
+            "t" => t!(),
+            name => Atom::Sym(name.into()),
+        }
+        .into())
+    }));
+
+    fun_map.insert("concat".into(), concat_ops("concat").into());
+    fun_map.insert("string-append".into(), concat_ops("string-append").into());
+    fun_map.insert("length".into(), length_op.into());
+    fun_map.insert("substring".into(), substring_op.into());
+    fun_map.insert(
+        "string-upcase".into(),
+        case_ops("string-upcase", str::to_uppercase).into(),
+    );
+    fun_map.insert(
+        "string-downcase".into(),
+        case_ops("string-downcase", str::to_lowercase).into(),
+    );
+    fun_map.insert("string-trim".into(), trim_op.into());
+    fun_map.insert("split".into(), split_op.into());
+    fun_map.insert("join".into(), join_op.into());
+    fun_map.insert("string-search".into(), search_ops("string-search").into());
+    fun_map.insert("index".into(), search_ops("index").into());
+    fun_map.insert("string-replace".into(), replace_op.into());
+    fun_map.insert(
+        "string=".into(),
+        compare_ops("string=", false, Ordering::is_eq).into(),
+    );
+    fun_map.insert(
+        "string/=".into(),
+        compare_ops("string/=", true, Ordering::is_ne).into(),
+    );
+    fun_map.insert(
+        "string<".into(),
+        compare_ops("string<", false, Ordering::is_lt).into(),
+    );
+    fun_map.insert(
+        "string>".into(),
+        compare_ops("string>", false, Ordering::is_gt).into(),
+    );
+    fun_map.insert(
+        "string<=".into(),
+        compare_ops("string<=", false, Ordering::is_le).into(),
+    );
+    fun_map.insert(
+        "string>=".into(),
+        compare_ops("string>=", false, Ordering::is_ge).into(),
+    );
+    fun_map.insert("string->number".into(), string_to_number_op.into());
+    fun_map.insert("number->string".into(), number_to_string_op.into());
+    fun_map.insert("symbol-name".into(), symbol_name_op.into());
+    fun_map.insert("intern".into(), intern_op.into());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{check, check_errors};
+
+    use super::*;
+
+    #[test]
+    fn test_unicode_strings() {
+        check(&[
+            (r#"(concat "héllo" ", " "wörld")"#, r#""héllo, wörld""#),
+            (r#"(string-append)"#, r#""""#),
+            (r#"(length "naïve 🦀")"#, "7"),
+            ("(length '(1 2 3))", "3"),
+            ("(length nil)", "0"),
+            (r#"(substring "naïve 🦀" 2 5)"#, r#""ïve""#),
+            (r#"(substring "naïve 🦀" 6)"#, r#""🦀""#),
+            (r#"(substring "abc" 3)"#, r#""""#),
+            (r#"(string-upcase "straße")"#, r#""STRASSE""#),
+            (r#"(string-downcase "ÀÉÎ")"#, r#""àéî""#),
+            (r#"(string-trim "  mañana  ")"#, r#""mañana""#),
+            (r#"(string-trim "¡¿hola?!" "¡¿?!")"#, r#""hola""#),
+        ]);
+    }
+
+    #[test]
+    fn test_split_join_search() {
+        check(&[
+            (r#"(split "  a  bc d ")"#, r#"("a" "bc" "d")"#),
+            (r#"(split "a,,b" ",")"#, r#"("a" "" "b")"#),
+            (r#"(split "日本" "")"#, r#"("日" "本")"#),
+            (r#"(split "   ")"#, "Nil"),
+            (r#"(join '("a" "b" "c") ", ")"#, r#""a, b, c""#),
+            (r#"(join '("a" "b"))"#, r#""ab""#),
+            (r#"(join nil "-")"#, r#""""#),
+            (r#"(string-search "é" "café crème")"#, "3"),
+            (r#"(string-search "è" "café crème" 5)"#, "7"),
+            (r#"(index "z" "café")"#, "Nil"),
+            (r#"(string-search "" "abc" 3)"#, "3"),
+            (
+                r#"(string-replace "ö" "oe" "Köln, Göttingen")"#,
+                r#""Koeln, Goettingen""#,
+            ),
+        ]);
+    }
+
+    #[test]
+    fn test_comparisons_and_conversions() {
+        check(&[
+            (r#"(string= "a" "a" "a")"#, "T"),
+            (r#"(string= "a" "A")"#, "Nil"),
+            (r#"(string/= "a" "b" "a")"#, "Nil"),
+            (r#"(string< "apple" "banana" "cherry")"#, "T"),
+            (r#"(string< "z" "é")"#, "T"),
+            (r#"(string>= "b" "b" "a")"#, "T"),
+            (r#"(string->number "42")"#, "42"),
+            (r#"(string->number " -1/2 ")"#, "-1/2"),
+            (r#"(string->number "1.5e3")"#, "1500.0"),
+            (
+                r#"(string->number "100000000000000000000")"#,
+                "100000000000000000000",
+            ),
+            (r#"(string->number "12abc")"#, "Nil"),
+            (r#"(string->number "1/0")"#, "Nil"),
+            ("(number->string 2/4)", r#""1/2""#),
+            ("(number->string 2.0)", r#""2.0""#),
+            ("(symbol-name 'café)", r#""café""#),
+            ("(symbol-name nil)", r#""nil""#),
+            (r#"(intern "café")"#, "café"),
+            (r#"(eq (intern "nil") nil)"#, "T"),
+        ]);
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        let env = &mut Env::default();
+        check_errors(
+            env,
+            &[
+                (r#"(concat "a" 1)"#, "type-error"),
+                ("(length 5)", "type-error"),
+                (r#"(substring "abc" 4)"#, "type-error"),
+                (r#"(substring "abc" -1)"#, "type-error"),
+                (r#"(substring "abc" 2 1)"#, "type-error"),
+                (r#"(join '("a" 1))"#, "type-error"),
+                (r#"(string-replace "" "x" "abc")"#, "type-error"),
+                ("(number->string 'a)", "type-error"),
+                (r#"(symbol-name "a")"#, "type-error"),
+                ("(string-upcase)", "program-error"),
+            ],
+        );
+    }
+}
@@ -0,0 +1,31 @@
+//! Helpers shared by the unit tests of the builtin modules.
+
+use crate::{
+    env::Env,
+    lisp_eval::{eval, EvalResult},
+    lisp_parsing::parse,
+};
+
+/// Read and evaluate `src`.
+pub fn run(src: &str, env: &mut Env) -> EvalResult {
+    eval(parse(src).into(), env)
+}
+
+/// Evaluate each source of `cases` in turn, in one env, checking the printed
+/// value against the expected one.
+pub fn check(cases: &[(&str, &str)]) {
+    let env = &mut Env::default();
+    for (src, expected) in cases {
+        let res = run(src, env).unwrap();
+        assert_eq!(format!("{:?}", res), *expected, "{src}");
+    }
+}
+
+/// Evaluate each source of `cases` in `env`, checking it fails with an error
+/// of the expected kind.
+pub fn check_errors(env: &mut Env, cases: &[(&str, &str)]) {
+    for (src, kind) in cases {
+        let err = run(src, env).unwrap_err();
+        assert_eq!(err.error.kind_name(), *kind, "{src}");
+    }
+}
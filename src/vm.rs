@@ -0,0 +1,891 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    mem,
+    sync::Arc,
+};
+
+use crate::{
+    atom::{Atom, Fun, SAtom},
+    compiler::{compile, Op, Proto, Slot},
+    conditions, cons,
+    control::{block_exit, go_exit, tag_name},
+    env::{case_matches, designated_fun, Binding, Env, Scope},
+    lisp_error::{EvalError, Exit, LispError},
+    lisp_eval::{frame, run_to_vm, Args, EvalResult, Extent, Tail, TailResult, TAIL_FRAMES},
+    nil, num,
+    sexpr::SExpr,
+    t,
+};
+
+/// A function compiled for the VM, with the values it closed over.
+pub struct Closure {
+    pub proto: Arc<Proto>,
+    /// Bindings of the captured slots of `proto`, shared with the frame that
+    /// created the closure.
+    pub captured: Vec<Binding>,
+    /// Lexical bindings of the interpreter when the closure was created.
+    pub val: Scope,
+}
+
+/// The call that entered a frame, for backtraces.
+struct Call {
+    form: SAtom,
+    /// Whether the form called the closure itself, rather than through a
+    /// builtin like `funcall`.
+    direct: bool,
+}
+
+struct Frame {
+    proto: Arc<Proto>,
+    pc: usize,
+    /// Stack index of the first operand. The callee sits just below it.
+    base: usize,
+    /// Local slots, named by the `slots` of the proto.
+    locals: Vec<Binding>,
+    /// Caller bindings to restore on return.
+    saved_val: Option<Scope>,
+    call: Option<Call>,
+    /// Frames this one replaced through tail calls, newest last.
+    tail_frames: VecDeque<(SAtom, Atom)>,
+    /// The blocks and tagbodies the frame is in, innermost last.
+    handlers: Vec<Handler>,
+}
+
+/// A compiled `block` or `tagbody` being run.
+struct Handler {
+    marker: Binding,
+    /// Stack height it was entered at.
+    height: usize,
+    /// The `Op::Block` or `Op::Tagbody` that entered it.
+    op: Op,
+}
+
+impl Frame {
+    /// The call form and the call with evaluated arguments.
+    fn describe(&self) -> Option<(SAtom, Atom)> {
+        let call = self.call.as_ref()?;
+        let Atom::Cons(SExpr { car, .. }) = &*call.form else {
+            return None;
+        };
+        if !call.direct {
+            return Some((call.form.clone(), (*call.form).clone()));
+        }
+
+        let args = &self.locals[..self.proto.nparams];
+        let args = match args.is_empty() {
+            true => Atom::Nil,
+            false => Atom::Cons(args.iter().map(Binding::get).collect()),
+        };
+        Some((call.form.clone(), cons!(car.clone(), args)))
+    }
+
+    /// Index of the handler with `marker`.
+    fn handler(&self, marker: &Binding) -> Option<usize> {
+        self.handlers.iter().rposition(|h| h.marker.same(marker))
+    }
+
+    /// Leave the handlers inside `handlers[idx]` and unwind the stack to
+    /// its height.
+    fn unwind_to(&mut self, stack: &mut Vec<SAtom>, idx: usize) {
+        for handler in self.handlers.drain(idx + 1..) {
+            handler.marker.set(nil!().into());
+        }
+        stack.truncate(self.handlers[idx].height);
+    }
+
+    /// Exit with `value` to the block of `handlers[idx]`.
+    fn return_to(&mut self, stack: &mut Vec<SAtom>, idx: usize, value: SAtom) {
+        self.unwind_to(stack, idx);
+        stack.push(value);
+        let Op::Block(_, end) = self.handlers[idx].op else {
+            unreachable!()
+        };
+        self.pc = end;
+    }
+
+    /// Go to `tag` in the tagbody of `handlers[idx]`.
+    fn go_to(&mut self, stack: &mut Vec<SAtom>, idx: usize, tag: &str) {
+        self.unwind_to(stack, idx);
+        let Op::Tagbody(targets) = self.handlers[idx].op else {
+            unreachable!()
+        };
+        let slots = &self.proto.slots;
+        let target = self.proto.targets[targets]
+            .iter()
+            .find(|(slot, _)| matches!(&slots[*slot], Slot::Tag(t) if t == tag));
+        self.pc = target.unwrap().1;
+    }
+
+    fn leave_innermost(&mut self) {
+        let handler = self.handlers.pop().unwrap();
+        handler.marker.set(nil!().into());
+    }
+
+    /// Leave the handlers of the frame, which is done.
+    fn leave(&mut self) {
+        for handler in self.handlers.drain(..) {
+            handler.marker.set(nil!().into());
+        }
+    }
+}
+
+#[derive(Default)]
+struct Vm {
+    stack: Vec<SAtom>,
+    frames: Vec<Frame>,
+    /// Extents a form left to the interpreter was in when it handed back the
+    /// call it ended with, by the index of the frame that runs the call in
+    /// them, innermost last. They are closed when that frame returns.
+    extents: Vec<(usize, Extent)>,
+}
+
+/// The protos forms were compiled to, by form address, so a form evaluated
+/// again, like the body of a loop the interpreter runs, or a lambda form
+/// passed to `funcall`, is compiled once. Holding the form keeps its address
+/// from being reused. Defining a macro, which can change how forms expand,
+/// empties it, and so does filling it up, so that forms made at run time
+/// don't pile up.
+#[derive(Clone, Default)]
+pub struct ProtoCache {
+    protos: HashMap<usize, (SAtom, Arc<Proto>)>,
+    /// The macros the protos were compiled with.
+    macros: Option<Arc<HashMap<String, Arc<Fun>>>>,
+}
+
+impl ProtoCache {
+    const CAPACITY: usize = 4096;
+
+    /// The proto of `form`, if it was compiled with the `macros` and local
+    /// functions of now.
+    fn get(
+        &self,
+        form: &SAtom,
+        macros: &Arc<HashMap<String, Arc<Fun>>>,
+        val: &Scope,
+    ) -> Option<Arc<Proto>> {
+        if !self.macros.as_ref().is_some_and(|m| Arc::ptr_eq(m, macros)) {
+            return None;
+        }
+        let (_, proto) = self.protos.get(&(Arc::as_ptr(form) as usize))?;
+        let same_shadowing = proto
+            .macros
+            .iter()
+            .all(|(name, shadowed)| val.get_fun(name).is_some() == *shadowed);
+        same_shadowing.then(|| proto.clone())
+    }
+
+    fn insert(&mut self, form: &SAtom, proto: Arc<Proto>, macros: &Arc<HashMap<String, Arc<Fun>>>) {
+        let same_macros = self.macros.as_ref().is_some_and(|m| Arc::ptr_eq(m, macros));
+        if !same_macros || self.protos.len() >= Self::CAPACITY {
+            self.protos.clear();
+            self.macros = Some(macros.clone());
+        }
+        let key = Arc::as_ptr(form) as usize;
+        self.protos.insert(key, (form.clone(), proto));
+    }
+}
+
+/// Compile `v` to bytecode and run it.
+pub fn eval(v: SAtom, s: &mut Env) -> EvalResult {
+    let proto = compiled(&v, s).map_err(|e| conditions::signal_error(s, e.at(&v)))?;
+    run_proto(proto, s)
+}
+
+/// The proto of `form`, compiled unless `s.compiled` has it.
+fn compiled(form: &SAtom, s: &mut Env) -> Result<Arc<Proto>, EvalError> {
+    if let Some(proto) = s.compiled.get(form, &s.macros, &s.val) {
+        return Ok(proto);
+    }
+    let proto = compile(form, s)?;
+    s.compiled.insert(form, proto.clone(), &s.macros);
+    Ok(proto)
+}
+
+/// Run the top-level form compiled to `proto`.
+fn run_proto(proto: Arc<Proto>, s: &mut Env) -> EvalResult {
+    let locals = vec![Binding::default(); proto.slots.len()];
+    let mut vm = Vm {
+        stack: vec![SAtom::new(Atom::Nil)],
+        frames: vec![Frame {
+            proto,
+            pc: 0,
+            base: 1,
+            locals,
+            saved_val: None,
+            call: None,
+            tail_frames: VecDeque::new(),
+            handlers: Vec::new(),
+        }],
+        ..Default::default()
+    };
+    vm.run(s)
+}
+
+/// Call `closure` from outside the VM.
+pub fn call(closure: &Arc<Closure>, args: Vec<SAtom>, s: &mut Env) -> EvalResult {
+    let mut vm = Vm {
+        stack: vec![SAtom::new(Atom::Nil)],
+        ..Default::default()
+    };
+    let argc = args.len();
+    vm.stack.extend(args);
+    vm.enter(s, closure.clone(), argc, None, false)?;
+    vm.run(s)
+}
+
+impl Vm {
+    fn run(&mut self, s: &mut Env) -> EvalResult {
+        loop {
+            let err = match self.exec(s) {
+                Ok(value) => return Ok(value),
+                Err(err) => err,
+            };
+            if let Some(value) = self.unwind(s, err)? {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn exec(&mut self, s: &mut Env) -> EvalResult {
+        loop {
+            let frame = self.frames.last_mut().unwrap();
+            let op = frame.proto.code[frame.pc];
+            frame.pc += 1;
+
+            match op {
+                Op::Const(idx) => self.stack.push(frame.proto.consts[idx].clone()),
+                Op::Local(slot) => self.stack.push(frame.locals[slot].get()),
+                Op::SetLocal(slot) => frame.locals[slot].set(self.stack.last().unwrap().clone()),
+                Op::Global(idx) => {
+                    let name = sym_name(&frame.proto.consts[idx]);
+                    let value = s
+                        .get_val(name)
+                        .ok_or_else(|| LispError::UnboundVariable(name.into()))?;
+                    self.stack.push(value.clone());
+                }
+                Op::SetGlobal(idx) => {
+                    let name = sym_name(&frame.proto.consts[idx]).to_string();
+                    s.set_val(name, self.stack.last().unwrap().clone());
+                }
+                Op::Function(idx) => {
+                    let name = sym_name(&frame.proto.consts[idx]);
+                    let fun = s
+                        .get_fun(name)
+                        .ok_or_else(|| LispError::UnboundFunction(name.into()))?;
+                    self.stack.push(SAtom::new(Atom::Fun(fun)));
+                }
+                Op::Pop => {
+                    self.stack.pop();
+                }
+                Op::Dup => {
+                    let top = self.stack.last().unwrap().clone();
+                    self.stack.push(top);
+                }
+                Op::Jump(to) => frame.pc = to,
+                Op::JumpIfNil(to) => {
+                    if *self.stack.pop().unwrap() == Atom::Nil {
+                        frame.pc = to;
+                    }
+                }
+                Op::JumpIfSpecial(idx, to) => {
+                    let Atom::Cons(names) = &*frame.proto.consts[idx] else {
+                        unreachable!()
+                    };
+                    if names.iter().any(|name| s.is_special(sym_name(&name))) {
+                        frame.pc = to;
+                    }
+                }
+                Op::Closure(idx) => {
+                    let proto = frame.proto.protos[idx].clone();
+                    let captured = proto
+                        .captures
+                        .iter()
+                        .map(|(outer, _)| frame.locals[*outer].clone())
+                        .collect();
+                    let closure = Closure {
+                        proto,
+                        captured,
+                        val: s.val.clone(),
+                    };
+                    let fun = Fun::Compiled(Arc::new(closure));
+                    self.stack.push(SAtom::new(Atom::Fun(fun.into())));
+                }
+                Op::Call(argc) => {
+                    self.call(s, argc, false, true)?;
+                }
+                Op::TailCall(argc) => {
+                    // A builtin doesn't replace the frame, so return its value.
+                    if !self.call(s, argc, true, true)? {
+                        if let Some(value) = self.ret(s)? {
+                            return Ok(value);
+                        }
+                    }
+                }
+                Op::Funcall(argc, tail) => {
+                    let at = self.stack.len() - argc - 1;
+                    let designator = self.stack[at].clone();
+                    self.stack[at] = match &*designator {
+                        Atom::Fun(_) => designator,
+                        Atom::Cons(_) => designator_form(s, designator)?,
+                        _ => SAtom::new(Atom::Fun(designated_fun(s, &designator)?)),
+                    };
+                    if !self.call(s, argc, tail, false)? && tail {
+                        if let Some(value) = self.ret(s)? {
+                            return Ok(value);
+                        }
+                    }
+                }
+                Op::CallForm(idx, scope) => {
+                    let form = frame.proto.consts[idx].clone();
+                    let Atom::Cons(SExpr { cdr, .. }) = &*form else {
+                        unreachable!()
+                    };
+                    let fun = self.stack.pop().unwrap();
+                    match &*fun {
+                        Atom::Fun(_) => {
+                            let args = Args::try_from(&**cdr)?.to_vec();
+                            let argc = args.len();
+                            self.stack.push(fun);
+                            self.stack.extend(args);
+                            self.call(s, argc, false, true)?;
+                        }
+                        _ => {
+                            let form = SAtom::new(cons!(fun, cdr.clone()));
+                            let res = self.interp(s, form.clone(), scope)?;
+                            self.resume(s, res, form, false)?;
+                        }
+                    }
+                }
+                Op::Defun(idx, is_macro) => {
+                    let name = frame.proto.consts[idx].clone();
+                    let fname = sym_name(&name).to_string();
+                    let Atom::Fun(fun) = &*self.stack.pop().unwrap() else {
+                        unreachable!()
+                    };
+                    if is_macro {
+                        Arc::make_mut(&mut s.macros).insert(fname, fun.clone());
+                    } else {
+                        s.set_fun(fname, fun.clone());
+                    }
+                    self.stack.push(name);
+                }
+                Op::Interp(idx, scope) => {
+                    let form = frame.proto.consts[idx].clone();
+                    let tail = frame.proto.code[frame.pc] == Op::Return;
+                    let res = self.interp(s, form.clone(), scope)?;
+                    self.resume(s, res, form, tail)?;
+                }
+                Op::Case(idx, exhaustive) => {
+                    let key = self.stack.pop().unwrap();
+                    let matches = case_matches(&frame.proto.consts[idx], &key, exhaustive);
+                    self.stack.push(if matches { t!() } else { nil!() }.into());
+                }
+                Op::EcaseError => {
+                    let key = self.stack.pop().unwrap();
+                    return Err(LispError::type_error("a key of an ecase clause", &key).into());
+                }
+                Op::Bind(slot) => frame.locals[slot] = Binding::new(self.stack.pop().unwrap()),
+                Op::Block(slot, _) => {
+                    let Slot::Block(name) = &frame.proto.slots[slot] else {
+                        unreachable!()
+                    };
+                    // the marker holds the name while the block is active
+                    let marker = Binding::new(SAtom::new(Atom::Sym(name.clone())));
+                    frame.locals[slot] = marker.clone();
+                    let height = self.stack.len();
+                    frame.handlers.push(Handler { marker, height, op });
+                }
+                Op::Tagbody(idx) => {
+                    let marker = Binding::new(t!().into());
+                    for (slot, _) in &frame.proto.targets[idx] {
+                        frame.locals[*slot] = marker.clone();
+                    }
+                    let height = self.stack.len();
+                    frame.handlers.push(Handler { marker, height, op });
+                }
+                Op::Leave => frame.leave_innermost(),
+                Op::ReturnFrom(slot, is_return) => {
+                    let value = self.stack.pop().unwrap();
+                    let marker = frame.locals[slot].clone();
+                    match frame.handler(&marker) {
+                        Some(idx) => frame.return_to(&mut self.stack, idx, value),
+                        None => {
+                            let Slot::Block(name) = &frame.proto.slots[slot] else {
+                                unreachable!()
+                            };
+                            let form = if is_return { "return" } else { "return-from" };
+                            return Err(block_exit(form, name, marker, value));
+                        }
+                    }
+                }
+                Op::Go(slot) => {
+                    let Slot::Tag(tag) = &frame.proto.slots[slot] else {
+                        unreachable!()
+                    };
+                    let marker = frame.locals[slot].clone();
+                    match frame.handler(&marker) {
+                        Some(idx) => {
+                            let tag = tag.clone();
+                            frame.go_to(&mut self.stack, idx, &tag);
+                        }
+                        None => {
+                            let Atom::Cons(SExpr { cdr, .. }) = &*frame.proto.forms[frame.pc - 1]
+                            else {
+                                unreachable!()
+                            };
+                            let Atom::Cons(SExpr { car: tag, .. }) = &**cdr else {
+                                unreachable!()
+                            };
+                            return Err(go_exit(marker, tag.clone()));
+                        }
+                    }
+                }
+                Op::Dotimes(to) => {
+                    let [count, i] = &self.stack[self.stack.len() - 2..] else {
+                        unreachable!()
+                    };
+                    let (Atom::Int(count), Atom::Int(i)) = (&**count, &**i) else {
+                        return Err(LispError::type_error("integer", count).into());
+                    };
+                    if i >= count {
+                        frame.pc = to;
+                    }
+                }
+                Op::Incr => {
+                    let Atom::Int(i) = **self.stack.last().unwrap() else {
+                        unreachable!()
+                    };
+                    *self.stack.last_mut().unwrap() = num!(i + 1).into();
+                }
+                Op::Dolist(to) => {
+                    let list = self.stack.pop().unwrap();
+                    match &*list {
+                        Atom::Cons(SExpr { car, cdr }) => {
+                            self.stack.push(cdr.clone());
+                            self.stack.push(car.clone());
+                        }
+                        Atom::Nil => {
+                            self.stack.push(list);
+                            frame.pc = to;
+                        }
+                        _ => return Err(LispError::type_error("list", &list).into()),
+                    }
+                }
+                Op::Return => {
+                    if let Some(value) = self.ret(s)? {
+                        return Ok(value);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Return from the current frame with the value on top of the stack.
+    /// Yields the value when that was the outermost frame.
+    fn ret(&mut self, s: &mut Env) -> Result<Option<SAtom>, EvalError> {
+        let value = self.stack.pop().unwrap();
+        let mut frame = self.frames.pop().unwrap();
+        frame.leave();
+        if let Some(val) = frame.saved_val {
+            s.val = val;
+        }
+        self.stack.truncate(frame.base - 1);
+        let value = self.close_extents(s, Ok(value))?;
+        Ok(self.returned(value))
+    }
+
+    /// Hand `value` to the caller of the frame just popped, or yield it when
+    /// that was the outermost frame.
+    fn returned(&mut self, value: SAtom) -> Option<SAtom> {
+        if self.frames.is_empty() {
+            return Some(value);
+        }
+        self.stack.push(value);
+        None
+    }
+
+    /// Close the extents of the frames popped so far, innermost first, with
+    /// the value or error they were left with.
+    fn close_extents(&mut self, s: &mut Env, mut res: EvalResult) -> EvalResult {
+        while let Some((depth, _)) = self.extents.last() {
+            if *depth < self.frames.len() {
+                break;
+            }
+            let (_, extent) = self.extents.pop().unwrap();
+            res = extent.close(res, s);
+        }
+        res
+    }
+
+    /// Call the function below the top `argc` values of the stack. Returns
+    /// whether a frame was entered, otherwise the value is on the stack.
+    /// A `direct` call form has the callee in its head, rather than a builtin
+    /// like `funcall`.
+    fn call(
+        &mut self,
+        s: &mut Env,
+        argc: usize,
+        tail: bool,
+        direct: bool,
+    ) -> Result<bool, EvalError> {
+        let at = self.stack.len() - argc - 1;
+        let top = self.frames.last().unwrap();
+        let form = top.proto.forms[top.pc - 1].clone();
+
+        let fun = match &*self.stack[at] {
+            Atom::Fun(fun) => fun.clone(),
+            _ => return Err(LispError::type_error("function", &self.stack[at]).into()),
+        };
+        if let Fun::Compiled(closure) = &*fun {
+            let call = Call { form, direct };
+            return self
+                .enter(s, closure.clone(), argc, Some(call), tail)
+                .map(|_| true);
+        }
+
+        let args: SExpr = self.stack.drain(at + 1..).collect();
+        self.stack.pop();
+        let args = match argc {
+            0 => Args::Nil,
+            _ => Args::S(&args),
+        };
+        let describe = || {
+            let head = match &*form {
+                Atom::Cons(SExpr { car, .. }) if direct => car.clone(),
+                _ => return frame(format!("{:?}", form), &form),
+            };
+            let args = match &args {
+                Args::S(args) => Atom::Cons((*args).clone()),
+                Args::Nil => Atom::Nil,
+            };
+            frame(format!("{:?}", cons!(head, args)), &form)
+        };
+
+        let res = match fun.call_tail(s, &args) {
+            Ok(next @ (Tail::Done(_) | Tail::Compiled(..))) => next,
+            Ok(next) => run_to_vm(next, s).map_err(|e| e.with_frame(describe()))?,
+            Err(e) => return Err(e.with_frame(describe())),
+        };
+        self.resume(s, res, form, tail)
+    }
+
+    /// Go on after a builtin or the interpreter returned `res`: push its
+    /// value, or enter the compiled function it called last, in place of the
+    /// current frame in tail position. Returns whether a frame was entered.
+    fn resume(
+        &mut self,
+        s: &mut Env,
+        res: Tail,
+        form: SAtom,
+        tail: bool,
+    ) -> Result<bool, EvalError> {
+        match res {
+            Tail::Compiled(closure, args) => {
+                let argc = args.len();
+                self.stack.push(SAtom::new(Atom::Nil));
+                self.stack.extend(args);
+                let call = Call {
+                    form,
+                    direct: false,
+                };
+                self.enter(s, closure, argc, Some(call), tail).map(|_| true)
+            }
+            Tail::Done(value) => {
+                self.stack.push(value);
+                Ok(false)
+            }
+            // the extents are closed when the frame of the call returns
+            Tail::Within(extent, next) => {
+                if tail {
+                    self.hoist_blocks();
+                }
+                let depth = self.frames.len() - usize::from(tail);
+                self.extents.push((depth, extent));
+                self.resume(s, *next, form, tail)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Push a frame for `closure`, whose `argc` arguments are on top of the
+    /// stack. In tail position it replaces the current frame.
+    fn enter(
+        &mut self,
+        s: &mut Env,
+        closure: Arc<Closure>,
+        argc: usize,
+        call: Option<Call>,
+        tail: bool,
+    ) -> Result<(), EvalError> {
+        let proto = closure.proto.clone();
+        if argc != proto.nparams {
+            let err: EvalError =
+                LispError::arity(&proto.name, &proto.nparams.to_string(), argc).into();
+            return Err(match &call {
+                Some(Call { form, .. }) => err.with_frame(frame(format!("{:?}", form), form)),
+                None => err,
+            });
+        }
+
+        let base = self.stack.len() - argc;
+        let mut locals: Vec<Binding> = self.stack.drain(base..).map(Binding::new).collect();
+        if proto.slots.len() > argc {
+            // `let` slots get their binding from `Op::Bind`
+            locals.resize(proto.slots.len(), Binding::default());
+            for ((_, slot), binding) in proto.captures.iter().zip(&closure.captured) {
+                locals[*slot] = binding.clone();
+            }
+        }
+        let caller_val = mem::replace(&mut s.val, closure.val.clone());
+        let mut frame = Frame {
+            proto,
+            pc: 0,
+            base,
+            locals,
+            saved_val: Some(caller_val),
+            call,
+            tail_frames: VecDeque::new(),
+            handlers: Vec::new(),
+        };
+
+        if tail {
+            self.hoist_blocks();
+            let mut old = self.frames.pop().unwrap();
+            if old.saved_val.is_some() {
+                frame.saved_val = old.saved_val.take();
+            }
+            let desc = old.describe();
+            frame.tail_frames = mem::take(&mut old.tail_frames);
+            if let Some(desc) = desc {
+                if frame.tail_frames.len() == TAIL_FRAMES {
+                    frame.tail_frames.pop_front();
+                }
+                frame.tail_frames.push_back(desc);
+            }
+            self.stack.drain(old.base - 1..base - 1);
+            frame.base = old.base;
+        }
+
+        self.frames.push(frame);
+        Ok(())
+    }
+
+    /// Turn the blocks the current frame is in into extents of its index,
+    /// for a tail call made in them to replace the frame. No tail call is
+    /// made in a tagbody.
+    fn hoist_blocks(&mut self) {
+        let depth = self.frames.len() - 1;
+        let frame = self.frames.last_mut().unwrap();
+        for handler in frame.handlers.drain(..) {
+            debug_assert!(matches!(handler.op, Op::Block(..)));
+            self.extents.push((depth, Extent::Block(handler.marker)));
+        }
+    }
+
+    /// Evaluate `form` with the interpreter, the local slots of `scopes[scope]`
+    /// bound by name. A call to a compiled function it ends with is left to
+    /// the VM, so recursion through the form doesn't nest VMs.
+    fn interp(&mut self, s: &mut Env, form: SAtom, scope: usize) -> TailResult {
+        let frame = self.frames.last().unwrap();
+        let proto = &frame.proto;
+        // blocks and tags are in namespaces of their own
+        let mut lexical = s.val.clone();
+        let mut vars = Vec::new();
+        for slot in &proto.scopes[scope] {
+            let binding = frame.locals[*slot].clone();
+            match &proto.slots[*slot] {
+                Slot::Var(name) => vars.push((name.as_str().into(), binding)),
+                Slot::Fun(name) => {
+                    lexical = lexical.extend(mem::take(&mut vars));
+                    lexical = lexical.extend_funs([(name.as_str().into(), binding)]);
+                }
+                Slot::Block(name) => {
+                    lexical = lexical.extend(mem::take(&mut vars));
+                    lexical = lexical.extend_block(name.as_str().into(), binding);
+                }
+                Slot::Tag(tag) => {
+                    lexical = lexical.extend(mem::take(&mut vars));
+                    lexical = lexical.extend_tags([tag.as_str().into()], &binding);
+                }
+            }
+        }
+        let scope = lexical.extend(vars);
+
+        let caller_val = mem::replace(&mut s.val, scope);
+        let res = run_to_vm(Tail::Eval(form), s);
+        s.val = caller_val;
+        res
+    }
+
+    /// Signal `err` and pop frames, recording them in the backtrace, until
+    /// an extent they ran in takes it, like a `catch` does a `throw`: the
+    /// frame run in the extent then returns its value. Yields the value when
+    /// that was the outermost frame.
+    fn unwind(&mut self, s: &mut Env, err: EvalError) -> Result<Option<SAtom>, EvalError> {
+        let mut err = match self.frames.last() {
+            Some(frame) => err.at(&frame.proto.forms[frame.pc - 1]),
+            None => err,
+        };
+        err = conditions::signal_error(s, err);
+
+        loop {
+            match self.close_extents(s, Err(err)) {
+                Ok(value) => return Ok(self.returned(value)),
+                Err(e) => err = e,
+            }
+            if let (Some(top), LispError::Exit(exit)) = (self.frames.last_mut(), &err.error) {
+                match &**exit {
+                    Exit::Block(marker, value) => {
+                        if let Some(idx) = top.handler(marker) {
+                            top.return_to(&mut self.stack, idx, value.clone());
+                            return Ok(None);
+                        }
+                    }
+                    Exit::Go(marker, tag) => {
+                        if let Some(idx) = top.handler(marker) {
+                            top.go_to(&mut self.stack, idx, &tag_name(tag).unwrap());
+                            return Ok(None);
+                        }
+                    }
+                    Exit::Throw(..) => {}
+                }
+            }
+            let Some(mut top) = self.frames.pop() else {
+                return Err(err);
+            };
+            top.leave();
+            let descs = top.describe().into_iter();
+            for (form, call) in descs.chain(top.tail_frames.into_iter().rev()) {
+                err = err.with_frame(frame(format!("{:?}", call), &form));
+            }
+            if let Some(val) = top.saved_val {
+                s.val = val;
+            }
+            self.stack.truncate(top.base - 1);
+        }
+    }
+}
+
+/// Evaluate the form passed to `funcall` or `apply` as the function,
+/// compiling it only the first time.
+fn designator_form(s: &mut Env, form: SAtom) -> EvalResult {
+    let proto = compiled(&form, s)?;
+    let fun = run_proto(proto, s)?;
+    match &*fun {
+        Atom::Fun(_) => Ok(fun),
+        _ => Err(LispError::type_error("function", &fun).into()),
+    }
+}
+
+fn sym_name(v: &Atom) -> &str {
+    match v {
+        Atom::Sym(name) => name,
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{env::Backend, lisp_parsing::parse, num, sexpr, sym, test_utils::run};
+
+    use super::*;
+
+    fn vm_env() -> Env {
+        Env {
+            backend: Backend::Vm,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_vm_closures() {
+        let env = &mut vm_env();
+        run("(defun adder (n) (lambda (x) (add x n)))", env).unwrap();
+        run("(defvar add2 (adder 2))", env).unwrap();
+        assert_eq!(*run("(funcall add2 40)", env).unwrap(), num!(42));
+
+        // assignments to params stay local to the call
+        run("(defun bump (n) (setq n (add n 1)))", env).unwrap();
+        assert_eq!(*run("(bump 1)", env).unwrap(), num!(2));
+    }
+
+    #[test]
+    fn test_vm_interp_fallback() {
+        let env = &mut vm_env();
+        // special forms left to the interpreter see and assign the locals
+        run(
+            "(defun safe_div (a b) (handler-case (div a (car b)) (error (e) (setq a 0) a)))",
+            env,
+        )
+        .unwrap();
+        assert_eq!(*run("(safe_div 6 (list 3))", env).unwrap(), num!(2));
+        assert_eq!(*run("(safe_div 6 3)", env).unwrap(), num!(0));
+
+        run("(defun wrap (x rest) `(x ,x ,@rest))", env).unwrap();
+        assert_eq!(
+            *run("(wrap 1 (list 2 3))", env).unwrap(),
+            sexpr!(sym!("x"), num!(1), num!(2), num!(3))
+        );
+    }
+
+    #[test]
+    fn test_vm_proto_cache() {
+        let env = &mut vm_env();
+        // a form evaluated again is compiled once
+        let form: SAtom = parse("(twice 1)").into();
+        let err = eval(form.clone(), env).unwrap_err();
+        assert!(matches!(err.error, LispError::UnboundFunction(_)));
+        let proto = compiled(&form, env).unwrap();
+        assert!(Arc::ptr_eq(&proto, &compiled(&form, env).unwrap()));
+
+        // unless a macro it calls is defined or shadowed since
+        run("(defmacro twice (x) `(list ,x ,x))", env).unwrap();
+        assert_eq!(*eval(form.clone(), env).unwrap(), sexpr!(num!(1), num!(1)));
+        let fun = run("(lambda (x) (mul x 2))", env).unwrap();
+        let val = env.val.clone();
+        env.val = val.extend_funs([("twice".into(), Binding::new(fun))]);
+        assert_eq!(*eval(form.clone(), env).unwrap(), num!(2));
+        env.val = val;
+        assert_eq!(*eval(form, env).unwrap(), sexpr!(num!(1), num!(1)));
+    }
+
+    #[test]
+    fn test_vm_deep_recursion() {
+        let env = &mut vm_env();
+        // VM frames live on the heap, so non-tail recursion is not bounded
+        // by the Rust stack either
+        run(
+            "(defun depth (n) (if (eq n 0) 0 (add 1 (depth (sub n 1)))))",
+            env,
+        )
+        .unwrap();
+        assert_eq!(*run("(depth 100000)", env).unwrap(), num!(100000));
+
+        // nor is recursion through a form left to the interpreter
+        run(
+            "(defun depth2 (n) (if (eq n 0) 0 (add 1 (flet ((g () (depth2 (sub n 1)))) (g)))))",
+            env,
+        )
+        .unwrap();
+        assert_eq!(*run("(depth2 100000)", env).unwrap(), num!(100000));
+    }
+
+    #[test]
+    fn test_vm_mixed_backends() {
+        // functions defined by the interpreter can be called from the VM and
+        // the other way around
+        let env = &mut Env::default();
+        run("(defun twice (f x) (funcall f (funcall f x)))", env).unwrap();
+        env.backend = Backend::Vm;
+        run("(defun inc (x) (add x 1))", env).unwrap();
+        assert_eq!(
+            *run("(twice (lambda (x) (inc x)) 1)", env).unwrap(),
+            num!(3)
+        );
+        env.backend = Backend::Interpreter;
+        assert_eq!(
+            *run("(inc (twice (lambda (x) (mul x 3)) 1))", env).unwrap(),
+            num!(10)
+        );
+    }
+}